@@ -0,0 +1,67 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Exercise the Windows desktop integration by hand.
+//!
+//! Run with `cargo run --example windows_desktop_api` on Windows. An optional
+//! image path may be passed as the first argument to test wallpaper changes.
+
+#[cfg(windows)]
+fn main() {
+  use std::path::PathBuf;
+  use std::time::Duration;
+
+  use loxerpaper::api::{DesktopApi, Notification, Urgency, WindowsDesktopApi};
+
+  let desktop = WindowsDesktopApi::new();
+
+  println!("Capabilities: {:?}", desktop.capabilities());
+
+  let notification = Notification::builder("New Wallpaper")
+    .body("Your desktop background has been updated!")
+    .urgency(Urgency::Normal)
+    .timeout(Duration::from_secs(5))
+    .action("view", "View")
+    .action("undo", "Undo")
+    .build();
+  if let Err(e) = desktop.send_notification(&notification) {
+    eprintln!("Failed to send notification: {e}");
+  }
+
+  let image = std::env::args()
+    .nth(1)
+    .map(PathBuf::from)
+    .unwrap_or_else(|| PathBuf::from("C:\\Windows\\Web\\Wallpaper\\Windows\\img0.jpg"));
+
+  if image.exists() {
+    match desktop.change_background(&image) {
+      Ok(()) => println!("Wallpaper set to {image:?}"),
+      Err(e) => eprintln!("Failed to set wallpaper: {e}"),
+    }
+    if let Err(e) = desktop.open_file(&image) {
+      eprintln!("Failed to open file: {e}");
+    }
+  } else {
+    println!("Image {image:?} not found, skipping wallpaper and open tests");
+  }
+}
+
+#[cfg(not(windows))]
+fn main() {
+  eprintln!("This example only runs on Windows.");
+}
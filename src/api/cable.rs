@@ -0,0 +1,324 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+use crate::constants::CABLE_ORIGIN;
+use crate::model::link::Link;
+
+/// ActionCable pings every 3 seconds, so this much silence means the socket is dead.
+const STALE_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum CableError {
+  WebSocket(tungstenite::Error),
+  Rejected,
+  Closed,
+  Stale,
+  Protocol(String),
+}
+
+impl fmt::Display for CableError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CableError::WebSocket(e) => write!(f, "websocket error: {e}"),
+      CableError::Rejected => write!(f, "subscription rejected by server"),
+      CableError::Closed => write!(f, "connection closed before subscription was confirmed"),
+      CableError::Stale => write!(f, "no message received for {}s", STALE_AFTER.as_secs()),
+      CableError::Protocol(msg) => write!(f, "protocol error: {msg}"),
+    }
+  }
+}
+
+impl Error for CableError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      CableError::WebSocket(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+impl From<tungstenite::Error> for CableError {
+  fn from(e: tungstenite::Error) -> Self {
+    CableError::WebSocket(e)
+  }
+}
+
+/// A single frame as sent by the ActionCable server.
+#[derive(Debug, Deserialize)]
+struct Frame {
+  #[serde(rename = "type")]
+  kind: Option<String>,
+  identifier: Option<String>,
+  message: Option<serde_json::Value>,
+}
+
+/// An update pushed on the link channel.
+#[derive(Debug, PartialEq)]
+pub enum LinkUpdate {
+  /// The server pushed the whole link.
+  Full(Box<Link>),
+  /// The link changed but the payload is partial, it should be fetched again.
+  Changed,
+}
+
+/// Subscription to the `LinkChannel` of a single link.
+pub struct LinkSubscription {
+  socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+  identifier: String,
+}
+
+impl LinkSubscription {
+  /// Connect to the cable at `url` and subscribe to `link_id`.
+  ///
+  /// Returns once the server confirmed the subscription.
+  pub async fn connect(url: &str, link_id: i64) -> Result<Self, CableError> {
+    let mut request = url.into_client_request()?;
+    request
+      .headers_mut()
+      .insert("Origin", HeaderValue::from_static(CABLE_ORIGIN));
+    let (socket, _) = connect_async(request).await?;
+
+    // ActionCable expects the identifier as a JSON encoded string.
+    let identifier = json!({ "channel": "LinkChannel", "id": link_id }).to_string();
+    let mut subscription = LinkSubscription { socket, identifier };
+
+    let subscribe = json!({ "command": "subscribe", "identifier": subscription.identifier });
+    subscription
+      .socket
+      .send(Message::text(subscribe.to_string()))
+      .await?;
+
+    loop {
+      let frame = subscription.next_frame().await?.ok_or(CableError::Closed)?;
+      match frame.kind.as_deref() {
+        Some("confirm_subscription") if subscription.is_ours(&frame) => return Ok(subscription),
+        Some("reject_subscription") if subscription.is_ours(&frame) => {
+          return Err(CableError::Rejected);
+        }
+        // Welcome and pings are expected before the confirmation.
+        _ => continue,
+      }
+    }
+  }
+
+  /// Wait for the next update on the link.
+  ///
+  /// Returns `Ok(None)` when the server closed the connection. Cancel safe, dropping the
+  /// future loses no update.
+  pub async fn next_update(&mut self) -> Result<Option<LinkUpdate>, CableError> {
+    loop {
+      let Some(frame) = self.next_frame().await? else {
+        return Ok(None);
+      };
+
+      if frame.kind.as_deref() == Some("disconnect") {
+        return Ok(None);
+      }
+
+      if frame.kind.is_some() || !self.is_ours(&frame) {
+        continue;
+      }
+
+      if let Some(message) = frame.message {
+        return Ok(Some(match serde_json::from_value::<Link>(message) {
+          Ok(link) => LinkUpdate::Full(Box::new(link)),
+          Err(_) => LinkUpdate::Changed,
+        }));
+      }
+    }
+  }
+
+  fn is_ours(&self, frame: &Frame) -> bool {
+    frame.identifier.as_deref() == Some(self.identifier.as_str())
+  }
+
+  async fn next_frame(&mut self) -> Result<Option<Frame>, CableError> {
+    loop {
+      let message = tokio::time::timeout(STALE_AFTER, self.socket.next())
+        .await
+        .map_err(|_| CableError::Stale)?;
+
+      match message {
+        None | Some(Ok(Message::Close(_))) => return Ok(None),
+        Some(Ok(Message::Text(text))) => {
+          return serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| CableError::Protocol(format!("invalid frame: {e}")));
+        }
+        // Websocket level pings are answered by tungstenite itself.
+        Some(Ok(_)) => continue,
+        Some(Err(e)) => return Err(e.into()),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpListener;
+  use tokio_tungstenite::accept_async;
+
+  type ServerSocket = WebSocketStream<TcpStream>;
+
+  /// Start a stand-in cable server on a random port, running `script` for the first connection.
+  async fn serve<F, Fut>(script: F) -> String
+  where
+    F: FnOnce(ServerSocket) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+  {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (stream, _) = listener.accept().await.unwrap();
+      let socket = accept_async(stream).await.unwrap();
+      script(socket).await;
+    });
+    format!("ws://{addr}/cable")
+  }
+
+  async fn send(socket: &mut ServerSocket, value: serde_json::Value) {
+    socket.send(Message::text(value.to_string())).await.unwrap();
+  }
+
+  /// Plays the welcome/subscribe/confirm handshake and returns the identifier.
+  async fn handshake(socket: &mut ServerSocket) -> String {
+    send(socket, json!({ "type": "welcome" })).await;
+    let subscribe = socket.next().await.unwrap().unwrap();
+    let subscribe: serde_json::Value = serde_json::from_str(subscribe.to_text().unwrap()).unwrap();
+    assert_eq!(subscribe["command"], "subscribe");
+    let identifier = subscribe["identifier"].as_str().unwrap().to_string();
+    send(
+      socket,
+      json!({ "type": "confirm_subscription", "identifier": identifier }),
+    )
+    .await;
+    identifier
+  }
+
+  #[tokio::test]
+  async fn subscribes_to_link_channel() {
+    let url = serve(|mut socket| async move {
+      let identifier = handshake(&mut socket).await;
+      let identifier: serde_json::Value = serde_json::from_str(&identifier).unwrap();
+      assert_eq!(identifier, json!({ "channel": "LinkChannel", "id": 42 }));
+    })
+    .await;
+
+    LinkSubscription::connect(&url, 42).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn rejected_subscription_is_an_error() {
+    let url = serve(|mut socket| async move {
+      send(&mut socket, json!({ "type": "welcome" })).await;
+      let subscribe = socket.next().await.unwrap().unwrap();
+      let subscribe: serde_json::Value =
+        serde_json::from_str(subscribe.to_text().unwrap()).unwrap();
+      send(
+        &mut socket,
+        json!({ "type": "reject_subscription", "identifier": subscribe["identifier"] }),
+      )
+      .await;
+    })
+    .await;
+
+    let result = LinkSubscription::connect(&url, 1).await;
+    assert!(matches!(result, Err(CableError::Rejected)));
+  }
+
+  #[tokio::test]
+  async fn yields_pushed_updates_and_skips_pings() {
+    let url = serve(|mut socket| async move {
+      let identifier = handshake(&mut socket).await;
+      send(&mut socket, json!({ "type": "ping", "message": 1 })).await;
+      send(
+        &mut socket,
+        json!({
+          "identifier": identifier,
+          "message": {
+            "id": 7,
+            "username": "gray",
+            "post_url": "https://static1.e621.net/data/5d/87/abc.png",
+            "set_by": "someone"
+          }
+        }),
+      )
+      .await;
+      send(
+        &mut socket,
+        json!({ "identifier": identifier, "message": { "post_url": "https://static1.e621.net/x.png" } }),
+      )
+      .await;
+      socket.close(None).await.unwrap();
+    })
+    .await;
+
+    let mut subscription = LinkSubscription::connect(&url, 7).await.unwrap();
+
+    match subscription.next_update().await.unwrap() {
+      Some(LinkUpdate::Full(link)) => {
+        assert_eq!(link.id, 7);
+        assert_eq!(link.set_by.as_deref(), Some("someone"));
+      }
+      other => panic!("unexpected update {other:?}"),
+    }
+    assert_eq!(
+      subscription.next_update().await.unwrap(),
+      Some(LinkUpdate::Changed)
+    );
+    assert_eq!(subscription.next_update().await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn ignores_messages_for_other_channels() {
+    let url = serve(|mut socket| async move {
+      let identifier = handshake(&mut socket).await;
+      send(
+        &mut socket,
+        json!({ "identifier": "{\"channel\":\"Other\"}", "message": { "id": 1 } }),
+      )
+      .await;
+      send(
+        &mut socket,
+        json!({ "identifier": identifier, "message": {} }),
+      )
+      .await;
+    })
+    .await;
+
+    let mut subscription = LinkSubscription::connect(&url, 3).await.unwrap();
+    assert_eq!(
+      subscription.next_update().await.unwrap(),
+      Some(LinkUpdate::Changed)
+    );
+  }
+}
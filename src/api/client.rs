@@ -16,23 +16,39 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::constants::{link_url, response_url, user_url_opt};
+use std::path::Path;
+
+use crate::api::download::{Download, DownloadError, Downloader, RetryPolicy};
+use crate::constants::{USER_AGENT, link_url, response_url, user_url_opt};
 use crate::model::config::Config;
-use crate::model::{link::Link, response::Response, user::User};
+use crate::model::link::{Link, ResponseType};
+use crate::model::{response::Response, user::User};
+use crate::policy::DownloadPolicy;
 
-/// Simple API client that holds a base URL and a reusable reqwest client.
+/// Simple API client that holds a base URL and reusable reqwest clients.
+///
+/// Clones share the connection pools.
 #[derive(Clone)]
 pub struct ApiClient {
   client: reqwest::Client,
+  downloader: Downloader,
   pub config: Config,
 }
 
 impl ApiClient {
   /// Create a new client with an explicit base URL.
   pub fn new(config: Config) -> Self {
+    let downloader = Downloader::new(
+      DownloadPolicy::from_preferences(&config.preferences),
+      RetryPolicy::default(),
+    );
     ApiClient {
       config,
-      client: reqwest::Client::new(),
+      client: reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .build()
+        .expect("failed to build the API client"),
+      downloader,
     }
   }
 
@@ -59,8 +75,6 @@ impl ApiClient {
     if self
       .config
       .feed
-      .as_ref()
-      .unwrap()
       .token
       .as_ref()
       .map(|s| s == "your_token")
@@ -81,6 +95,21 @@ impl ApiClient {
     Ok(link)
   }
 
+  /// React to the post currently set on `link`, with an optional reply `text`.
+  ///
+  /// The response is checked with `Response::validate` before anything is sent.
+  pub async fn react(
+    &self,
+    link: i64,
+    response_type: ResponseType,
+    text: impl Into<String>,
+  ) -> Result<Link, Box<dyn std::error::Error>> {
+    let api_key = self.config.feed.token.clone().unwrap_or_default();
+    let response = Response::new(api_key, response_type, text);
+    response.validate()?;
+    self.post_response(link, &response).await
+  }
+
   /// Get user details; api_key is optional.
   pub async fn get_user(
     &self,
@@ -94,13 +123,23 @@ impl ApiClient {
     Ok(user)
   }
 
+  /// Download a post image to `dest`, see `Downloader::download`.
+  pub async fn download(
+    &self,
+    url: &str,
+    dest: &Path,
+    max_bytes: u64,
+  ) -> Result<Download, DownloadError> {
+    self.downloader.download(url, dest, max_bytes).await
+  }
+
   /// Get the base URL of the API client.
   pub fn base_url(&self) -> &str {
-    self.config.base.as_ref().unwrap().base.as_ref().unwrap()
+    self.config.base.base.as_ref().unwrap()
   }
 
   /// Get the link ID from the API client.
   pub fn link_id(&self) -> i64 {
-    self.config.feed.as_ref().unwrap().feed.unwrap()
+    self.config.feed.feed.unwrap()
   }
 }
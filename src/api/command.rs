@@ -0,0 +1,382 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification, WallpaperInfo};
+use crate::model::config::DesktopConfig;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// DesktopApi running user supplied command templates, see `DesktopConfig`.
+pub struct CommandDesktopApi {
+  set_command: Vec<String>,
+  animated_command: Option<Vec<String>>,
+  open_command: Option<Vec<String>>,
+  notify_command: Option<Vec<String>>,
+  timeout: Duration,
+}
+
+/// Split a template into arguments, shell style.
+fn parse_template(template: &str) -> Result<Vec<String>, DesktopApiError> {
+  let words = shell_words::split(template)
+    .map_err(|e| DesktopApiError::Backend(format!("invalid command template {template:?}: {e}")))?;
+  if words.is_empty() {
+    return Err(DesktopApiError::Backend(
+      "empty command template".to_string(),
+    ));
+  }
+  Ok(words)
+}
+
+impl CommandDesktopApi {
+  /// Build the backend from the `[Desktop]` section, `None` when no `set_command` is set.
+  ///
+  /// Invalid templates are reported and ignored.
+  pub fn from_config(config: &DesktopConfig) -> Option<Self> {
+    let set_command = config.set_command.as_deref()?;
+    match Self::new(
+      set_command,
+      config.open_command.as_deref(),
+      config.notify_command.as_deref(),
+      config
+        .command_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT),
+    )
+    .and_then(|api| match config.animated_command.as_deref() {
+      Some(template) => api.with_animated_command(template),
+      None => Ok(api),
+    }) {
+      Ok(api) => Some(api),
+      Err(e) => {
+        eprintln!("Ignoring set_command: {e}");
+        None
+      }
+    }
+  }
+
+  pub fn new(
+    set_command: &str,
+    open_command: Option<&str>,
+    notify_command: Option<&str>,
+    timeout: Duration,
+  ) -> Result<Self, DesktopApiError> {
+    Ok(CommandDesktopApi {
+      set_command: parse_template(set_command)?,
+      animated_command: None,
+      open_command: open_command.map(parse_template).transpose()?,
+      notify_command: notify_command.map(parse_template).transpose()?,
+      timeout,
+    })
+  }
+
+  /// Also play animated images and videos with `template`, which takes the same placeholders
+  /// as `set_command`.
+  pub fn with_animated_command(mut self, template: &str) -> Result<Self, DesktopApiError> {
+    self.animated_command = Some(parse_template(template)?);
+    Ok(self)
+  }
+
+  /// Substitute `{name}` placeholders in every argument of `template` and run it.
+  fn run(&self, template: &[String], values: &[(&str, &str)]) -> Result<(), DesktopApiError> {
+    let args: Vec<String> = template.iter().map(|arg| substitute(arg, values)).collect();
+
+    let mut child = Command::new(&args[0])
+      .args(&args[1..])
+      .stdin(Stdio::null())
+      .spawn()
+      .map_err(DesktopApiError::Io)?;
+
+    let deadline = Instant::now() + self.timeout;
+    let status = loop {
+      if let Some(status) = child.try_wait().map_err(DesktopApiError::Io)? {
+        break status;
+      }
+      if Instant::now() >= deadline {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(DesktopApiError::Backend(format!(
+          "{} timed out after {}s",
+          args[0],
+          self.timeout.as_secs()
+        )));
+      }
+      std::thread::sleep(Duration::from_millis(20));
+    };
+
+    if status.success() {
+      Ok(())
+    } else {
+      Err(DesktopApiError::Backend(format!(
+        "{} failed with exit code: {status}",
+        args[0]
+      )))
+    }
+  }
+}
+
+impl DesktopApi for CommandDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    self.apply_wallpaper(None, image, &WallpaperInfo::default())
+  }
+
+  fn change_background_on(&self, output: &str, image: &Path) -> Result<(), DesktopApiError> {
+    self.apply_wallpaper(Some(output), image, &WallpaperInfo::default())
+  }
+
+  fn apply_wallpaper(
+    &self,
+    output: Option<&str>,
+    image: &Path,
+    info: &WallpaperInfo,
+  ) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let link_id = info.link_id.map(|id| id.to_string()).unwrap_or_default();
+    let run = |template: &[String], image: &Path| {
+      let path = image.display().to_string();
+      self.run(
+        template,
+        &[
+          ("path", path.as_str()),
+          ("setter", info.set_by.as_deref().unwrap_or_default()),
+          ("link_id", link_id.as_str()),
+          ("output", output.unwrap_or_default()),
+        ],
+      )
+    };
+
+    if let (Some(template), Some(media)) = (&self.animated_command, &info.animated) {
+      match run(template, media) {
+        Ok(()) => {
+          println!("Successfully changed wallpaper to animated {media:?}");
+          return Ok(());
+        }
+        Err(e) => eprintln!("Cannot play {media:?}, using a still frame: {e}"),
+      }
+    }
+
+    run(&self.set_command, image)?;
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: self.notify_command.is_some(),
+      actions: false,
+      set_wallpaper: true,
+      raw_icon_bytes: false,
+      open_file: self.open_command.is_some(),
+      per_output_wallpaper: self.set_command.iter().any(|a| a.contains("{output}")),
+      animated_wallpaper: self.animated_command.is_some(),
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    let template = self
+      .notify_command
+      .as_ref()
+      .ok_or(DesktopApiError::Unsupported)?;
+    self.run(
+      template,
+      &[
+        ("title", notification.title.as_str()),
+        ("body", notification.body.as_deref().unwrap_or_default()),
+      ],
+    )
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    let template = self
+      .open_command
+      .as_ref()
+      .ok_or(DesktopApiError::Unsupported)?;
+    if !file.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "file path {file:?} does not exist"
+      )));
+    }
+    let path = file.display().to_string();
+    self.run(template, &[("path", path.as_str())])
+  }
+}
+
+/// Replace the `{name}` placeholders of `arg` in a single pass, so values are never scanned
+/// for placeholders themselves. Unknown placeholders are left as they are.
+fn substitute(arg: &str, values: &[(&str, &str)]) -> String {
+  let mut out = String::with_capacity(arg.len());
+  let mut rest = arg;
+  while let Some(start) = rest.find('{') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let value = rest.find('}').and_then(|end| {
+      let name = &rest[1..end];
+      let (_, value) = values.iter().find(|(n, _)| *n == name)?;
+      Some((end, value))
+    });
+    match value {
+      Some((end, value)) => {
+        out.push_str(value);
+        rest = &rest[end + 1..];
+      }
+      None => {
+        out.push('{');
+        rest = &rest[1..];
+      }
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+
+  fn api(set_command: &str) -> CommandDesktopApi {
+    CommandDesktopApi::new(set_command, None, None, DEFAULT_TIMEOUT).unwrap()
+  }
+
+  #[test]
+  fn substitutes_placeholders_without_splitting_values() {
+    let dir = tempfile::tempdir().unwrap();
+    let setter = recording_command(dir.path(), "setter");
+    let image = dir.path().join("my wall.png");
+    std::fs::write(&image, b"png").unwrap();
+
+    let api = api(&format!(
+      "'{}' --bg-fill {{path}} 'by {{setter}}' {{link_id}}",
+      setter.display()
+    ));
+    let info = WallpaperInfo {
+      link_id: Some(42),
+      set_by: Some("some one; rm -rf ~".to_string()),
+      animated: None,
+    };
+    api.apply_wallpaper(None, &image, &info).unwrap();
+
+    assert_eq!(
+      recorded(dir.path(), "setter"),
+      [
+        "--bg-fill",
+        &image.display().to_string(),
+        "by some one; rm -rf ~",
+        "42"
+      ]
+    );
+  }
+
+  #[test]
+  fn never_substitutes_inside_values() {
+    let values = [("setter", "{link_id} {path"), ("link_id", "42")];
+    assert_eq!(
+      substitute("{setter}/{link_id}/{unknown}/{{link_id}}", &values),
+      "{link_id} {path/42/{unknown}/{42}"
+    );
+  }
+
+  #[test]
+  fn plays_animated_media_and_falls_back_to_the_still() {
+    let dir = tempfile::tempdir().unwrap();
+    let setter = recording_command(dir.path(), "setter");
+    let player = recording_command(dir.path(), "player");
+    let still = dir.path().join("post.still.png");
+    let gif = dir.path().join("post.gif");
+    std::fs::write(&still, b"png").unwrap();
+    std::fs::write(&gif, b"gif").unwrap();
+    let info = WallpaperInfo {
+      animated: Some(gif.clone()),
+      ..WallpaperInfo::default()
+    };
+
+    let playing = api(&format!("{} {{path}}", setter.display()))
+      .with_animated_command(&format!("{} {{path}}", player.display()))
+      .unwrap();
+    assert!(playing.capabilities().animated_wallpaper);
+    playing.apply_wallpaper(None, &still, &info).unwrap();
+    assert_eq!(recorded(dir.path(), "player"), [gif.display().to_string()]);
+    assert!(recorded(dir.path(), "setter").is_empty());
+
+    let failing = fake_command(dir.path(), "failing", "exit 1");
+    let broken = api(&format!("{} {{path}}", setter.display()))
+      .with_animated_command(&format!("{} {{path}}", failing.display()))
+      .unwrap();
+    broken.apply_wallpaper(None, &still, &info).unwrap();
+    assert_eq!(
+      recorded(dir.path(), "setter"),
+      [still.display().to_string()]
+    );
+  }
+
+  #[test]
+  fn maps_exit_status_to_backend_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let failing = fake_command(dir.path(), "failing", "exit 4");
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+
+    let err = api(&format!("{} {{path}}", failing.display()))
+      .change_background(&image)
+      .unwrap_err();
+    assert!(matches!(err, DesktopApiError::Backend(msg) if msg.contains("exit status: 4")));
+  }
+
+  #[test]
+  fn kills_commands_that_time_out() {
+    let dir = tempfile::tempdir().unwrap();
+    let slow = fake_command(dir.path(), "slow", "exec /bin/sleep 10");
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+    let api = CommandDesktopApi::new(
+      &format!("{} {{path}}", slow.display()),
+      None,
+      None,
+      Duration::from_millis(100),
+    )
+    .unwrap();
+
+    let started = Instant::now();
+    let err = api.change_background(&image).unwrap_err();
+    assert!(matches!(err, DesktopApiError::Backend(msg) if msg.contains("timed out")));
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn optional_commands_are_unsupported_when_unset() {
+    let api = api("true {path}");
+    assert!(matches!(
+      api.open_file(Path::new("/")),
+      Err(DesktopApiError::Unsupported)
+    ));
+    assert!(!api.capabilities().notifications);
+  }
+
+  #[test]
+  fn rejects_unbalanced_quotes() {
+    assert!(CommandDesktopApi::new("feh '{path}", None, None, DEFAULT_TIMEOUT).is_err());
+  }
+}
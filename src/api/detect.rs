@@ -0,0 +1,415 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Choosing a desktop backend.
+//!
+//! The environment is matched against every backend we know, most specific hint first
+//! (`XDG_CURRENT_DESKTOP`, `DESKTOP_SESSION`, compositor variables, running processes), then
+//! generic fallbacks. Candidates whose tools are missing are skipped, and when nothing is
+//! left a `DetectError` explains what was tried.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::api::{CommandDesktopApi, DesktopApi};
+use crate::model::config::{DesktopBackend, DesktopConfig};
+
+#[cfg(target_os = "linux")]
+use crate::api::freedesktop::Commands;
+#[cfg(target_os = "linux")]
+use crate::api::wlroots::Compositor;
+
+#[derive(Debug)]
+pub enum DetectError {
+  /// The backend forced in the config cannot run here.
+  Unavailable {
+    backend: DesktopBackend,
+    reason: String,
+  },
+  /// Every candidate was rejected, with the reason for each.
+  NoBackend {
+    tried: Vec<(DesktopBackend, String)>,
+  },
+  /// The operating system has no backend at all.
+  UnsupportedOs(String),
+}
+
+impl fmt::Display for DetectError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DetectError::Unavailable { backend, reason } => {
+        write!(f, "configured backend {backend} is unavailable: {reason}")
+      }
+      DetectError::NoBackend { tried } if tried.is_empty() => write!(
+        f,
+        "no desktop environment detected, set `backend` or `set_command` in the [Desktop] section"
+      ),
+      DetectError::NoBackend { tried } => {
+        write!(f, "no usable desktop backend found (")?;
+        for (i, (backend, reason)) in tried.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{backend}: {reason}")?;
+        }
+        write!(
+          f,
+          "), set `backend` or `set_command` in the [Desktop] section"
+        )
+      }
+      DetectError::UnsupportedOs(os) => write!(f, "the operating system {os} is not supported"),
+    }
+  }
+}
+
+impl Error for DetectError {}
+
+/// Creates a desktop API implementation appropriate for the current platform
+///
+/// A configured `set_command` takes precedence, then a forced `backend`, then detection.
+pub fn create_desktop_api(config: &DesktopConfig) -> Result<Arc<dyn DesktopApi>, DetectError> {
+  if let Some(api) = CommandDesktopApi::from_config(config) {
+    return Ok(Arc::new(api));
+  }
+  if config.backend == Some(DesktopBackend::Command) {
+    return Err(DetectError::Unavailable {
+      backend: DesktopBackend::Command,
+      reason: "set_command is missing or invalid".to_string(),
+    });
+  }
+
+  #[cfg(target_os = "windows")]
+  {
+    match config.backend {
+      None | Some(DesktopBackend::Windows) => Ok(Arc::new(crate::api::WindowsDesktopApi::new())),
+      Some(backend) => Err(DetectError::Unavailable {
+        backend,
+        reason: "only the windows backend exists on Windows".to_string(),
+      }),
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  {
+    let env = Environment::current();
+    let commands = Commands::new();
+
+    if let Some(backend) = config.backend {
+      return match availability(backend, &env, &commands) {
+        Ok(()) => Ok(build(backend)),
+        Err(reason) => Err(DetectError::Unavailable { backend, reason }),
+      };
+    }
+
+    let mut tried = Vec::new();
+    for backend in rank(&env) {
+      match availability(backend, &env, &commands) {
+        Ok(()) => {
+          println!("Using the {backend} desktop backend");
+          return Ok(build(backend));
+        }
+        Err(reason) => tried.push((backend, reason)),
+      }
+    }
+    Err(DetectError::NoBackend { tried })
+  }
+
+  #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+  Err(DetectError::UnsupportedOs(std::env::consts::OS.to_string()))
+}
+
+/// The parts of the session we look at, gathered once so detection can be tested.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+  /// Lowercased entries of the colon separated `XDG_CURRENT_DESKTOP`.
+  pub current_desktop: Vec<String>,
+  /// Lowercased `DESKTOP_SESSION`, without any directory or `.desktop` suffix.
+  pub session: Option<String>,
+  pub wayland: bool,
+  pub x11: bool,
+  pub session_bus: bool,
+  pub sandboxed: bool,
+  pub compositor: Option<Compositor>,
+  /// Lowercased names of the running processes.
+  pub processes: Vec<String>,
+}
+
+#[cfg(target_os = "linux")]
+impl Environment {
+  pub fn current() -> Self {
+    let var = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+    Environment {
+      current_desktop: var("XDG_CURRENT_DESKTOP")
+        .map(|v| parse_current_desktop(&v))
+        .unwrap_or_default(),
+      session: var("DESKTOP_SESSION").map(|v| parse_session(&v)),
+      wayland: var("WAYLAND_DISPLAY").is_some()
+        || var("XDG_SESSION_TYPE").as_deref() == Some("wayland"),
+      x11: var("DISPLAY").is_some(),
+      session_bus: var("DBUS_SESSION_BUS_ADDRESS").is_some()
+        || var("XDG_RUNTIME_DIR")
+          .map(|dir| std::path::Path::new(&dir).join("bus").exists())
+          .unwrap_or(false),
+      sandboxed: crate::api::portal::is_sandboxed(),
+      compositor: Compositor::detect(),
+      processes: running_processes(),
+    }
+  }
+}
+
+/// Split `XDG_CURRENT_DESKTOP`, e.g. `ubuntu:GNOME`, into lowercased entries.
+pub fn parse_current_desktop(value: &str) -> Vec<String> {
+  value
+    .split(':')
+    .map(|part| part.trim().to_lowercase())
+    .filter(|part| !part.is_empty())
+    .collect()
+}
+
+/// Normalize `DESKTOP_SESSION`, which some display managers set to a session file path.
+pub fn parse_session(value: &str) -> String {
+  let name = value.rsplit('/').next().unwrap_or(value);
+  name.trim_end_matches(".desktop").to_lowercase()
+}
+
+/// Backend for a desktop or session name.
+pub fn backend_for_name(name: &str) -> Option<DesktopBackend> {
+  match name {
+    "gnome" | "gnome-classic" | "gnome-flashback" | "gnome-xorg" | "ubuntu" | "ubuntu-xorg"
+    | "unity" | "pop" | "pantheon" | "budgie" | "budgie-desktop" => Some(DesktopBackend::Gnome),
+    "kde" | "plasma" | "plasmawayland" | "plasmax11" => Some(DesktopBackend::Kde),
+    "xfce" | "xubuntu" => Some(DesktopBackend::Xfce),
+    "x-cinnamon" | "cinnamon" | "cinnamon2d" => Some(DesktopBackend::Cinnamon),
+    "mate" => Some(DesktopBackend::Mate),
+    "sway" | "hyprland" | "river" | "wlroots" | "wayfire" | "labwc" => {
+      Some(DesktopBackend::Wlroots)
+    }
+    "i3" | "bspwm" | "openbox" | "awesome" | "dwm" | "herbstluftwm" | "xmonad" | "fluxbox" => {
+      Some(DesktopBackend::X11)
+    }
+    _ => None,
+  }
+}
+
+/// Backend for a process that only runs under a given desktop.
+pub fn backend_for_process(name: &str) -> Option<DesktopBackend> {
+  match name {
+    "gnome-shell" | "budgie-wm" | "budgie-panel" => Some(DesktopBackend::Gnome),
+    "plasmashell" => Some(DesktopBackend::Kde),
+    "xfdesktop" | "xfce4-session" => Some(DesktopBackend::Xfce),
+    "cinnamon" => Some(DesktopBackend::Cinnamon),
+    "mate-session" => Some(DesktopBackend::Mate),
+    "sway" | "hyprland" | "river" => Some(DesktopBackend::Wlroots),
+    _ => None,
+  }
+}
+
+/// Candidate backends for `env`, best first and without duplicates.
+#[cfg(target_os = "linux")]
+pub fn rank(env: &Environment) -> Vec<DesktopBackend> {
+  let mut ranked = Vec::new();
+
+  // Inside a sandbox gsettings and xdg-open are unreachable, only the portals work.
+  if env.sandboxed {
+    ranked.push(DesktopBackend::Portal);
+  }
+  ranked.extend(
+    env
+      .current_desktop
+      .iter()
+      .filter_map(|d| backend_for_name(d)),
+  );
+  ranked.extend(env.session.as_deref().and_then(backend_for_name));
+  if env.compositor.is_some() {
+    ranked.push(DesktopBackend::Wlroots);
+  }
+  ranked.extend(env.processes.iter().filter_map(|p| backend_for_process(p)));
+
+  // Generic fallbacks: a bare X server, then whatever the portal can do.
+  if env.x11 && !env.wayland {
+    ranked.push(DesktopBackend::X11);
+  }
+  if env.session_bus {
+    ranked.push(DesktopBackend::Portal);
+  }
+
+  let mut unique = Vec::new();
+  for backend in ranked {
+    if !unique.contains(&backend) {
+      unique.push(backend);
+    }
+  }
+  unique
+}
+
+/// Check that the tools `backend` relies on are present.
+#[cfg(target_os = "linux")]
+pub fn availability(
+  backend: DesktopBackend,
+  env: &Environment,
+  commands: &Commands,
+) -> Result<(), String> {
+  let any = |programs: &[&str]| -> Result<(), String> {
+    if programs.iter().any(|p| commands.exists(p)) {
+      Ok(())
+    } else {
+      Err(format!("{} not found", programs.join(" or ")))
+    }
+  };
+
+  match backend {
+    DesktopBackend::Gnome | DesktopBackend::Cinnamon | DesktopBackend::Mate => any(&["gsettings"]),
+    DesktopBackend::Kde => any(&["dbus-send", "plasma-apply-wallpaperimage"]),
+    DesktopBackend::Xfce => any(&["xfconf-query"]),
+    DesktopBackend::Wlroots if !env.wayland => Err("not a Wayland session".to_string()),
+    DesktopBackend::Wlroots => any(&["swww", "hyprctl", "swaybg"]),
+    DesktopBackend::X11 if !env.x11 => Err("DISPLAY is not set".to_string()),
+    DesktopBackend::X11 => Ok(()),
+    DesktopBackend::Portal if !env.session_bus => Err("no D-Bus session bus".to_string()),
+    DesktopBackend::Portal => Ok(()),
+    DesktopBackend::Command => Err("set_command is missing or invalid".to_string()),
+    DesktopBackend::Windows => Err("only available on Windows".to_string()),
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn build(backend: DesktopBackend) -> Arc<dyn DesktopApi> {
+  use crate::api::*;
+
+  match backend {
+    DesktopBackend::Gnome => Arc::new(GnomeDesktopApi::new()),
+    DesktopBackend::Kde => Arc::new(KdeDesktopApi::new()),
+    DesktopBackend::Xfce => Arc::new(XfceDesktopApi::new()),
+    DesktopBackend::Cinnamon => Arc::new(GsettingsDesktopApi::new(WallpaperKey::CINNAMON)),
+    DesktopBackend::Mate => Arc::new(GsettingsDesktopApi::new(WallpaperKey::MATE)),
+    DesktopBackend::Wlroots => Arc::new(WlrootsDesktopApi::new()),
+    DesktopBackend::X11 => Arc::new(X11DesktopApi::new()),
+    DesktopBackend::Portal => Arc::new(PortalDesktopApi::new()),
+    DesktopBackend::Command | DesktopBackend::Windows => {
+      unreachable!("{backend} is rejected by availability()")
+    }
+  }
+}
+
+/// Names of the running processes, from `/proc/<pid>/comm`.
+#[cfg(target_os = "linux")]
+fn running_processes() -> Vec<String> {
+  let Ok(entries) = std::fs::read_dir("/proc") else {
+    return Vec::new();
+  };
+  let mut names: Vec<String> = entries
+    .flatten()
+    .filter(|e| {
+      e.file_name()
+        .to_string_lossy()
+        .bytes()
+        .all(|b| b.is_ascii_digit())
+    })
+    .filter_map(|e| std::fs::read_to_string(e.path().join("comm")).ok())
+    .map(|name| name.trim().to_lowercase())
+    .collect();
+  names.sort();
+  names.dedup();
+  names
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+  use DesktopBackend::*;
+
+  #[test]
+  fn parses_colon_separated_desktops() {
+    assert_eq!(parse_current_desktop("ubuntu:GNOME"), ["ubuntu", "gnome"]);
+    assert_eq!(
+      parse_current_desktop("GNOME-Classic:GNOME:"),
+      ["gnome-classic", "gnome"]
+    );
+    assert!(parse_current_desktop("").is_empty());
+    assert_eq!(
+      parse_session("/usr/share/xsessions/plasma.desktop"),
+      "plasma"
+    );
+  }
+
+  #[test]
+  fn ranks_hints_before_fallbacks() {
+    let env = Environment {
+      current_desktop: parse_current_desktop("ubuntu:GNOME"),
+      x11: true,
+      session_bus: true,
+      ..Environment::default()
+    };
+    assert_eq!(rank(&env), [Gnome, X11, Portal]);
+  }
+
+  #[test]
+  fn uses_session_and_processes_when_desktop_is_empty() {
+    let env = Environment {
+      session: Some("plasmawayland".to_string()),
+      processes: vec!["plasmashell".to_string(), "xfdesktop".to_string()],
+      wayland: true,
+      x11: true,
+      ..Environment::default()
+    };
+    assert_eq!(rank(&env), [Kde, Xfce]);
+  }
+
+  #[test]
+  fn sandbox_prefers_the_portal() {
+    let env = Environment {
+      current_desktop: vec!["kde".to_string()],
+      sandboxed: true,
+      session_bus: true,
+      ..Environment::default()
+    };
+    assert_eq!(rank(&env), [Portal, Kde]);
+  }
+
+  #[test]
+  fn checks_required_tools() {
+    let dir = tempfile::tempdir().unwrap();
+    let commands = Commands::with_path(dir.path());
+    let env = Environment {
+      wayland: true,
+      ..Environment::default()
+    };
+
+    assert!(availability(Gnome, &env, &commands).is_err());
+    assert!(availability(X11, &env, &commands).is_err());
+    recording_command(dir.path(), "gsettings");
+    recording_command(dir.path(), "swaybg");
+    assert_eq!(availability(Gnome, &env, &commands), Ok(()));
+    assert_eq!(availability(Wlroots, &env, &commands), Ok(()));
+  }
+
+  #[test]
+  fn error_lists_what_was_tried() {
+    let err = DetectError::NoBackend {
+      tried: vec![(Gnome, "gsettings not found".to_string())],
+    };
+    assert_eq!(
+      err.to_string(),
+      "no usable desktop backend found (gnome: gsettings not found), set `backend` or `set_command` in the [Desktop] section"
+    );
+  }
+}
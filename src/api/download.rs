@@ -0,0 +1,471 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Streaming post downloads: retries with backoff, `Range` resume and atomic writes.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::constants::USER_AGENT;
+use crate::media::{InvalidMedia, LimitBreach};
+use crate::policy::{self, DownloadPolicy, PolicyViolation};
+
+/// How often and how patiently a download is retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+  /// Attempts after the first one.
+  pub retries: u32,
+  /// Wait before the first retry, doubled for every following one.
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      retries: 4,
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(30),
+    }
+  }
+}
+
+impl RetryPolicy {
+  fn backoff(&self, attempt: u32) -> Duration {
+    self
+      .initial_backoff
+      .saturating_mul(2u32.saturating_pow(attempt))
+      .min(self.max_backoff)
+  }
+}
+
+/// A finished download.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Download {
+  pub path: PathBuf,
+  pub content_type: Option<String>,
+  pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+  /// The URL or a redirect broke the `DownloadPolicy`.
+  Policy(PolicyViolation),
+  /// The server answered with something we won't take, see `InvalidMedia`.
+  Rejected(InvalidMedia),
+  Http(reqwest::Error),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for DownloadError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DownloadError::Policy(violation) => write!(f, "download blocked: {violation}"),
+      DownloadError::Rejected(invalid) => write!(f, "download rejected: {invalid}"),
+      DownloadError::Http(e) => write!(f, "HTTP error: {e}"),
+      DownloadError::Io(e) => write!(f, "IO error: {e}"),
+    }
+  }
+}
+
+impl Error for DownloadError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      DownloadError::Policy(violation) => Some(violation),
+      DownloadError::Rejected(invalid) => Some(invalid),
+      DownloadError::Http(e) => Some(e),
+      DownloadError::Io(e) => Some(e),
+    }
+  }
+}
+
+impl DownloadError {
+  /// Whether trying again might succeed.
+  fn is_transient(&self) -> bool {
+    match self {
+      DownloadError::Http(_) => true,
+      DownloadError::Rejected(InvalidMedia::Status(status)) => {
+        *status == 429 || (500..600).contains(status)
+      }
+      DownloadError::Rejected(InvalidMedia::Truncated { .. }) => true,
+      _ => false,
+    }
+  }
+}
+
+impl From<std::io::Error> for DownloadError {
+  fn from(e: std::io::Error) -> Self {
+    DownloadError::Io(e)
+  }
+}
+
+impl From<reqwest::Error> for DownloadError {
+  fn from(e: reqwest::Error) -> Self {
+    // A refused redirect surfaces as a reqwest error wrapping our violation.
+    match policy::find_violation(&e) {
+      Some(violation) => DownloadError::Policy(violation.clone()),
+      None => DownloadError::Http(e),
+    }
+  }
+}
+
+/// Downloads post images through one pooled client that enforces a `DownloadPolicy`.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+  client: reqwest::Client,
+  policy: DownloadPolicy,
+  retry: RetryPolicy,
+}
+
+impl Downloader {
+  pub fn new(policy: DownloadPolicy, retry: RetryPolicy) -> Self {
+    let client = reqwest::Client::builder()
+      .user_agent(USER_AGENT)
+      .redirect(policy.redirect_policy())
+      .https_only(policy.requires_https())
+      .connect_timeout(Duration::from_secs(15))
+      .read_timeout(Duration::from_secs(30))
+      .build()
+      .expect("failed to build the download client");
+    Downloader {
+      client,
+      policy,
+      retry,
+    }
+  }
+
+  pub fn policy(&self) -> &DownloadPolicy {
+    &self.policy
+  }
+
+  /// Download `url` to `dest`, giving up once more than `max_bytes` arrive.
+  ///
+  /// The body is streamed to `<dest>.part`, which is kept between attempts so a retry can
+  /// resume with a `Range` request. Only a complete body is synced and renamed to `dest`, so
+  /// `dest` never holds a partial file.
+  pub async fn download(
+    &self,
+    url: &str,
+    dest: &Path,
+    max_bytes: u64,
+  ) -> Result<Download, DownloadError> {
+    let url = self.policy.check_str(url).map_err(DownloadError::Policy)?;
+    let part = part_path(dest);
+
+    let mut attempt = 0;
+    let result = loop {
+      match self.attempt(url.as_str(), &part, max_bytes).await {
+        Ok(download) => break Ok(download),
+        Err(e) if e.is_transient() && attempt < self.retry.retries => {
+          let wait = self.retry.backoff(attempt);
+          eprintln!("Download of {url} failed ({e}), retrying in {wait:?}");
+          tokio::time::sleep(wait).await;
+          attempt += 1;
+        }
+        Err(e) => break Err(e),
+      }
+    };
+
+    match result {
+      Ok((content_type, size)) => {
+        fs::rename(&part, dest).await?;
+        sync_parent(dest).await;
+        Ok(Download {
+          path: dest.to_path_buf(),
+          content_type,
+          size,
+        })
+      }
+      Err(e) => {
+        let _ = fs::remove_file(&part).await;
+        Err(e)
+      }
+    }
+  }
+
+  /// One request, appending to whatever an earlier attempt left in `part`.
+  async fn attempt(
+    &self,
+    url: &str,
+    part: &Path,
+    max_bytes: u64,
+  ) -> Result<(Option<String>, u64), DownloadError> {
+    let existing = fs::metadata(part).await.map(|m| m.len()).unwrap_or(0);
+    let mut request = self.client.get(url);
+    if existing > 0 {
+      request = request.header(RANGE, format!("bytes={existing}-"));
+    }
+    let mut response = request.send().await?;
+
+    let status = response.status();
+    let resumed = status == StatusCode::PARTIAL_CONTENT && existing > 0;
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+      // Whatever we kept doesn't match the file any more, start over.
+      let _ = fs::remove_file(part).await;
+      return Err(DownloadError::Rejected(InvalidMedia::Truncated {
+        expected: 0,
+        received: existing,
+      }));
+    }
+    if !status.is_success() {
+      return Err(DownloadError::Rejected(InvalidMedia::Status(
+        status.as_u16(),
+      )));
+    }
+
+    let content_type = response
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|v| v.to_str().ok())
+      .map(str::to_string);
+    let offset = if resumed { existing } else { 0 };
+    let expected = if resumed {
+      total_from_content_range(&response)
+    } else {
+      response.content_length()
+    };
+    let too_large = |size| {
+      DownloadError::Rejected(InvalidMedia::LimitExceeded(LimitBreach::Bytes {
+        limit: max_bytes,
+        size,
+      }))
+    };
+    if let Some(expected) = expected
+      && expected > max_bytes
+    {
+      return Err(too_large(expected));
+    }
+
+    let mut file = if resumed {
+      OpenOptions::new().append(true).open(part).await?
+    } else {
+      File::create(part).await?
+    };
+    let mut received = offset;
+    while let Some(chunk) = response.chunk().await? {
+      received += chunk.len() as u64;
+      if received > max_bytes {
+        return Err(too_large(received));
+      }
+      file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+
+    if let Some(expected) = expected
+      && expected != received
+    {
+      return Err(DownloadError::Rejected(InvalidMedia::Truncated {
+        expected,
+        received,
+      }));
+    }
+    Ok((content_type, received))
+  }
+}
+
+/// `<dest>.part`, next to `dest` so the final rename stays on one filesystem.
+fn part_path(dest: &Path) -> PathBuf {
+  let mut name = dest.file_name().unwrap_or_default().to_os_string();
+  name.push(".part");
+  dest.with_file_name(name)
+}
+
+/// Total size from a `Content-Range: bytes 100-199/200` header.
+fn total_from_content_range(response: &reqwest::Response) -> Option<u64> {
+  let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+  value.rsplit_once('/')?.1.parse().ok()
+}
+
+/// Make the rename itself durable, where the platform allows it.
+async fn sync_parent(path: &Path) {
+  #[cfg(unix)]
+  if let Some(parent) = path.parent()
+    && let Ok(dir) = File::open(parent).await
+  {
+    let _ = dir.sync_all().await;
+  }
+  #[cfg(not(unix))]
+  let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+  use tokio::io::AsyncReadExt;
+  use tokio::net::TcpListener;
+  use url::Url;
+
+  const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+  /// A one-shot HTTP server answering the n-th request with `responses[n]`, closing the
+  /// connection after each. Returns its base URL and the request heads it saw.
+  async fn serve(responses: Vec<Vec<u8>>) -> (Url, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+      for response in responses {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+          let n = socket.read(&mut buf).await.unwrap();
+          head.extend_from_slice(&buf[..n]);
+        }
+        seen
+          .lock()
+          .unwrap()
+          .push(String::from_utf8_lossy(&head).to_lowercase());
+        let _ = socket.write_all(&response).await;
+        let _ = socket.shutdown().await;
+      }
+    });
+    (url, requests)
+  }
+
+  fn response(status: &str, headers: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut out = format!("HTTP/1.1 {status}\r\nconnection: close\r\n");
+    for header in headers {
+      out.push_str(header);
+      out.push_str("\r\n");
+    }
+    out.push_str("\r\n");
+    let mut out = out.into_bytes();
+    out.extend_from_slice(body);
+    out
+  }
+
+  fn downloader(url: &Url) -> Downloader {
+    Downloader::new(
+      DownloadPolicy::insecure(&[url]),
+      RetryPolicy {
+        retries: 2,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+      },
+    )
+  }
+
+  #[tokio::test]
+  async fn resumes_an_interrupted_body() {
+    let length = format!("content-length: {}", BODY.len());
+    let range = format!("content-range: bytes 10-{}/{}", BODY.len() - 1, BODY.len());
+    let rest_length = format!("content-length: {}", BODY.len() - 10);
+    let (url, requests) = serve(vec![
+      response("200 OK", &[&length, "content-type: image/png"], &BODY[..10]),
+      response("206 Partial Content", &[&range, &rest_length], &BODY[10..]),
+    ])
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("post.png");
+
+    let download = downloader(&url)
+      .download(url.join("post.png").unwrap().as_str(), &dest, 1024)
+      .await
+      .unwrap();
+
+    assert_eq!(download.size, BODY.len() as u64);
+    assert_eq!(std::fs::read(&dest).unwrap(), BODY);
+    assert!(!part_path(&dest).exists());
+    let requests = requests.lock().unwrap();
+    assert!(!requests[0].contains("range:"));
+    assert!(requests[1].contains("range: bytes=10-"));
+    assert!(requests[0].contains("user-agent: loxerpaper/"));
+  }
+
+  #[tokio::test]
+  async fn retries_server_errors_but_not_client_errors() {
+    let length = format!("content-length: {}", BODY.len());
+    let (url, _) = serve(vec![
+      response("503 Service Unavailable", &["content-length: 0"], b""),
+      response("200 OK", &[&length], BODY),
+      response("404 Not Found", &["content-length: 0"], b""),
+    ])
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    let downloader = downloader(&url);
+
+    let dest = dir.path().join("a.png");
+    downloader
+      .download(url.as_str(), &dest, 1024)
+      .await
+      .unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), BODY);
+
+    let dest = dir.path().join("b.png");
+    let err = downloader
+      .download(url.as_str(), &dest, 1024)
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      err,
+      DownloadError::Rejected(InvalidMedia::Status(404))
+    ));
+    assert!(!dest.exists());
+    assert!(!part_path(&dest).exists());
+  }
+
+  #[tokio::test]
+  async fn stops_at_the_byte_limit() {
+    let (url, _) = serve(vec![response("200 OK", &[], BODY)]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let dest = dir.path().join("big.png");
+
+    let err = downloader(&url)
+      .download(url.as_str(), &dest, 8)
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      err,
+      DownloadError::Rejected(InvalidMedia::LimitExceeded(LimitBreach::Bytes {
+        limit: 8,
+        ..
+      }))
+    ));
+    assert!(!dest.exists());
+  }
+
+  #[tokio::test]
+  async fn refuses_redirects_off_the_allowlist() {
+    let (url, _) = serve(vec![response(
+      "302 Found",
+      &["location: http://evil.example/x.png", "content-length: 0"],
+      b"",
+    )])
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+
+    let err = downloader(&url)
+      .download(url.as_str(), &dir.path().join("x.png"), 1024)
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      err,
+      DownloadError::Policy(PolicyViolation::HostNotAllowed(_))
+    ));
+  }
+}
@@ -0,0 +1,415 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Pieces shared by the Linux desktop backends: freedesktop notifications, `xdg-open`,
+//! and running helper commands.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+use notify_rust::Notification as NotifyRustNotification;
+
+use crate::api::{DesktopApiError, Icon, Notification};
+
+/// Builds the helper commands a backend runs.
+///
+/// A custom `PATH` can be set so tests can substitute fake binaries.
+#[derive(Debug, Clone, Default)]
+pub struct Commands {
+  path: Option<OsString>,
+}
+
+impl Commands {
+  pub fn new() -> Self {
+    Commands { path: None }
+  }
+
+  /// Look up programs in `path` instead of the inherited `PATH`.
+  pub fn with_path(path: impl Into<OsString>) -> Self {
+    Commands {
+      path: Some(path.into()),
+    }
+  }
+
+  pub fn command(&self, program: &str) -> Command {
+    let mut command = Command::new(program);
+    if let Some(path) = &self.path {
+      command.env("PATH", path);
+    }
+    command
+  }
+
+  /// Whether `program` can be found on the search path.
+  pub fn exists(&self, program: &str) -> bool {
+    let path = self.path.clone().or_else(|| std::env::var_os("PATH"));
+    path
+      .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+      .unwrap_or(false)
+  }
+
+  /// Run `program` to completion, mapping a failed exit status to `DesktopApiError::Backend`.
+  pub fn run<I, S>(&self, program: &str, args: I) -> Result<(), DesktopApiError>
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+  {
+    let status = self
+      .command(program)
+      .args(args)
+      .status()
+      .map_err(DesktopApiError::Io)?;
+
+    if status.success() {
+      Ok(())
+    } else {
+      Err(DesktopApiError::Backend(format!(
+        "{program} failed with exit code: {status}"
+      )))
+    }
+  }
+
+  /// Run `program` and return its standard output.
+  pub fn output<I, S>(&self, program: &str, args: I) -> Result<String, DesktopApiError>
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+  {
+    let output = self
+      .command(program)
+      .args(args)
+      .output()
+      .map_err(DesktopApiError::Io)?;
+
+    if output.status.success() {
+      Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+      Err(DesktopApiError::Backend(format!(
+        "{program} failed with exit code: {}",
+        output.status
+      )))
+    }
+  }
+}
+
+/// Show a notification through the freedesktop notification service.
+pub fn send_notification(notification: &Notification) -> Result<(), DesktopApiError> {
+  build(notification).show().map_err(notify_error)?;
+  Ok(())
+}
+
+/// Show a notification and block until one of its actions is invoked or it is closed.
+///
+/// Returns the id of the invoked action, `None` when the notification was closed.
+pub fn wait_for_action(notification: &Notification) -> Result<Option<String>, DesktopApiError> {
+  let handle = build(notification).show().map_err(notify_error)?;
+  let mut invoked = None;
+  handle.wait_for_action(|action| {
+    if action != "__closed" {
+      invoked = Some(action.to_string());
+    }
+  });
+  Ok(invoked)
+}
+
+fn notify_error(e: notify_rust::error::Error) -> DesktopApiError {
+  DesktopApiError::Backend(format!("notify-rust error: {e}"))
+}
+
+fn build(notification: &Notification) -> NotifyRustNotification {
+  let mut n = NotifyRustNotification::new();
+  n.summary(&notification.title);
+  if let Some(body) = &notification.body {
+    n.body(body);
+  }
+
+  if let Some(icon) = &notification.icon {
+    match icon {
+      Icon::Path(p) => {
+        n.icon(p.to_string_lossy().as_ref());
+      }
+      Icon::Resource(name) => {
+        n.icon(name);
+      }
+      Icon::Raw(bytes) => {
+        // notify-rust doesn't accept raw bytes; write a temp file fallback
+        if let Ok(mut tmp) = tempfile::Builder::new().suffix(".png").tempfile() {
+          use std::io::Write;
+          if tmp.write_all(bytes).is_ok()
+            && let Ok(path) = tmp.into_temp_path().keep()
+          {
+            n.icon(path.to_string_lossy().as_ref());
+          }
+        }
+      }
+    }
+  }
+
+  // Map urgency using hint - TODO: figure out correct API
+  // let urgency_hint = match notification.urgency {
+  //   crate::api::Urgency::Low => notify_rust::Hint::Urgency(notify_rust::Urgency::Low),
+  //   crate::api::Urgency::Normal => notify_rust::Hint::Urgency(notify_rust::Urgency::Normal),
+  //   crate::api::Urgency::Critical => notify_rust::Hint::Urgency(notify_rust::Urgency::Critical),
+  // };
+  // n.hint(urgency_hint);
+
+  // timeout
+  if let Some(t) = notification.timeout {
+    n.timeout(t.as_millis() as i32);
+  }
+
+  for action in &notification.actions {
+    n.action(&action.id, &action.title);
+  }
+  n
+}
+
+/// Programs able to show an input dialog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialogTool {
+  Zenity,
+  Kdialog,
+}
+
+impl DialogTool {
+  fn program(self) -> &'static str {
+    match self {
+      DialogTool::Zenity => "zenity",
+      DialogTool::Kdialog => "kdialog",
+    }
+  }
+}
+
+/// Whether any of `tools` is installed.
+pub fn has_dialog_tool(commands: &Commands, tools: &[DialogTool]) -> bool {
+  tools.iter().any(|t| commands.exists(t.program()))
+}
+
+/// Ask the user to pick one of `choices` and type a reply, with the first installed of `tools`.
+///
+/// Returns the index of the choice and the text, `None` when the dialog was cancelled.
+pub fn reply_dialog(
+  commands: &Commands,
+  tools: &[DialogTool],
+  title: &str,
+  choices: &[&str],
+) -> Result<Option<(usize, String)>, DesktopApiError> {
+  let Some(tool) = tools.iter().find(|t| commands.exists(t.program())) else {
+    return Err(DesktopApiError::Unsupported);
+  };
+
+  let (choice, text) = match tool {
+    DialogTool::Zenity => {
+      // A single form, one field per line.
+      let args = [
+        "--forms".to_string(),
+        format!("--title={title}"),
+        format!("--text={title}"),
+        "--add-combo=Reaction".to_string(),
+        format!("--combo-values={}", choices.join("|")),
+        "--add-entry=Message".to_string(),
+        "--separator=\n".to_string(),
+      ];
+      let Some(output) = dialog_output(commands, "zenity", args)? else {
+        return Ok(None);
+      };
+      let (choice, text) = output.split_once('\n').unwrap_or((&output, ""));
+      (choice.to_string(), text.to_string())
+    }
+    DialogTool::Kdialog => {
+      let mut args = vec!["--title", title, "--combobox", "Reaction"];
+      args.extend(choices);
+      args.extend(["--default", choices.first().copied().unwrap_or_default()]);
+      let Some(choice) = dialog_output(commands, "kdialog", args)? else {
+        return Ok(None);
+      };
+      let args = ["--title", title, "--inputbox", "Message"];
+      let Some(text) = dialog_output(commands, "kdialog", args)? else {
+        return Ok(None);
+      };
+      (choice, text)
+    }
+  };
+
+  match choices.iter().position(|c| *c == choice) {
+    Some(index) => Ok(Some((index, text))),
+    None => Err(DesktopApiError::Backend(format!(
+      "{} returned an unknown choice: {choice:?}",
+      tool.program()
+    ))),
+  }
+}
+
+/// Run a dialog program and return what it printed, `None` when the user cancelled it.
+fn dialog_output<I, S>(
+  commands: &Commands,
+  program: &str,
+  args: I,
+) -> Result<Option<String>, DesktopApiError>
+where
+  I: IntoIterator<Item = S>,
+  S: AsRef<std::ffi::OsStr>,
+{
+  let output = commands
+    .command(program)
+    .args(args)
+    .output()
+    .map_err(DesktopApiError::Io)?;
+
+  match output.status.code() {
+    Some(0) => {
+      let stdout = String::from_utf8_lossy(&output.stdout);
+      Ok(Some(
+        stdout.strip_suffix('\n').unwrap_or(&stdout).to_string(),
+      ))
+    }
+    // Both zenity and kdialog exit with 1 on Cancel or when the dialog is closed.
+    Some(1) => Ok(None),
+    _ => Err(DesktopApiError::Backend(format!(
+      "{program} failed with exit code: {}",
+      output.status
+    ))),
+  }
+}
+
+/// Open `file` with the default application through `xdg-open`.
+pub fn open_file(commands: &Commands, file: &Path) -> Result<(), DesktopApiError> {
+  if !file.exists() {
+    return Err(DesktopApiError::InvalidNotification(format!(
+      "file path {file:?} does not exist"
+    )));
+  }
+
+  // Use xdg-open to open the file with the default application
+  commands.run("xdg-open", [file])?;
+  println!("Successfully opened file {file:?}");
+  Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+  use std::fs;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::{Path, PathBuf};
+
+  /// Write an executable shell script named `name` into `dir`.
+  pub fn fake_command(dir: &Path, name: &str, script: &str) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+  }
+
+  /// A fake command that appends its arguments, one per line, to `<dir>/<name>.log`.
+  pub fn recording_command(dir: &Path, name: &str) -> PathBuf {
+    let log = dir.join(format!("{name}.log"));
+    fake_command(
+      dir,
+      name,
+      &format!(
+        "for a in \"$@\"; do printf '%s\\n' \"$a\" >> '{}'; done",
+        log.display()
+      ),
+    )
+  }
+
+  /// Arguments recorded by a `recording_command`.
+  pub fn recorded(dir: &Path, name: &str) -> Vec<String> {
+    fs::read_to_string(dir.join(format!("{name}.log")))
+      .unwrap_or_default()
+      .lines()
+      .map(str::to_string)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::test_support::*;
+  use super::*;
+
+  #[test]
+  fn run_uses_custom_path_and_reports_failures() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "ok-tool");
+    fake_command(dir.path(), "bad-tool", "exit 3");
+    let commands = Commands::with_path(dir.path());
+
+    commands.run("ok-tool", ["a b", "c"]).unwrap();
+    assert_eq!(recorded(dir.path(), "ok-tool"), ["a b", "c"]);
+    assert!(commands.exists("ok-tool"));
+    assert!(!commands.exists("missing-tool"));
+
+    let err = commands.run("bad-tool", [""; 0]).unwrap_err();
+    assert!(matches!(err, DesktopApiError::Backend(_)));
+  }
+
+  #[test]
+  fn reply_dialog_reads_zenity_forms() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("zenity.log");
+    fake_command(
+      dir.path(),
+      "zenity",
+      &format!(
+        "printf '%s\\n' \"$@\" > '{}'\nprintf 'Came\\nso good | really\\n'",
+        log.display()
+      ),
+    );
+    recording_command(dir.path(), "kdialog");
+    let commands = Commands::with_path(dir.path());
+    let tools = [DialogTool::Zenity, DialogTool::Kdialog];
+
+    let reply = reply_dialog(&commands, &tools, "Reply", &["Horny", "Disgust", "Came"]).unwrap();
+    assert_eq!(reply, Some((2, "so good | really".to_string())));
+    let args = std::fs::read_to_string(log).unwrap();
+    assert!(args.contains("--combo-values=Horny|Disgust|Came"));
+    assert!(recorded(dir.path(), "kdialog").is_empty());
+  }
+
+  #[test]
+  fn reply_dialog_falls_back_to_kdialog() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_command(
+      dir.path(),
+      "kdialog",
+      "case \"$3\" in --combobox) echo Disgust ;; *) echo 'no thanks' ;; esac",
+    );
+    let commands = Commands::with_path(dir.path());
+    let tools = [DialogTool::Zenity, DialogTool::Kdialog];
+
+    let reply = reply_dialog(&commands, &tools, "Reply", &["Horny", "Disgust"]).unwrap();
+    assert_eq!(reply, Some((1, "no thanks".to_string())));
+  }
+
+  #[test]
+  fn reply_dialog_reports_cancel_and_missing_tools() {
+    let dir = tempfile::tempdir().unwrap();
+    let commands = Commands::with_path(dir.path());
+    let err = reply_dialog(&commands, &[DialogTool::Zenity], "Reply", &["Horny"]).unwrap_err();
+    assert!(matches!(err, DesktopApiError::Unsupported));
+
+    fake_command(dir.path(), "zenity", "exit 1");
+    let reply = reply_dialog(&commands, &[DialogTool::Zenity], "Reply", &["Horny"]).unwrap();
+    assert_eq!(reply, None);
+
+    fake_command(dir.path(), "zenity", "exit 5");
+    let err = reply_dialog(&commands, &[DialogTool::Zenity], "Reply", &["Horny"]).unwrap_err();
+    assert!(matches!(err, DesktopApiError::Backend(_)));
+  }
+}
@@ -16,21 +16,76 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
-use std::process::Command;
-// std::time::Duration not needed here
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use notify_rust::Notification as NotifyRustNotification;
-
-use crate::api::DesktopApi;
-use crate::api::{DesktopApiError, DesktopCapabilities, Icon, Notification};
+use crate::api::freedesktop::{self, Commands};
+use crate::api::gsettings::{self, WallpaperKey};
+use crate::api::outputs::compose_spanned;
+use crate::api::{DesktopApi, Output};
+use crate::api::{DesktopApiError, DesktopCapabilities, Notification};
 
 /// GNOME implementation of DesktopApi using notify-rust for notifications and gsettings for wallpaper.
-pub struct GnomeDesktopApi {}
+///
+/// GNOME only knows a single background, so per-output wallpapers are composed into one
+/// image spanning every monitor.
+pub struct GnomeDesktopApi {
+  commands: Commands,
+  span: Mutex<SpanState>,
+}
+
+#[derive(Default)]
+struct SpanState {
+  /// Image assigned to each output, by output name.
+  images: HashMap<String, PathBuf>,
+  /// Last image set for the whole desktop, shown on outputs without their own image.
+  fallback: Option<PathBuf>,
+  /// Alternates the composed file name, GNOME ignores a URI that did not change.
+  generation: u64,
+}
 
 impl GnomeDesktopApi {
   pub fn new() -> Self {
-    GnomeDesktopApi {}
+    Self::with_commands(Commands::new())
+  }
+
+  pub fn with_commands(commands: Commands) -> Self {
+    GnomeDesktopApi {
+      commands,
+      span: Mutex::new(SpanState::default()),
+    }
+  }
+
+  fn set_picture_uri(&self, image: &Path) -> Result<(), DesktopApiError> {
+    // Set both light and dark mode wallpapers to ensure it works regardless of color scheme
+    WallpaperKey::GNOME.set(&self.commands, image)?;
+    WallpaperKey::GNOME_DARK.set(&self.commands, image)
+  }
+
+  /// Compose the images of `span` across `outputs` and show the result spanned.
+  fn show_spanned(&self, span: &mut SpanState, outputs: &[Output]) -> Result<(), DesktopApiError> {
+    let layout: Vec<(Output, Option<&Path>)> = outputs
+      .iter()
+      .map(|o| {
+        let image = span.images.get(&o.name).or(span.fallback.as_ref());
+        (o.clone(), image.map(PathBuf::as_path))
+      })
+      .collect();
+    let canvas = compose_spanned(&layout)?;
+
+    let mut target = dirs_next::cache_dir().unwrap_or_else(std::env::temp_dir);
+    target.push("loxerpaper");
+    std::fs::create_dir_all(&target)?;
+    target.push(format!("spanned-{}.png", span.generation % 2));
+    span.generation += 1;
+    canvas
+      .save(&target)
+      .map_err(|e| DesktopApiError::Backend(format!("failed to save {target:?}: {e}")))?;
+
+    let background = WallpaperKey::GNOME.schema;
+    gsettings::set(&self.commands, background, "picture-options", "spanned")?;
+    self.set_picture_uri(&target)
   }
 }
 
@@ -42,34 +97,40 @@ impl DesktopApi for GnomeDesktopApi {
       )));
     }
 
-    // Try to set via gsettings for org.gnome.desktop.background picture-uri
-    // Use file:// URI
-    let uri = format!("file://{}", image.display());
-
-    // Set both light and dark mode wallpapers to ensure it works regardless of color scheme
-    let status_light = Command::new("gsettings")
-      .args(["set", "org.gnome.desktop.background", "picture-uri", &uri])
-      .status()
-      .map_err(DesktopApiError::Io)?;
+    let mut span = self.span.lock().unwrap();
+    span.fallback = Some(image.to_path_buf());
 
-    let status_dark = Command::new("gsettings")
-      .args([
-        "set",
-        "org.gnome.desktop.background",
-        "picture-uri-dark",
-        &uri,
-      ])
-      .status()
-      .map_err(DesktopApiError::Io)?;
-
-    if status_light.success() && status_dark.success() {
-      println!("Successfully changed wallpaper to {image:?}");
-      Ok(())
+    // Pinned outputs keep their image, the new one only fills the others.
+    if !span.images.is_empty() {
+      self.show_spanned(&mut span, &self.outputs()?)?;
     } else {
-      Err(DesktopApiError::Backend(format!(
-        "gsettings failed - light: {status_light}, dark: {status_dark}"
-      )))
+      self.set_picture_uri(image)?;
     }
+
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn change_background_on(&self, output: &str, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let outputs = self.outputs()?;
+    if !outputs.iter().any(|o| o.name == output) {
+      return Err(DesktopApiError::Backend(format!(
+        "output {output} is not connected"
+      )));
+    }
+
+    let mut span = self.span.lock().unwrap();
+    span.images.insert(output.to_string(), image.to_path_buf());
+    self.show_spanned(&mut span, &outputs)?;
+
+    println!("Successfully changed wallpaper of {output} to {image:?}");
+    Ok(())
   }
 
   fn capabilities(&self) -> DesktopCapabilities {
@@ -79,80 +140,62 @@ impl DesktopApi for GnomeDesktopApi {
       set_wallpaper: true,
       raw_icon_bytes: true,
       open_file: true,
+      per_output_wallpaper: true,
+      animated_wallpaper: false,
     }
   }
 
   fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
-    let mut n = NotifyRustNotification::new();
-    n.summary(&notification.title);
-    if let Some(body) = &notification.body {
-      n.body(body);
-    }
-
-    if let Some(icon) = &notification.icon {
-      match icon {
-        Icon::Path(p) => {
-          n.icon(p.to_string_lossy().as_ref());
-        }
-        Icon::Resource(name) => {
-          n.icon(name);
-        }
-        Icon::Raw(bytes) => {
-          // notify-rust doesn't accept raw bytes; write a temp file fallback
-          if let Ok(mut tmp) = tempfile::Builder::new().suffix(".png").tempfile() {
-            use std::io::Write;
-            if tmp.write_all(bytes).is_ok()
-              && let Ok(path) = tmp.into_temp_path().keep()
-            {
-              n.icon(path.to_string_lossy().as_ref());
-            }
-          }
-        }
-      }
-    }
+    freedesktop::send_notification(notification)
+  }
 
-    // Map urgency using hint - TODO: figure out correct API
-    // let urgency_hint = match notification.urgency {
-    //   crate::api::Urgency::Low => notify_rust::Hint::Urgency(notify_rust::Urgency::Low),
-    //   crate::api::Urgency::Normal => notify_rust::Hint::Urgency(notify_rust::Urgency::Normal),
-    //   crate::api::Urgency::Critical => notify_rust::Hint::Urgency(notify_rust::Urgency::Critical),
-    // };
-    // n.hint(urgency_hint);
-
-    // timeout
-    if let Some(t) = notification.timeout {
-      n.timeout(t.as_millis() as i32);
-    }
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
 
-    for action in &notification.actions {
-      n.action(&action.id, &action.title);
-    }
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+}
 
-    n.show()
-      .map_err(|e| DesktopApiError::Backend(format!("notify-rust error: {e}")))?;
-    Ok(())
+impl Default for GnomeDesktopApi {
+  fn default() -> Self {
+    Self::new()
   }
+}
 
-  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
-    if !file.exists() {
-      return Err(DesktopApiError::InvalidNotification(format!(
-        "file path {file:?} does not exist"
-      )));
-    }
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
 
-    // Use xdg-open to open the file with the default application
-    let status = Command::new("xdg-open")
-      .arg(file)
-      .status()
-      .map_err(DesktopApiError::Io)?;
+  #[test]
+  fn sets_light_and_dark_wallpapers() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "gsettings");
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+    let api = GnomeDesktopApi::with_commands(Commands::with_path(dir.path()));
 
-    if status.success() {
-      println!("Successfully opened file {file:?}");
-      Ok(())
-    } else {
-      Err(DesktopApiError::Backend(format!(
-        "xdg-open failed with exit code: {status}"
-      )))
-    }
+    api.change_background(&image).unwrap();
+
+    let uri = format!("file://{}", image.display());
+    let schema = "org.gnome.desktop.background";
+    assert_eq!(
+      recorded(dir.path(), "gsettings"),
+      [
+        "set",
+        schema,
+        "picture-uri",
+        uri.as_str(),
+        "set",
+        schema,
+        "picture-uri-dark",
+        uri.as_str(),
+      ]
+    );
   }
 }
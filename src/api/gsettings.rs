@@ -0,0 +1,175 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Wallpapers stored in `gsettings`, shared by GNOME and the desktops forked from it.
+
+use std::path::Path;
+
+use crate::api::freedesktop::{self, Commands};
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification};
+
+/// A gsettings key holding the wallpaper.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WallpaperKey {
+  pub schema: &'static str,
+  pub key: &'static str,
+  /// Whether the key takes a `file://` URI rather than a plain path.
+  pub uri: bool,
+}
+
+impl WallpaperKey {
+  pub const GNOME: WallpaperKey = WallpaperKey {
+    schema: "org.gnome.desktop.background",
+    key: "picture-uri",
+    uri: true,
+  };
+  /// GNOME's wallpaper under the dark style. Written along with `GNOME` so the wallpaper
+  /// follows either color scheme.
+  pub const GNOME_DARK: WallpaperKey = WallpaperKey {
+    key: "picture-uri-dark",
+    ..WallpaperKey::GNOME
+  };
+  pub const CINNAMON: WallpaperKey = WallpaperKey {
+    schema: "org.cinnamon.desktop.background",
+    key: "picture-uri",
+    uri: true,
+  };
+  pub const MATE: WallpaperKey = WallpaperKey {
+    schema: "org.mate.background",
+    key: "picture-filename",
+    uri: false,
+  };
+
+  /// Point the key at `image`.
+  pub fn set(&self, commands: &Commands, image: &Path) -> Result<(), DesktopApiError> {
+    // `picture-uri` style keys take a `file://` URI.
+    let value = if self.uri {
+      format!("file://{}", image.display())
+    } else {
+      image.display().to_string()
+    };
+    set(commands, self.schema, self.key, &value)
+  }
+}
+
+/// Set `key` of `schema` to `value`.
+pub fn set(
+  commands: &Commands,
+  schema: &str,
+  key: &str,
+  value: &str,
+) -> Result<(), DesktopApiError> {
+  commands.run("gsettings", ["set", schema, key, value])
+}
+
+/// DesktopApi for desktops whose wallpaper is a single gsettings key, such as Cinnamon
+/// (`WallpaperKey::CINNAMON`) and MATE (`WallpaperKey::MATE`).
+pub struct GsettingsDesktopApi {
+  commands: Commands,
+  key: WallpaperKey,
+}
+
+impl GsettingsDesktopApi {
+  pub fn new(key: WallpaperKey) -> Self {
+    Self::with_commands(Commands::new(), key)
+  }
+
+  pub fn with_commands(commands: Commands, key: WallpaperKey) -> Self {
+    GsettingsDesktopApi { commands, key }
+  }
+}
+
+impl DesktopApi for GsettingsDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    self.key.set(&self.commands, image)?;
+
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: false,
+      animated_wallpaper: false,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    freedesktop::send_notification(notification)
+  }
+
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+
+  #[test]
+  fn sets_the_schema_of_each_desktop() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "gsettings");
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+    let commands = || Commands::with_path(dir.path());
+
+    GsettingsDesktopApi::with_commands(commands(), WallpaperKey::CINNAMON)
+      .change_background(&image)
+      .unwrap();
+    GsettingsDesktopApi::with_commands(commands(), WallpaperKey::MATE)
+      .change_background(&image)
+      .unwrap();
+
+    let path = image.display().to_string();
+    let uri = format!("file://{path}");
+    assert_eq!(
+      recorded(dir.path(), "gsettings"),
+      [
+        "set",
+        "org.cinnamon.desktop.background",
+        "picture-uri",
+        uri.as_str(),
+        "set",
+        "org.mate.background",
+        "picture-filename",
+        path.as_str(),
+      ]
+    );
+  }
+}
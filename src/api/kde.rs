@@ -0,0 +1,217 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+
+use crate::api::freedesktop::{self, Commands, DialogTool};
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification};
+
+/// KDE Plasma implementation of DesktopApi.
+///
+/// The wallpaper is set on every Plasma containment through the plasmashell scripting
+/// interface, falling back to `plasma-apply-wallpaperimage` when D-Bus scripting fails.
+pub struct KdeDesktopApi {
+  commands: Commands,
+}
+
+impl KdeDesktopApi {
+  pub fn new() -> Self {
+    KdeDesktopApi {
+      commands: Commands::new(),
+    }
+  }
+
+  /// Use `commands` to run the Plasma helper tools.
+  pub fn with_commands(commands: Commands) -> Self {
+    KdeDesktopApi { commands }
+  }
+
+  /// Plasma script setting `uri` as the image wallpaper of every desktop containment.
+  fn wallpaper_script(uri: &str) -> String {
+    // A JSON string is a valid JavaScript string literal, which takes care of escaping.
+    let uri = serde_json::to_string(uri).unwrap();
+    format!(
+      "var all = desktops();\n\
+       for (var i = 0; i < all.length; i++) {{\n\
+         var d = all[i];\n\
+         d.wallpaperPlugin = \"org.kde.image\";\n\
+         d.currentConfigGroup = [\"Wallpaper\", \"org.kde.image\", \"General\"];\n\
+         d.writeConfig(\"Image\", {uri});\n\
+       }}"
+    )
+  }
+}
+
+impl DesktopApi for KdeDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let uri = format!("file://{}", image.display());
+    let script = format!("string:{}", Self::wallpaper_script(&uri));
+
+    let scripted = self.commands.run(
+      "dbus-send",
+      [
+        "--session",
+        "--type=method_call",
+        "--dest=org.kde.plasmashell",
+        "/PlasmaShell",
+        "org.kde.PlasmaShell.evaluateScript",
+        script.as_str(),
+      ],
+    );
+
+    if let Err(e) = scripted {
+      eprintln!("plasmashell scripting failed, trying plasma-apply-wallpaperimage: {e}");
+      self.commands.run("plasma-apply-wallpaperimage", [image])?;
+    }
+
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: false,
+      animated_wallpaper: false,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    freedesktop::send_notification(notification)
+  }
+
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+
+  /// Prefers `kdialog`, which blends in with Plasma, over zenity.
+  fn reply_dialog(
+    &self,
+    title: &str,
+    choices: &[&str],
+  ) -> Result<Option<(usize, String)>, DesktopApiError> {
+    freedesktop::reply_dialog(
+      &self.commands,
+      &[DialogTool::Kdialog, DialogTool::Zenity],
+      title,
+      choices,
+    )
+  }
+
+  fn has_reply_dialog(&self) -> bool {
+    freedesktop::has_dialog_tool(&self.commands, &[DialogTool::Kdialog, DialogTool::Zenity])
+  }
+}
+
+impl Default for KdeDesktopApi {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+
+  fn image(dir: &Path) -> std::path::PathBuf {
+    let image = dir.join("wall's \"paper\".png");
+    std::fs::write(&image, b"png").unwrap();
+    image
+  }
+
+  #[test]
+  fn sets_every_containment_over_dbus() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "dbus-send");
+    recording_command(dir.path(), "plasma-apply-wallpaperimage");
+    let image = image(dir.path());
+    let kde = KdeDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    kde.change_background(&image).unwrap();
+
+    let args = recorded(dir.path(), "dbus-send").join("\n");
+    assert!(args.contains("--dest=org.kde.plasmashell"));
+    assert!(args.contains("org.kde.PlasmaShell.evaluateScript"));
+    assert!(args.contains("desktops()"));
+    assert!(args.contains(&serde_json::to_string(&format!("file://{}", image.display())).unwrap()));
+    assert!(recorded(dir.path(), "plasma-apply-wallpaperimage").is_empty());
+  }
+
+  #[test]
+  fn falls_back_to_plasma_apply_wallpaperimage() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_command(dir.path(), "dbus-send", "exit 1");
+    recording_command(dir.path(), "plasma-apply-wallpaperimage");
+    let image = image(dir.path());
+    let kde = KdeDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    kde.change_background(&image).unwrap();
+
+    assert_eq!(
+      recorded(dir.path(), "plasma-apply-wallpaperimage"),
+      [image.display().to_string()]
+    );
+  }
+
+  #[test]
+  fn reports_failure_when_both_methods_fail() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_command(dir.path(), "dbus-send", "exit 1");
+    fake_command(dir.path(), "plasma-apply-wallpaperimage", "exit 2");
+    let image = image(dir.path());
+    let kde = KdeDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    assert!(matches!(
+      kde.change_background(&image),
+      Err(DesktopApiError::Backend(_))
+    ));
+  }
+
+  #[test]
+  fn opens_files_with_xdg_open() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "xdg-open");
+    let image = image(dir.path());
+    let kde = KdeDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    kde.open_file(&image).unwrap();
+
+    assert_eq!(
+      recorded(dir.path(), "xdg-open"),
+      [image.display().to_string()]
+    );
+  }
+}
@@ -16,19 +16,55 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod cable;
 pub mod client;
+pub mod command;
+pub mod detect;
+pub mod download;
+#[cfg(target_os = "linux")]
+pub mod freedesktop;
 #[cfg(target_os = "linux")]
 pub mod gnome;
+#[cfg(target_os = "linux")]
+pub mod gsettings;
+#[cfg(target_os = "linux")]
+pub mod kde;
 pub mod notify_helper;
+pub mod outputs;
+#[cfg(target_os = "linux")]
+pub mod portal;
+#[cfg(target_os = "linux")]
+pub mod wlroots;
+#[cfg(target_os = "linux")]
+pub mod x11;
+#[cfg(target_os = "linux")]
+pub mod xfce;
 
 #[cfg(windows)]
 pub mod windows;
 
+pub use cable::{CableError, LinkSubscription, LinkUpdate};
 pub use client::ApiClient;
+pub use command::CommandDesktopApi;
+pub use detect::{DetectError, create_desktop_api};
+pub use download::{Download, DownloadError, Downloader, RetryPolicy};
 pub use notify_helper::spawn_review_notification;
+pub use outputs::Output;
 
 #[cfg(target_os = "linux")]
 pub use gnome::GnomeDesktopApi;
+#[cfg(target_os = "linux")]
+pub use gsettings::{GsettingsDesktopApi, WallpaperKey};
+#[cfg(target_os = "linux")]
+pub use kde::KdeDesktopApi;
+#[cfg(target_os = "linux")]
+pub use portal::PortalDesktopApi;
+#[cfg(target_os = "linux")]
+pub use wlroots::WlrootsDesktopApi;
+#[cfg(target_os = "linux")]
+pub use x11::X11DesktopApi;
+#[cfg(target_os = "linux")]
+pub use xfce::XfceDesktopApi;
 
 #[cfg(windows)]
 pub use windows::WindowsDesktopApi;
@@ -39,34 +75,13 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-/// Creates a desktop API implementation appropriate for the current platform
-pub fn create_desktop_api() -> Arc<dyn DesktopApi> {
-  #[cfg(target_os = "windows")]
-  {
-    return Arc::new(WindowsDesktopApi::new());
-  }
-  #[cfg(target_os = "linux")]
-  {
-    let desktop_env = std::env::var("XDG_CURRENT_DESKTOP")
-      .unwrap_or_default()
-      .to_lowercase();
-
-    return match desktop_env.as_str() {
-      "gnome" => Arc::new(GnomeDesktopApi::new()),
-      _ => {
-        unimplemented!(
-          "The desktop environment {} is not currently supported, please wait for future updates.",
-          desktop_env
-        );
-      }
-    };
-  }
-
-  // Not supported
-  unimplemented!(
-    "The operating system {} is not currently supported, please wait for future updates.",
-    std::env::consts::OS
-  );
+/// Where a wallpaper comes from, for backends that can make use of it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WallpaperInfo {
+  pub link_id: Option<i64>,
+  pub set_by: Option<String>,
+  /// The original animated image or video, when the wallpaper is a still frame of it.
+  pub animated: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
@@ -198,14 +213,119 @@ pub struct DesktopCapabilities {
   pub set_wallpaper: bool,
   pub raw_icon_bytes: bool,
   pub open_file: bool,
+  pub per_output_wallpaper: bool,
+  /// Whether `change_background_animated` can play animated images or videos.
+  pub animated_wallpaper: bool,
 }
 
 pub trait DesktopApi: Send + Sync {
   fn change_background(&self, image: &Path) -> Result<(), DesktopApiError>;
 
+  /// Set the background of a single output, leaving the other outputs as they are.
+  fn change_background_on(&self, _output: &str, _image: &Path) -> Result<(), DesktopApiError> {
+    Err(DesktopApiError::Unsupported)
+  }
+
+  /// Show `image` on `output`, or on the whole desktop when `None`.
+  ///
+  /// This is the entry point used by the daemon, `info` describes the image.
+  fn apply_wallpaper(
+    &self,
+    output: Option<&str>,
+    image: &Path,
+    info: &WallpaperInfo,
+  ) -> Result<(), DesktopApiError> {
+    if let Some(media) = &info.animated
+      && self.capabilities().animated_wallpaper
+    {
+      match self.change_background_animated(output, media) {
+        Ok(()) => return Ok(()),
+        Err(e) => eprintln!("Cannot play {media:?}, using a still frame: {e}"),
+      }
+    }
+    match output {
+      Some(output) => self.change_background_on(output, image),
+      None => self.change_background(image),
+    }
+  }
+
+  /// Show an animated image or video as the background, on `output` or everywhere.
+  fn change_background_animated(
+    &self,
+    _output: Option<&str>,
+    _media: &Path,
+  ) -> Result<(), DesktopApiError> {
+    Err(DesktopApiError::Unsupported)
+  }
+
+  /// List the connected outputs with their resolution, scale and position.
+  ///
+  /// On Linux the default asks sway, `wlr-randr`, Mutter or `xrandr`, whichever applies.
+  fn outputs(&self) -> Result<Vec<Output>, DesktopApiError> {
+    #[cfg(target_os = "linux")]
+    return outputs::detect(&freedesktop::Commands::new());
+    #[cfg(not(target_os = "linux"))]
+    Err(DesktopApiError::Unsupported)
+  }
+
   fn capabilities(&self) -> DesktopCapabilities;
 
   fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError>;
 
+  /// Show `notification` and block until one of its actions is invoked.
+  ///
+  /// Returns the id of the invoked action, or `None` when the notification was closed. The
+  /// default cannot report clicks, it only shows the notification and returns `None`.
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    self.send_notification(notification)?;
+    Ok(None)
+  }
+
   fn open_file(&self, file: &Path) -> Result<(), DesktopApiError>;
+
+  /// Ask the user to pick one of `choices` and type a reply, with a native dialog.
+  ///
+  /// Returns the index of the choice and the text, `None` when the dialog was cancelled.
+  /// On Linux the default uses `zenity`, or `kdialog` when zenity is not installed.
+  fn reply_dialog(
+    &self,
+    title: &str,
+    choices: &[&str],
+  ) -> Result<Option<(usize, String)>, DesktopApiError> {
+    #[cfg(target_os = "linux")]
+    return freedesktop::reply_dialog(
+      &freedesktop::Commands::new(),
+      &[
+        freedesktop::DialogTool::Zenity,
+        freedesktop::DialogTool::Kdialog,
+      ],
+      title,
+      choices,
+    );
+    #[cfg(not(target_os = "linux"))]
+    {
+      let _ = (title, choices);
+      Err(DesktopApiError::Unsupported)
+    }
+  }
+
+  /// Whether `reply_dialog` has a way to ask, so replying is worth offering at all.
+  fn has_reply_dialog(&self) -> bool {
+    #[cfg(target_os = "linux")]
+    return freedesktop::has_dialog_tool(
+      &freedesktop::Commands::new(),
+      &[
+        freedesktop::DialogTool::Zenity,
+        freedesktop::DialogTool::Kdialog,
+      ],
+    );
+    #[cfg(not(target_os = "linux"))]
+    false
+  }
+
+  /// Release anything the backend keeps running, such as helper processes, before exiting.
+  fn shutdown(&self) {}
 }
@@ -17,65 +17,515 @@
  */
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::thread;
 
 use crate::api::{ApiClient, DesktopApi, Notification, Urgency};
+use crate::approval::{Approvals, Decision};
+use crate::censor::Reveals;
+use crate::model::link::ResponseType;
 
 /// Spawn a background thread that shows a review notification with actions.
 ///
 /// - `client`: an `ApiClient` clone to use for posting responses.
 /// - `current_id`: Arc to the current image/post id (e621 id). The thread will read this to ensure the user is reacting to the current image.
+/// - `approvals`: set when the image waits for approval, the notification then offers to approve or reject it.
+/// - `reveals`: set when the image is shown censored, the notification then offers to reveal it.
 /// - `link_id`: the id of the link being reviewed.
 /// - `username`: the username who provided the image (for the notification body).
-/// - `api_key`: the API key to include in the response.
 /// - `image_path`: the path to the current background image file.
 ///
 /// This function returns immediately; the thread handles user interactions and posts responses.
+/// It must be called from within a Tokio runtime, which is used to post the responses.
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_review_notification(
-  _client: &ApiClient,
+  client: &ApiClient,
   desktop: Arc<dyn DesktopApi>,
-  _current_id: Arc<std::sync::atomic::AtomicI64>,
-  _link_id: i64,
+  current_id: Arc<AtomicI64>,
+  approvals: Option<Arc<Approvals>>,
+  reveals: Option<Arc<Reveals>>,
+  link_id: i64,
   post_id: i64,
   username: String,
-  _api_key: String,
   image_path: std::path::PathBuf,
 ) {
-  // Clone what we need into the thread - simplified for now
-  thread::spawn(move || {
-    // Build a cross-platform notification via DesktopApi
-    let notif = Notification::builder("Background change pending")
-      .body(format!(
-        "Your background will soon change to an image provided by {username}. You may review it from here."
+  let runtime = tokio::runtime::Handle::current();
+  let review = Review {
+    client: client.clone(),
+    current_id,
+    approvals,
+    reveals,
+    link_id,
+    post_id,
+    username,
+    can_reply: desktop.has_reply_dialog(),
+  };
+
+  // Auto-open the image for review while the notification waits for a reaction, unless it
+  // is meant to stay hidden
+  if review.reveals.is_none() {
+    let opener = desktop.clone();
+    thread::spawn(move || {
+      std::thread::sleep(std::time::Duration::from_secs(5));
+      open_for_review(opener.as_ref(), &image_path);
+    });
+  }
+
+  thread::spawn(move || review.run(desktop.as_ref(), &runtime));
+}
+
+/// Reactions offered by the reply dialog, with their labels.
+const REACTIONS: [(ResponseType, &str); 3] = [
+  (ResponseType::Horny, "Horny"),
+  (ResponseType::Disgust, "Disgust"),
+  (ResponseType::Came, "Came"),
+];
+
+/// What the user clicked on the review notification.
+#[derive(Debug, PartialEq)]
+enum ReviewAction {
+  React(ResponseType),
+  /// Pick a reaction and type a message in a dialog first.
+  Reply,
+  Approve,
+  Reject,
+  /// Swap the censored preview for the real image.
+  Reveal,
+}
+
+/// The post a review notification was shown for.
+struct Review {
+  client: ApiClient,
+  current_id: Arc<AtomicI64>,
+  approvals: Option<Arc<Approvals>>,
+  reveals: Option<Arc<Reveals>>,
+  link_id: i64,
+  post_id: i64,
+  username: String,
+  /// Whether the desktop has a dialog to type a reply in.
+  can_reply: bool,
+}
+
+impl Review {
+  /// Show the review notification and carry out what the user picks on it.
+  ///
+  /// While the image waits for approval or is shown censored, the notification comes back
+  /// after each step so the next one can be taken.
+  fn run(&self, desktop: &dyn DesktopApi, runtime: &tokio::runtime::Handle) {
+    let mut awaiting_approval = self.approvals.is_some();
+    let mut hidden = self.reveals.is_some();
+    loop {
+      let notif = self.notification(awaiting_approval, hidden);
+      let action = match desktop.wait_for_action(&notif) {
+        Ok(Some(action)) => action,
+        Ok(None) => return,
+        Err(e) => {
+          eprintln!("Failed to send review notification: {e}");
+          return;
+        }
+      };
+      let Some(action) = parse_action(&action, self.post_id) else {
+        eprintln!("Ignoring unknown notification action {action}");
+        return;
+      };
+
+      let (approving, revealing, reacting) = (
+        action == ReviewAction::Approve,
+        action == ReviewAction::Reveal,
+        matches!(action, ReviewAction::React(_) | ReviewAction::Reply),
+      );
+      let outcome = runtime.block_on(self.handle(desktop, action));
+      if let Some(notif) = &outcome {
+        let _ = desktop.send_notification(notif);
+      }
+      match outcome {
+        None if approving => awaiting_approval = false,
+        None if revealing => hidden = false,
+        // The image can still be revealed after reacting to it.
+        _ if reacting && hidden => {}
+        _ => return,
+      }
+    }
+  }
+
+  /// The review notification, offering to approve or reject the image while it is pending,
+  /// then to react to it and to reveal it while it is shown censored.
+  fn notification(&self, awaiting_approval: bool, hidden: bool) -> Notification {
+    let post_id = self.post_id;
+    let mut builder = if awaiting_approval {
+      Notification::builder("Background change needs approval")
+        .body(format!(
+          "{} wants to change your background. Approve to apply it, you may review it from here.",
+          self.username
+        ))
+        .action(format!("approve-{post_id}"), "Approve")
+        .action(format!("reject-{post_id}"), "Reject")
+    } else {
+      Notification::builder("Background change pending").body(format!(
+        "Your background will soon change to an image provided by {}. You may review it from here.",
+        self.username
       ))
-      .action(format!("horny-{post_id}"), "Horny")
-      .action(format!("disgust-{post_id}"), "Disgust")
-      .action(format!("came-{post_id}"), "Came")
-      .urgency(Urgency::Normal)
-      .build();
-
-    let _ = desktop.send_notification(&notif);
-    println!("Review notification sent");
-
-    // For now, just wait a bit and then provide a simple notification and open the image
-    std::thread::sleep(std::time::Duration::from_secs(5));
-
-    // Auto-open the image for review
-    match desktop.open_file(&image_path) {
-      Ok(_) => {
-        let notif = Notification::builder("Image opened")
-          .body("Successfully opened the current background image")
-          .urgency(Urgency::Normal)
-          .build();
-        let _ = desktop.send_notification(&notif);
+    };
+    if !awaiting_approval {
+      if hidden {
+        builder = builder.action(format!("reveal-{post_id}"), "Reveal");
       }
-      Err(e) => {
-        let notif = Notification::builder("Failed to open image")
-          .body(format!("Failed to open image: {}", e))
-          .urgency(Urgency::Critical)
-          .build();
-        let _ = desktop.send_notification(&notif);
+      builder = builder
+        .action(format!("horny-{post_id}"), "Horny")
+        .action(format!("disgust-{post_id}"), "Disgust")
+        .action(format!("came-{post_id}"), "Came");
+      if self.can_reply {
+        builder = builder.action(format!("reply-{post_id}"), "Reply…");
       }
     }
-  });
+    builder.urgency(Urgency::Normal).build()
+  }
+
+  /// Carry out `action`, returning the notification telling the user how it went.
+  async fn handle(&self, desktop: &dyn DesktopApi, action: ReviewAction) -> Option<Notification> {
+    match action {
+      ReviewAction::Approve | ReviewAction::Reject => {
+        let decision = match action {
+          ReviewAction::Approve => Decision::Approve,
+          _ => Decision::Reject,
+        };
+        let decided = self
+          .approvals
+          .as_ref()
+          .is_some_and(|approvals| approvals.decide(self.link_id, Some(self.post_id), decision));
+        (!decided).then(|| {
+          Notification::builder("Too late")
+            .body("This image is no longer waiting for approval.")
+            .urgency(Urgency::Normal)
+            .build()
+        })
+      }
+      ReviewAction::Reveal => {
+        let revealed = self
+          .reveals
+          .as_ref()
+          .is_some_and(|reveals| reveals.reveal(self.link_id, Some(self.post_id)));
+        (!revealed).then(|| {
+          Notification::builder("Nothing to reveal")
+            .body("This image is not shown censored anymore.")
+            .urgency(Urgency::Normal)
+            .build()
+        })
+      }
+      ReviewAction::React(response_type) => Some(self.respond(response_type, "").await),
+      ReviewAction::Reply => {
+        let labels = REACTIONS.map(|(_, label)| label);
+        let title = format!("Reply to {}", self.username);
+        match desktop.reply_dialog(&title, &labels) {
+          Ok(Some((index, text))) => Some(self.respond(REACTIONS[index].0.clone(), &text).await),
+          Ok(None) => None,
+          Err(e) => Some(
+            Notification::builder("Cannot reply")
+              .body(format!("Failed to open the reply dialog: {e}"))
+              .urgency(Urgency::Critical)
+              .build(),
+          ),
+        }
+      }
+    }
+  }
+
+  /// Post `response_type` with `text` to the link, unless the background changed since the
+  /// notification was shown. Returns the notification telling the user how it went.
+  async fn respond(&self, response_type: ResponseType, text: &str) -> Notification {
+    if self.current_id.load(Ordering::SeqCst) != self.post_id {
+      return Notification::builder("Reaction not sent")
+        .body("The background changed since, your reaction was dropped.")
+        .urgency(Urgency::Normal)
+        .build();
+    }
+
+    let name = response_type.as_str().to_string();
+    match self.client.react(self.link_id, response_type, text).await {
+      Ok(_) => Notification::builder("Reaction sent")
+        .body(format!(
+          "{} now knows your reaction: {}.",
+          self.username, name
+        ))
+        .urgency(Urgency::Normal)
+        .build(),
+      Err(e) => Notification::builder("Failed to send reaction")
+        .body(format!("Failed to send reaction: {e}"))
+        .urgency(Urgency::Critical)
+        .build(),
+    }
+  }
+}
+
+/// The action chosen with `action` on the review notification of `post_id`.
+fn parse_action(action: &str, post_id: i64) -> Option<ReviewAction> {
+  match action.strip_suffix(&format!("-{post_id}"))? {
+    "reply" => Some(ReviewAction::Reply),
+    "approve" => Some(ReviewAction::Approve),
+    "reject" => Some(ReviewAction::Reject),
+    "reveal" => Some(ReviewAction::Reveal),
+    reaction => reaction.parse().ok().map(ReviewAction::React),
+  }
+}
+
+fn open_for_review(desktop: &dyn DesktopApi, image_path: &std::path::Path) {
+  match desktop.open_file(image_path) {
+    Ok(_) => {
+      let notif = Notification::builder("Image opened")
+        .body("Successfully opened the current background image")
+        .urgency(Urgency::Normal)
+        .build();
+      let _ = desktop.send_notification(&notif);
+    }
+    Err(e) => {
+      let notif = Notification::builder("Failed to open image")
+        .body(format!("Failed to open image: {}", e))
+        .urgency(Urgency::Critical)
+        .build();
+      let _ = desktop.send_notification(&notif);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::{DesktopApiError, DesktopCapabilities};
+  use crate::approval::ApprovalPolicy;
+  use crate::arbiter::Arbiter;
+  use crate::model::config::{CensorMode, Config, MergePolicy};
+  use std::path::Path;
+  use std::sync::Mutex;
+
+  fn review(current_id: i64, post_id: i64) -> Review {
+    let config = Config::from_str("[Base]\n[Feed]\nfeed = 1\n[Preferences]\n").unwrap();
+    Review {
+      client: ApiClient::new(config),
+      current_id: Arc::new(AtomicI64::new(current_id)),
+      approvals: None,
+      reveals: None,
+      link_id: 1,
+      post_id,
+      username: "gray".to_string(),
+      can_reply: true,
+    }
+  }
+
+  #[test]
+  fn maps_actions_to_reactions() {
+    use ReviewAction::*;
+    assert_eq!(
+      parse_action("horny-42", 42),
+      Some(React(ResponseType::Horny))
+    );
+    assert_eq!(
+      parse_action("disgust-42", 42),
+      Some(React(ResponseType::Disgust))
+    );
+    assert_eq!(parse_action("came-42", 42), Some(React(ResponseType::Came)));
+    assert_eq!(parse_action("reply-42", 42), Some(Reply));
+    assert_eq!(parse_action("approve-42", 42), Some(Approve));
+    assert_eq!(parse_action("reject-42", 42), Some(Reject));
+    assert_eq!(parse_action("reveal-42", 42), Some(Reveal));
+    assert_eq!(parse_action("came-41", 42), None);
+    assert_eq!(parse_action("default", 42), None);
+  }
+
+  #[tokio::test]
+  async fn drops_reactions_to_replaced_backgrounds() {
+    let notification = review(7, 42).respond(ResponseType::Horny, "").await;
+    assert_eq!(notification.title, "Reaction not sent");
+  }
+
+  #[tokio::test]
+  async fn reports_failures() {
+    let mut review = review(42, 42);
+    review.client.config.feed.token = Some("your_token".to_string());
+    let notification = review.respond(ResponseType::Came, "").await;
+    assert_eq!(notification.title, "Failed to send reaction");
+    assert!(matches!(notification.urgency, Urgency::Critical));
+  }
+
+  #[tokio::test]
+  async fn approves_from_the_notification() {
+    let approvals = Arc::new(Approvals::new(ApprovalPolicy {
+      timeout: None,
+      on_timeout: Decision::Reject,
+      disgust_on_reject: false,
+    }));
+    let pending = approvals.request(1, 42);
+    let mut review = review(42, 42);
+    review.approvals = Some(approvals.clone());
+    let desktop = ReplyingDesktop::default();
+
+    let actions: Vec<_> = review
+      .notification(true, false)
+      .actions
+      .into_iter()
+      .map(|a| a.id)
+      .collect();
+    // Reactions wait until the image is approved.
+    assert_eq!(actions, ["approve-42", "reject-42"]);
+
+    assert!(
+      review
+        .handle(&desktop, ReviewAction::Approve)
+        .await
+        .is_none()
+    );
+    assert_eq!(pending.decision().await, Some(Decision::Approve));
+
+    // Nothing is left to decide on.
+    let notification = review.handle(&desktop, ReviewAction::Reject).await;
+    assert_eq!(notification.unwrap().title, "Too late");
+  }
+
+  #[tokio::test]
+  async fn offers_to_reveal_censored_images() {
+    let review = review(42, 42);
+    let offers_reveal =
+      |notification: Notification| notification.actions.iter().any(|a| a.id == "reveal-42");
+    assert!(offers_reveal(review.notification(false, true)));
+    assert!(!offers_reveal(review.notification(true, true)));
+    assert!(!offers_reveal(review.notification(false, false)));
+
+    let desktop = ReplyingDesktop::default();
+    let notification = review.handle(&desktop, ReviewAction::Reveal).await;
+    assert_eq!(notification.unwrap().title, "Nothing to reveal");
+  }
+
+  /// Answers the reply dialog with `reply` and records what it was asked, clicks `clicks`
+  /// on the notifications in turn and records the title of every one shown.
+  #[derive(Default)]
+  struct ReplyingDesktop {
+    reply: Option<(usize, String)>,
+    asked: Mutex<Vec<(String, Vec<String>)>>,
+    clicks: Mutex<Vec<String>>,
+    shown: Mutex<Vec<String>>,
+  }
+
+  impl DesktopApi for ReplyingDesktop {
+    fn change_background(&self, _image: &Path) -> Result<(), DesktopApiError> {
+      Ok(())
+    }
+
+    fn capabilities(&self) -> DesktopCapabilities {
+      DesktopCapabilities {
+        notifications: true,
+        actions: true,
+        set_wallpaper: false,
+        raw_icon_bytes: false,
+        open_file: false,
+        per_output_wallpaper: false,
+        animated_wallpaper: false,
+      }
+    }
+
+    fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+      let title = notification.title.clone();
+      self.shown.lock().unwrap().push(title);
+      Ok(())
+    }
+
+    fn wait_for_action(
+      &self,
+      notification: &Notification,
+    ) -> Result<Option<String>, DesktopApiError> {
+      self.send_notification(notification)?;
+      let mut clicks = self.clicks.lock().unwrap();
+      Ok((!clicks.is_empty()).then(|| clicks.remove(0)))
+    }
+
+    fn open_file(&self, _file: &Path) -> Result<(), DesktopApiError> {
+      Ok(())
+    }
+
+    fn reply_dialog(
+      &self,
+      title: &str,
+      choices: &[&str],
+    ) -> Result<Option<(usize, String)>, DesktopApiError> {
+      let choices = choices.iter().map(|c| c.to_string()).collect();
+      self
+        .asked
+        .lock()
+        .unwrap()
+        .push((title.to_string(), choices));
+      Ok(self.reply.clone())
+    }
+  }
+
+  #[test]
+  fn offers_to_reply_only_with_a_dialog() {
+    let mut review = review(42, 42);
+    let offers_reply = |review: &Review| {
+      review
+        .notification(false, false)
+        .actions
+        .iter()
+        .any(|a| a.id == "reply-42")
+    };
+    assert!(offers_reply(&review));
+    review.can_reply = false;
+    assert!(!offers_reply(&review));
+  }
+
+  #[tokio::test]
+  async fn replies_through_the_dialog() {
+    let desktop = ReplyingDesktop::default();
+    assert!(
+      review(42, 42)
+        .handle(&desktop, ReviewAction::Reply)
+        .await
+        .is_none()
+    );
+    assert_eq!(
+      desktop.asked.lock().unwrap()[0],
+      (
+        "Reply to gray".to_string(),
+        vec!["Horny".into(), "Disgust".into(), "Came".into()]
+      )
+    );
+
+    // The reply still goes through `react`, which refuses it without a token.
+    let desktop = ReplyingDesktop {
+      reply: Some((1, "nope".to_string())),
+      ..Default::default()
+    };
+    let notification = review(42, 42).handle(&desktop, ReviewAction::Reply).await;
+    assert_eq!(notification.unwrap().title, "Failed to send reaction");
+
+    let notification = review(7, 42).handle(&desktop, ReviewAction::Reply).await;
+    assert_eq!(notification.unwrap().title, "Reaction not sent");
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn comes_back_after_reacting_to_a_censored_image() {
+    let desktop = Arc::new(ReplyingDesktop {
+      clicks: Mutex::new(vec!["horny-42".to_string()]),
+      ..Default::default()
+    });
+    let arbiter = Arbiter::new(desktop.clone(), MergePolicy::LatestWins, vec![1]);
+    let mut review = review(42, 42);
+    review.reveals = Some(Arc::new(Reveals::new(
+      Arc::new(arbiter),
+      CensorMode::Pixelate,
+    )));
+
+    let runtime = tokio::runtime::Handle::current();
+    let shown_on = desktop.clone();
+    tokio::task::spawn_blocking(move || review.run(shown_on.as_ref(), &runtime))
+      .await
+      .unwrap();
+
+    // The reaction fails without a token, then the image can still be revealed.
+    assert_eq!(
+      *desktop.shown.lock().unwrap(),
+      [
+        "Background change pending",
+        "Failed to send reaction",
+        "Background change pending"
+      ]
+    );
+  }
 }
@@ -0,0 +1,653 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbImage};
+
+use crate::api::DesktopApiError;
+#[cfg(target_os = "linux")]
+use crate::api::freedesktop::Commands;
+
+/// A connected monitor and where it sits in the desktop layout.
+///
+/// Position and size are both in layout coordinates, the space the display server arranges
+/// outputs in. On Wayland that is logical pixels, the mode's resolution divided by `scale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+  pub name: String,
+  /// Size in layout coordinates, after rotation.
+  pub width: u32,
+  pub height: u32,
+  /// Position in layout coordinates.
+  pub x: i32,
+  pub y: i32,
+  /// Pixels per layout unit, 1.0 when the layout is in pixels (X11).
+  pub scale: f64,
+}
+
+impl Output {
+  /// Resolution of the output in pixels.
+  pub fn pixel_size(&self) -> (u32, u32) {
+    (
+      (self.width as f64 * self.scale).round() as u32,
+      (self.height as f64 * self.scale).round() as u32,
+    )
+  }
+}
+
+/// Turn a mode's resolution into a size in layout coordinates.
+fn layout_size(width: u64, height: u64, scale: f64) -> (u32, u32) {
+  let scale = if scale > 0.0 { scale } else { 1.0 };
+  (
+    (width as f64 / scale).round() as u32,
+    (height as f64 / scale).round() as u32,
+  )
+}
+
+/// Parse the outputs listed by `xrandr --query`.
+///
+/// Only connected outputs with an active mode are returned.
+pub fn parse_xrandr(text: &str) -> Vec<Output> {
+  text
+    .lines()
+    .filter_map(|line| {
+      let mut tokens = line.split_whitespace();
+      let name = tokens.next()?;
+      if tokens.next()? != "connected" {
+        return None;
+      }
+      let (width, height, x, y) = tokens.find_map(parse_geometry)?;
+      Some(Output {
+        name: name.to_string(),
+        width,
+        height,
+        x,
+        y,
+        scale: 1.0,
+      })
+    })
+    .collect()
+}
+
+/// Whether a Wayland transform name rotates the output by a quarter turn.
+fn is_sideways(transform: &str) -> bool {
+  matches!(transform, "90" | "270" | "flipped-90" | "flipped-270")
+}
+
+/// Parse the outputs listed by `wlr-randr`.
+///
+/// Disabled outputs and outputs without a current mode are skipped.
+pub fn parse_wlr_randr(text: &str) -> Vec<Output> {
+  struct Block {
+    name: String,
+    enabled: bool,
+    mode: Option<(u32, u32)>,
+    position: (i32, i32),
+    transform: String,
+    scale: f64,
+  }
+
+  let mut blocks: Vec<Block> = Vec::new();
+  for line in text.lines() {
+    if !line.starts_with(char::is_whitespace) {
+      let Some(name) = line.split_whitespace().next() else {
+        continue;
+      };
+      blocks.push(Block {
+        name: name.to_string(),
+        enabled: true,
+        mode: None,
+        position: (0, 0),
+        transform: "normal".to_string(),
+        scale: 1.0,
+      });
+      continue;
+    }
+    let Some(block) = blocks.last_mut() else {
+      continue;
+    };
+    let line = line.trim();
+    if let Some(value) = line.strip_prefix("Enabled:") {
+      block.enabled = value.trim() == "yes";
+    } else if let Some(value) = line.strip_prefix("Position:") {
+      if let Some((x, y)) = value.trim().split_once(',') {
+        block.position = (x.parse().unwrap_or(0), y.parse().unwrap_or(0));
+      }
+    } else if let Some(value) = line.strip_prefix("Transform:") {
+      block.transform = value.trim().to_string();
+    } else if let Some(value) = line.strip_prefix("Scale:") {
+      block.scale = value.trim().parse().unwrap_or(1.0);
+    } else if line.contains(" px,") && line.contains("current") {
+      let size = line.split_whitespace().next().unwrap_or_default();
+      if let Some((w, h)) = size.split_once('x') {
+        block.mode = w.parse().ok().zip(h.parse().ok());
+      }
+    }
+  }
+
+  blocks
+    .into_iter()
+    .filter(|b| b.enabled)
+    .filter_map(|b| {
+      let (mut width, mut height) = b.mode?;
+      if is_sideways(&b.transform) {
+        (width, height) = (height, width);
+      }
+      let (width, height) = layout_size(width as u64, height as u64, b.scale);
+      Some(Output {
+        name: b.name,
+        width,
+        height,
+        x: b.position.0,
+        y: b.position.1,
+        scale: b.scale,
+      })
+    })
+    .collect()
+}
+
+/// Parse the JSON printed by `swaymsg -t get_outputs --raw`.
+///
+/// Inactive outputs are skipped.
+pub fn parse_sway_outputs(json: &str) -> Result<Vec<Output>, DesktopApiError> {
+  let outputs: Vec<serde_json::Value> = serde_json::from_str(json)
+    .map_err(|e| DesktopApiError::Backend(format!("invalid swaymsg output: {e}")))?;
+
+  Ok(
+    outputs
+      .iter()
+      .filter(|o| o["active"].as_bool().unwrap_or(false))
+      .filter_map(|o| {
+        let mode = &o["current_mode"];
+        let (mut width, mut height) = (mode["width"].as_u64()?, mode["height"].as_u64()?);
+        if is_sideways(o["transform"].as_str().unwrap_or("normal")) {
+          (width, height) = (height, width);
+        }
+        let scale = o["scale"].as_f64().unwrap_or(1.0);
+        let (width, height) = layout_size(width, height, scale);
+        Some(Output {
+          name: o["name"].as_str()?.to_string(),
+          width,
+          height,
+          x: o["rect"]["x"].as_i64().unwrap_or(0) as i32,
+          y: o["rect"]["y"].as_i64().unwrap_or(0) as i32,
+          scale,
+        })
+      })
+      .collect(),
+  )
+}
+
+/// Connector, vendor, product and serial identifying a monitor to Mutter.
+#[cfg(target_os = "linux")]
+pub type MutterMonitorSpec = (String, String, String, String);
+
+/// A mode as returned by Mutter: id, width, height, refresh rate, preferred scale,
+/// supported scales and properties.
+#[cfg(target_os = "linux")]
+pub type MutterMode = (
+  String,
+  i32,
+  i32,
+  f64,
+  f64,
+  Vec<f64>,
+  HashMap<String, zbus::zvariant::OwnedValue>,
+);
+
+/// A physical monitor as returned by Mutter.
+#[cfg(target_os = "linux")]
+pub type MutterMonitor = (
+  MutterMonitorSpec,
+  Vec<MutterMode>,
+  HashMap<String, zbus::zvariant::OwnedValue>,
+);
+
+/// A logical monitor as returned by Mutter: x, y, scale, transform, primary, monitors and
+/// properties.
+#[cfg(target_os = "linux")]
+pub type MutterLogicalMonitor = (
+  i32,
+  i32,
+  f64,
+  u32,
+  bool,
+  Vec<MutterMonitorSpec>,
+  HashMap<String, zbus::zvariant::OwnedValue>,
+);
+
+/// Build outputs from the monitors and logical monitors of Mutter's `GetCurrentState`.
+///
+/// Mutter lays monitors out in logical pixels unless `physical_layout` is set, as its
+/// `layout-mode` property says it is on X11.
+#[cfg(target_os = "linux")]
+pub fn from_mutter_state(
+  monitors: &[MutterMonitor],
+  logical_monitors: &[MutterLogicalMonitor],
+  physical_layout: bool,
+) -> Vec<Output> {
+  let is_current = |mode: &MutterMode| {
+    mode
+      .6
+      .get("is-current")
+      .is_some_and(|v| matches!(&**v, zbus::zvariant::Value::Bool(true)))
+  };
+
+  let mut outputs = Vec::new();
+  for (x, y, scale, transform, _primary, specs, _) in logical_monitors {
+    for spec in specs {
+      let Some((_, modes, _)) = monitors.iter().find(|(s, _, _)| s == spec) else {
+        continue;
+      };
+      let Some(mode) = modes.iter().find(|m| is_current(m)) else {
+        continue;
+      };
+      let (mut width, mut height) = (mode.1 as u32, mode.2 as u32);
+      // Transforms 1 and 3 (and their flipped variants 5 and 7) are quarter turns.
+      if transform % 2 == 1 {
+        (width, height) = (height, width);
+      }
+      let scale = if physical_layout { 1.0 } else { *scale };
+      let (width, height) = layout_size(width as u64, height as u64, scale);
+      outputs.push(Output {
+        name: spec.0.clone(),
+        width,
+        height,
+        x: *x,
+        y: *y,
+        scale,
+      });
+    }
+  }
+  outputs
+}
+
+/// Query `xrandr`.
+#[cfg(target_os = "linux")]
+pub fn query_xrandr(commands: &Commands) -> Result<Vec<Output>, DesktopApiError> {
+  Ok(parse_xrandr(&commands.output("xrandr", ["--query"])?))
+}
+
+/// Query `wlr-randr`.
+#[cfg(target_os = "linux")]
+pub fn query_wlr_randr(commands: &Commands) -> Result<Vec<Output>, DesktopApiError> {
+  Ok(parse_wlr_randr(
+    &commands.output("wlr-randr", [] as [&str; 0])?,
+  ))
+}
+
+/// Query sway through `swaymsg`.
+#[cfg(target_os = "linux")]
+pub fn query_sway(commands: &Commands) -> Result<Vec<Output>, DesktopApiError> {
+  parse_sway_outputs(&commands.output("swaymsg", ["-t", "get_outputs", "--raw"])?)
+}
+
+/// Query Mutter's `org.gnome.Mutter.DisplayConfig` on the session bus.
+#[cfg(target_os = "linux")]
+pub fn query_mutter() -> Result<Vec<Output>, DesktopApiError> {
+  let dbus_error = |e: zbus::Error| DesktopApiError::Backend(format!("DisplayConfig error: {e}"));
+
+  let connection = zbus::blocking::Connection::session().map_err(dbus_error)?;
+  let reply = connection
+    .call_method(
+      Some("org.gnome.Mutter.DisplayConfig"),
+      "/org/gnome/Mutter/DisplayConfig",
+      Some("org.gnome.Mutter.DisplayConfig"),
+      "GetCurrentState",
+      &(),
+    )
+    .map_err(dbus_error)?;
+  let (_serial, monitors, logical_monitors, properties): (
+    u32,
+    Vec<MutterMonitor>,
+    Vec<MutterLogicalMonitor>,
+    HashMap<String, zbus::zvariant::OwnedValue>,
+  ) = reply.body().deserialize().map_err(dbus_error)?;
+
+  // Layout mode 1 is logical, 2 physical.
+  let physical_layout = properties
+    .get("layout-mode")
+    .is_some_and(|v| matches!(&**v, zbus::zvariant::Value::U32(2)));
+  Ok(from_mutter_state(
+    &monitors,
+    &logical_monitors,
+    physical_layout,
+  ))
+}
+
+/// Query whichever source fits the session: sway, then `wlr-randr` on Wayland, then Mutter,
+/// then `xrandr`.
+#[cfg(target_os = "linux")]
+pub fn detect(commands: &Commands) -> Result<Vec<Output>, DesktopApiError> {
+  let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+
+  if std::env::var_os("SWAYSOCK").is_some() && commands.exists("swaymsg") {
+    return query_sway(commands);
+  }
+  if wayland
+    && commands.exists("wlr-randr")
+    && let Ok(outputs) = query_wlr_randr(commands)
+  {
+    return Ok(outputs);
+  }
+  if let Ok(outputs) = query_mutter() {
+    return Ok(outputs);
+  }
+  // Under XWayland xrandr reports a single fake screen, so only trust it on X11.
+  if !wayland && commands.exists("xrandr") {
+    return query_xrandr(commands);
+  }
+  Err(DesktopApiError::Unsupported)
+}
+
+/// Parse an X geometry such as `1920x1080+1920+0`.
+fn parse_geometry(token: &str) -> Option<(u32, u32, i32, i32)> {
+  let (size, offsets) = token.split_once('+')?;
+  let (width, height) = size.split_once('x')?;
+  let (x, y) = offsets.split_once('+')?;
+  Some((
+    width.parse().ok()?,
+    height.parse().ok()?,
+    x.parse().ok()?,
+    y.parse().ok()?,
+  ))
+}
+
+/// Compose one image spanning every output, each image covering its own monitor.
+///
+/// The layout is rendered at the largest output scale so HiDPI monitors stay sharp. Outputs
+/// without an image are left black.
+pub fn compose_spanned(layout: &[(Output, Option<&Path>)]) -> Result<RgbImage, DesktopApiError> {
+  let factor = layout.iter().map(|(o, _)| o.scale).fold(1.0, f64::max);
+  let px = |v: i32| (v as f64 * factor).round() as i32;
+
+  let min_x = layout.iter().map(|(o, _)| o.x).min().unwrap_or(0);
+  let min_y = layout.iter().map(|(o, _)| o.y).min().unwrap_or(0);
+  let max_x = layout
+    .iter()
+    .map(|(o, _)| o.x + o.width as i32)
+    .max()
+    .unwrap_or(0);
+  let max_y = layout
+    .iter()
+    .map(|(o, _)| o.y + o.height as i32)
+    .max()
+    .unwrap_or(0);
+
+  let mut canvas = RgbImage::new(px(max_x - min_x) as u32, px(max_y - min_y) as u32);
+
+  for (output, image) in layout {
+    let Some(image) = image else {
+      continue;
+    };
+    let tile = open_image(image)?
+      .resize_to_fill(
+        px(output.width as i32) as u32,
+        px(output.height as i32) as u32,
+        FilterType::Lanczos3,
+      )
+      .to_rgb8();
+    imageops::replace(
+      &mut canvas,
+      &tile,
+      px(output.x - min_x) as i64,
+      px(output.y - min_y) as i64,
+    );
+  }
+
+  Ok(canvas)
+}
+
+fn open_image(path: &Path) -> Result<DynamicImage, DesktopApiError> {
+  image::open(path).map_err(|e| DesktopApiError::Backend(format!("failed to decode {path:?}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::Rgb;
+
+  const XRANDR: &str = "\
+Screen 0: minimum 320 x 200, current 4480 x 1440, maximum 16384 x 16384
+DP-1 connected primary 2560x1440+0+0 (normal left inverted right x axis y axis) 597mm x 336mm
+   2560x1440     59.95*+
+HDMI-1 connected 1920x1080+2560+180 (normal left inverted right x axis y axis) 527mm x 296mm
+   1920x1080     60.00*+
+HDMI-2 disconnected (normal left inverted right x axis y axis)
+DP-2 connected (normal left inverted right x axis y axis)
+";
+
+  const WLR_RANDR: &str = "\
+eDP-1 \"BOE 0x0A1C (eDP-1)\"
+  Make: BOE
+  Model: 0x0A1C
+  Serial: (null)
+  Physical size: 300x190 mm
+  Enabled: yes
+  Modes:
+    2880x1800 px, 60.000999 Hz (preferred, current)
+    1920x1200 px, 60.000999 Hz
+  Position: 0,0
+  Transform: normal
+  Scale: 1.500000
+  Adaptive Sync: disabled
+DP-3 \"Dell Inc. DELL P2720D (DP-3)\"
+  Make: Dell Inc.
+  Model: DELL P2720D
+  Serial: 1234
+  Physical size: 600x340 mm
+  Enabled: yes
+  Modes:
+    2560x1440 px, 59.951000 Hz (preferred, current)
+  Position: 1920,0
+  Transform: 90
+  Scale: 1.000000
+  Adaptive Sync: disabled
+HDMI-A-1 \"Unknown (HDMI-A-1)\"
+  Enabled: no
+  Modes:
+    1920x1080 px, 60.000000 Hz (preferred)
+";
+
+  const SWAYMSG: &str = r#"[
+  {
+    "name": "eDP-1", "active": true, "scale": 1.5, "transform": "normal",
+    "rect": { "x": 0, "y": 0, "width": 1920, "height": 1200 },
+    "current_mode": { "width": 2880, "height": 1800, "refresh": 60001 }
+  },
+  {
+    "name": "DP-3", "active": true, "scale": 1.0, "transform": "90",
+    "rect": { "x": 1920, "y": 0, "width": 1440, "height": 2560 },
+    "current_mode": { "width": 2560, "height": 1440, "refresh": 59951 }
+  },
+  {
+    "name": "HDMI-A-1", "active": false, "scale": -1.0, "transform": "normal",
+    "rect": { "x": 0, "y": 0, "width": 0, "height": 0 }
+  }
+]"#;
+
+  fn output(name: &str, width: u32, height: u32, x: i32, y: i32) -> Output {
+    Output {
+      name: name.to_string(),
+      width,
+      height,
+      x,
+      y,
+      scale: 1.0,
+    }
+  }
+
+  fn scaled(output: Output, scale: f64) -> Output {
+    Output { scale, ..output }
+  }
+
+  #[test]
+  fn parses_connected_outputs_with_modes() {
+    assert_eq!(
+      parse_xrandr(XRANDR),
+      vec![
+        output("DP-1", 2560, 1440, 0, 0),
+        output("HDMI-1", 1920, 1080, 2560, 180),
+      ]
+    );
+  }
+
+  #[test]
+  fn parses_wlr_randr() {
+    assert_eq!(
+      parse_wlr_randr(WLR_RANDR),
+      vec![
+        scaled(output("eDP-1", 1920, 1200, 0, 0), 1.5),
+        output("DP-3", 1440, 2560, 1920, 0),
+      ]
+    );
+  }
+
+  #[test]
+  fn parses_swaymsg_outputs() {
+    assert_eq!(
+      parse_sway_outputs(SWAYMSG).unwrap(),
+      vec![
+        scaled(output("eDP-1", 1920, 1200, 0, 0), 1.5),
+        output("DP-3", 1440, 2560, 1920, 0),
+      ]
+    );
+    assert!(parse_sway_outputs("not json").is_err());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn builds_outputs_from_mutter_state() {
+    use zbus::zvariant::{OwnedValue, Value};
+
+    let spec = |connector: &str| {
+      (
+        connector.to_string(),
+        "DEL".to_string(),
+        "DELL U2720Q".to_string(),
+        "ABC".to_string(),
+      )
+    };
+    let mode = |id: &str, width: i32, height: i32, current: bool| {
+      let mut properties = HashMap::new();
+      if current {
+        properties.insert(
+          "is-current".to_string(),
+          OwnedValue::try_from(Value::from(true)).unwrap(),
+        );
+      }
+      (
+        id.to_string(),
+        width,
+        height,
+        60.0,
+        1.0,
+        vec![1.0, 2.0],
+        properties,
+      )
+    };
+    let monitors = vec![
+      (
+        spec("DP-1"),
+        vec![
+          mode("3840x2160@60", 3840, 2160, true),
+          mode("1920x1080@60", 1920, 1080, false),
+        ],
+        HashMap::new(),
+      ),
+      (
+        spec("HDMI-1"),
+        vec![mode("1920x1080@60", 1920, 1080, true)],
+        HashMap::new(),
+      ),
+    ];
+    let logical = vec![
+      (0, 0, 2.0, 0, true, vec![spec("DP-1")], HashMap::new()),
+      (1920, 0, 1.0, 1, false, vec![spec("HDMI-1")], HashMap::new()),
+    ];
+
+    assert_eq!(
+      from_mutter_state(&monitors, &logical, false),
+      vec![
+        scaled(output("DP-1", 1920, 1080, 0, 0), 2.0),
+        output("HDMI-1", 1080, 1920, 1920, 0),
+      ]
+    );
+    assert_eq!(
+      from_mutter_state(&monitors, &logical, true)[0],
+      output("DP-1", 3840, 2160, 0, 0)
+    );
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn queries_recorded_command_output() {
+    use crate::api::freedesktop::test_support::fake_command;
+
+    let dir = tempfile::tempdir().unwrap();
+    let commands = Commands::with_path(dir.path());
+    // Each fake prints the recording stored next to it.
+    for (name, recording) in [("xrandr", XRANDR), ("swaymsg", SWAYMSG)] {
+      std::fs::write(dir.path().join(format!("{name}.txt")), recording).unwrap();
+      fake_command(dir.path(), name, "/bin/cat \"$0.txt\"");
+    }
+
+    assert_eq!(query_xrandr(&commands).unwrap().len(), 2);
+    assert_eq!(query_sway(&commands).unwrap().len(), 2);
+    assert!(query_wlr_randr(&commands).is_err());
+  }
+
+  #[test]
+  fn composes_images_side_by_side() {
+    let dir = tempfile::tempdir().unwrap();
+    let red = dir.path().join("red.png");
+    let blue = dir.path().join("blue.png");
+    RgbImage::from_pixel(8, 8, Rgb([255, 0, 0]))
+      .save(&red)
+      .unwrap();
+    RgbImage::from_pixel(8, 8, Rgb([0, 0, 255]))
+      .save(&blue)
+      .unwrap();
+
+    let canvas = compose_spanned(&[
+      (output("left", 4, 2, 0, 0), Some(red.as_path())),
+      (output("right", 4, 2, 4, 0), Some(blue.as_path())),
+      (output("below", 4, 2, 0, 2), None),
+    ])
+    .unwrap();
+
+    assert_eq!(canvas.dimensions(), (8, 4));
+    assert_eq!(canvas.get_pixel(1, 1), &Rgb([255, 0, 0]));
+    assert_eq!(canvas.get_pixel(6, 1), &Rgb([0, 0, 255]));
+    assert_eq!(canvas.get_pixel(1, 3), &Rgb([0, 0, 0]));
+
+    // A HiDPI output renders the whole layout at its scale.
+    let canvas = compose_spanned(&[
+      (scaled(output("left", 4, 2, 0, 0), 2.0), Some(red.as_path())),
+      (output("right", 4, 2, 4, 0), Some(blue.as_path())),
+    ])
+    .unwrap();
+    assert_eq!(canvas.dimensions(), (16, 4));
+    assert_eq!(canvas.get_pixel(7, 3), &Rgb([255, 0, 0]));
+    assert_eq!(canvas.get_pixel(8, 0), &Rgb([0, 0, 255]));
+  }
+}
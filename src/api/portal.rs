@@ -0,0 +1,516 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::{Fd, OwnedValue, Value};
+
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Icon, Notification, Urgency};
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+/// How long to wait for a click on a notification without a timeout of its own.
+const ACTION_WAIT_LIMIT: Duration = Duration::from_secs(30 * 60);
+
+/// Whether we run inside a Flatpak or Snap sandbox, where only the portals are reachable.
+pub fn is_sandboxed() -> bool {
+  Path::new("/.flatpak-info").exists()
+    || std::env::var_os("FLATPAK_ID").is_some()
+    || std::env::var_os("SNAP").is_some()
+}
+
+fn dbus_error(e: zbus::Error) -> DesktopApiError {
+  DesktopApiError::Backend(format!("portal error: {e}"))
+}
+
+/// DesktopApi using the XDG desktop portals, for sandboxed (Flatpak) installs.
+///
+/// Wallpapers go through `org.freedesktop.portal.Wallpaper`, files are opened with
+/// `org.freedesktop.portal.OpenURI` and notifications use `org.freedesktop.portal.Notification`.
+pub struct PortalDesktopApi {
+  /// Bus address, the session bus when `None`.
+  address: Option<String>,
+  connection: Mutex<Option<Connection>>,
+  next_notification: AtomicU64,
+}
+
+impl PortalDesktopApi {
+  pub fn new() -> Self {
+    PortalDesktopApi {
+      address: None,
+      connection: Mutex::new(None),
+      next_notification: AtomicU64::new(0),
+    }
+  }
+
+  /// Talk to the portal on the bus at `address` instead of the session bus.
+  pub fn with_address(address: impl Into<String>) -> Self {
+    PortalDesktopApi {
+      address: Some(address.into()),
+      ..Self::new()
+    }
+  }
+
+  /// The bus connection, opened on first use and shared afterwards.
+  fn connection(&self) -> Result<Connection, DesktopApiError> {
+    let mut connection = self.connection.lock().unwrap();
+    if let Some(connection) = connection.as_ref() {
+      return Ok(connection.clone());
+    }
+    let opened = match &self.address {
+      Some(address) => zbus::blocking::connection::Builder::address(address.as_str())
+        .and_then(|b| b.build())
+        .map_err(dbus_error)?,
+      None => Connection::session().map_err(dbus_error)?,
+    };
+    *connection = Some(opened.clone());
+    Ok(opened)
+  }
+
+  fn proxy(&self, interface: &'static str) -> Result<Proxy<'static>, DesktopApiError> {
+    Proxy::new(&self.connection()?, PORTAL_NAME, PORTAL_PATH, interface).map_err(dbus_error)
+  }
+
+  fn open(path: &Path) -> Result<File, DesktopApiError> {
+    if !path.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "file path {path:?} does not exist"
+      )));
+    }
+    Ok(File::open(path)?)
+  }
+
+  /// The `AddNotification` fields describing `notification`.
+  fn notification_fields(notification: &Notification) -> HashMap<&'static str, Value<'static>> {
+    let mut fields: HashMap<&str, Value<'static>> = HashMap::new();
+    fields.insert("title", Value::from(notification.title.clone()));
+    if let Some(body) = &notification.body {
+      fields.insert("body", Value::from(body.clone()));
+    }
+    if let Some(icon) = notification.icon.as_ref().and_then(Self::icon_value) {
+      fields.insert("icon", icon);
+    }
+    let priority = match notification.urgency {
+      Urgency::Low => "low",
+      Urgency::Normal => "normal",
+      Urgency::Critical => "urgent",
+    };
+    fields.insert("priority", Value::from(priority));
+    if !notification.actions.is_empty() {
+      let buttons: Vec<HashMap<&str, Value<'static>>> = notification
+        .actions
+        .iter()
+        .map(|action| {
+          HashMap::from([
+            ("label", Value::from(action.title.clone())),
+            ("action", Value::from(action.id.clone())),
+          ])
+        })
+        .collect();
+      fields.insert("buttons", Value::from(buttons));
+    }
+
+    fields
+  }
+
+  fn notification_id(&self) -> String {
+    format!(
+      "loxerpaper-{}",
+      self.next_notification.fetch_add(1, Ordering::SeqCst)
+    )
+  }
+
+  /// Serialize `icon` the way `g_icon_serialize` does, which is what the portal expects.
+  fn icon_value(icon: &Icon) -> Option<Value<'static>> {
+    match icon {
+      Icon::Resource(name) => Some(Value::from(("themed", Value::from(vec![name.clone()])))),
+      Icon::Raw(bytes) => Some(Value::from(("bytes", Value::from(bytes.clone())))),
+      Icon::Path(path) => std::fs::read(path)
+        .ok()
+        .map(|bytes| Value::from(("bytes", Value::from(bytes)))),
+    }
+  }
+}
+
+impl DesktopApi for PortalDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    let file = Self::open(image)?;
+    let options = HashMap::from([
+      ("show-preview", Value::from(false)),
+      ("set-on", Value::from("background")),
+    ]);
+
+    self
+      .proxy("org.freedesktop.portal.Wallpaper")?
+      .call_method("SetWallpaperFile", &("", Fd::from(&file), options))
+      .map_err(dbus_error)?;
+
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: false,
+      animated_wallpaper: false,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    let id = self.notification_id();
+    self
+      .proxy("org.freedesktop.portal.Notification")?
+      .call_method(
+        "AddNotification",
+        &(id.as_str(), Self::notification_fields(notification)),
+      )
+      .map_err(dbus_error)?;
+    Ok(())
+  }
+
+  /// The portal never reports dismissed notifications, so this gives up after the timeout
+  /// of `notification`, or `ACTION_WAIT_LIMIT` without one, withdraws the notification and
+  /// returns `None`.
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    let id = self.notification_id();
+    let proxy = self.proxy("org.freedesktop.portal.Notification")?;
+    let limit = notification.timeout.unwrap_or(ACTION_WAIT_LIMIT);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+      .enable_time()
+      .build()?;
+
+    runtime.block_on(async {
+      let proxy = proxy.inner();
+      // Subscribe first so an early click isn't missed.
+      let mut invoked = proxy
+        .receive_signal_with_args("ActionInvoked", &[(0, id.as_str())])
+        .await
+        .map_err(dbus_error)?;
+      proxy
+        .call_method(
+          "AddNotification",
+          &(id.as_str(), Self::notification_fields(notification)),
+        )
+        .await
+        .map_err(dbus_error)?;
+
+      let message = match tokio::time::timeout(limit, invoked.next()).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return Ok(None),
+        Err(_) => {
+          proxy
+            .call_method("RemoveNotification", &(id.as_str(),))
+            .await
+            .map_err(dbus_error)?;
+          return Ok(None);
+        }
+      };
+      let (_id, action, _parameter): (String, String, Vec<OwnedValue>) =
+        message.body().deserialize().map_err(dbus_error)?;
+      Ok(Some(action))
+    })
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    let handle = Self::open(file)?;
+    let options: HashMap<&str, Value> = HashMap::new();
+
+    self
+      .proxy("org.freedesktop.portal.OpenURI")?
+      .call_method("OpenFile", &("", Fd::from(&handle), options))
+      .map_err(dbus_error)?;
+
+    println!("Successfully opened file {file:?}");
+    Ok(())
+  }
+}
+
+impl Default for PortalDesktopApi {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+  use std::io::{BufRead, BufReader};
+  use std::process::{Child, Command, Stdio};
+
+  /// A private session bus, killed on drop.
+  pub struct PrivateBus {
+    child: Child,
+    pub address: String,
+  }
+
+  impl PrivateBus {
+    /// Start `dbus-daemon`, or `None` when it is not installed.
+    pub fn start() -> Option<Self> {
+      let mut child = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address=1"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+      let mut address = String::new();
+      BufReader::new(child.stdout.take()?)
+        .read_line(&mut address)
+        .ok()?;
+      Some(PrivateBus {
+        child,
+        address: address.trim().to_string(),
+      })
+    }
+  }
+
+  impl Drop for PrivateBus {
+    fn drop(&mut self) {
+      let _ = self.child.kill();
+      let _ = self.child.wait();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::test_support::PrivateBus;
+  use super::*;
+  use std::io::Read;
+  use std::sync::Arc;
+  use zbus::zvariant::{OwnedFd, OwnedObjectPath, OwnedValue};
+
+  /// Calls received by the mock portal: method name, content of the passed file, options.
+  type Calls = Arc<Mutex<Vec<(String, String, HashMap<String, OwnedValue>)>>>;
+
+  fn read_fd(fd: OwnedFd) -> String {
+    let mut content = String::new();
+    File::from(std::os::fd::OwnedFd::from(fd))
+      .read_to_string(&mut content)
+      .unwrap();
+    content
+  }
+
+  fn request_path() -> OwnedObjectPath {
+    OwnedObjectPath::try_from("/org/freedesktop/portal/desktop/request/1_1/t").unwrap()
+  }
+
+  struct MockWallpaper(Calls);
+
+  #[zbus::interface(name = "org.freedesktop.portal.Wallpaper")]
+  impl MockWallpaper {
+    fn set_wallpaper_file(
+      &self,
+      _parent_window: &str,
+      fd: OwnedFd,
+      options: HashMap<String, OwnedValue>,
+    ) -> OwnedObjectPath {
+      let call = ("SetWallpaperFile".to_string(), read_fd(fd), options);
+      self.0.lock().unwrap().push(call);
+      request_path()
+    }
+  }
+
+  struct MockOpenUri(Calls);
+
+  #[zbus::interface(name = "org.freedesktop.portal.OpenURI")]
+  impl MockOpenUri {
+    fn open_file(
+      &self,
+      _parent_window: &str,
+      fd: OwnedFd,
+      options: HashMap<String, OwnedValue>,
+    ) -> OwnedObjectPath {
+      let call = ("OpenFile".to_string(), read_fd(fd), options);
+      self.0.lock().unwrap().push(call);
+      request_path()
+    }
+  }
+
+  struct MockNotification(Calls);
+
+  /// Clicks the `horny` button of every notification with buttons, after a stray click on
+  /// another notification. Notifications without buttons are never clicked.
+  #[zbus::interface(name = "org.freedesktop.portal.Notification")]
+  impl MockNotification {
+    async fn add_notification(
+      &self,
+      #[zbus(signal_emitter)] emitter: zbus::object_server::SignalEmitter<'_>,
+      id: &str,
+      notification: HashMap<String, OwnedValue>,
+    ) -> zbus::fdo::Result<()> {
+      let clicked = notification.contains_key("buttons");
+      let call = ("AddNotification".to_string(), id.to_string(), notification);
+      self.0.lock().unwrap().push(call);
+      Self::action_invoked(&emitter, "other", "disgust", Vec::new()).await?;
+      if clicked {
+        Self::action_invoked(&emitter, id, "horny", Vec::new()).await?;
+      }
+      Ok(())
+    }
+
+    fn remove_notification(&self, id: &str) {
+      let call = (
+        "RemoveNotification".to_string(),
+        id.to_string(),
+        HashMap::new(),
+      );
+      self.0.lock().unwrap().push(call);
+    }
+
+    #[zbus(signal)]
+    async fn action_invoked(
+      emitter: &zbus::object_server::SignalEmitter<'_>,
+      id: &str,
+      action: &str,
+      parameter: Vec<OwnedValue>,
+    ) -> zbus::Result<()>;
+  }
+
+  /// Serve the mock portal on `bus`, returning the recorded calls.
+  fn mock_portal(bus: &PrivateBus) -> (Connection, Calls) {
+    let calls = Calls::default();
+    let connection = zbus::blocking::connection::Builder::address(bus.address.as_str())
+      .unwrap()
+      .name(PORTAL_NAME)
+      .unwrap()
+      .serve_at(PORTAL_PATH, MockWallpaper(calls.clone()))
+      .unwrap()
+      .serve_at(PORTAL_PATH, MockOpenUri(calls.clone()))
+      .unwrap()
+      .serve_at(PORTAL_PATH, MockNotification(calls.clone()))
+      .unwrap()
+      .build()
+      .unwrap();
+    (connection, calls)
+  }
+
+  #[test]
+  fn talks_to_the_portals() {
+    let Some(bus) = PrivateBus::start() else {
+      eprintln!("dbus-daemon not available, skipping");
+      return;
+    };
+    let (_server, calls) = mock_portal(&bus);
+    let dir = tempfile::tempdir().unwrap();
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, "image bytes").unwrap();
+    let api = PortalDesktopApi::with_address(bus.address.clone());
+
+    api.change_background(&image).unwrap();
+    api.open_file(&image).unwrap();
+    api
+      .send_notification(
+        &Notification::builder("Title")
+          .body("Body")
+          .action("horny-1", "Horny")
+          .build(),
+      )
+      .unwrap();
+
+    let calls = calls.lock().unwrap();
+    assert_eq!(calls.len(), 3);
+
+    let (method, content, options) = &calls[0];
+    assert_eq!(method, "SetWallpaperFile");
+    assert_eq!(content, "image bytes");
+    assert_eq!(
+      options["set-on"],
+      OwnedValue::try_from(Value::from("background")).unwrap()
+    );
+
+    let (method, content, _) = &calls[1];
+    assert_eq!(method, "OpenFile");
+    assert_eq!(content, "image bytes");
+
+    let (method, id, notification) = &calls[2];
+    assert_eq!(method, "AddNotification");
+    assert_eq!(id, "loxerpaper-0");
+    assert_eq!(
+      notification["title"],
+      OwnedValue::try_from(Value::from("Title")).unwrap()
+    );
+    assert!(notification.contains_key("buttons"));
+  }
+
+  #[test]
+  fn waits_for_the_clicked_action() {
+    let Some(bus) = PrivateBus::start() else {
+      eprintln!("dbus-daemon not available, skipping");
+      return;
+    };
+    let (_server, calls) = mock_portal(&bus);
+    let api = PortalDesktopApi::with_address(bus.address.clone());
+
+    let action = api
+      .wait_for_action(
+        &Notification::builder("Title")
+          .action("horny", "Horny")
+          .build(),
+      )
+      .unwrap();
+
+    assert_eq!(action.as_deref(), Some("horny"));
+    assert_eq!(calls.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn gives_up_on_an_unanswered_notification() {
+    let Some(bus) = PrivateBus::start() else {
+      eprintln!("dbus-daemon not available, skipping");
+      return;
+    };
+    let (_server, calls) = mock_portal(&bus);
+    let api = PortalDesktopApi::with_address(bus.address.clone());
+
+    let action = api
+      .wait_for_action(
+        &Notification::builder("Title")
+          .timeout(Duration::from_millis(200))
+          .build(),
+      )
+      .unwrap();
+
+    assert_eq!(action, None);
+    let calls = calls.lock().unwrap();
+    let methods: Vec<_> = calls
+      .iter()
+      .map(|(method, id, _)| (method.as_str(), id.as_str()))
+      .collect();
+    assert_eq!(
+      methods,
+      [
+        ("AddNotification", "loxerpaper-0"),
+        ("RemoveNotification", "loxerpaper-0")
+      ]
+    );
+  }
+}
@@ -25,17 +25,17 @@ use {
   std::ffi::OsStr,
   std::os::windows::ffi::OsStrExt,
   windows::{
-    core::PCWSTR,
     Win32::{
       Foundation::HWND,
       UI::{
         Shell::ShellExecuteW,
         WindowsAndMessaging::{
-          SystemParametersInfoW, SPIF_SENDCHANGE, SPIF_UPDATEINIFILE, SPI_SETDESKWALLPAPER,
-          SW_SHOWNORMAL,
+          SPI_SETDESKWALLPAPER, SPIF_SENDCHANGE, SPIF_UPDATEINIFILE, SW_SHOWNORMAL,
+          SystemParametersInfoW,
         },
       },
     },
+    core::PCWSTR,
   },
   winrt_notification::{Duration, IconCrop, Sound, Toast},
 };
@@ -108,6 +108,8 @@ impl DesktopApi for WindowsDesktopApi {
         set_wallpaper: true,
         raw_icon_bytes: false, // WinRT notifications don't easily support raw bytes
         open_file: true,
+        per_output_wallpaper: false,
+        animated_wallpaper: false,
       }
     }
 
@@ -119,6 +121,8 @@ impl DesktopApi for WindowsDesktopApi {
         set_wallpaper: false,
         raw_icon_bytes: false,
         open_file: false,
+        per_output_wallpaper: false,
+        animated_wallpaper: false,
       }
     }
   }
@@ -0,0 +1,486 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::sync::Mutex;
+
+use crate::api::freedesktop::{self, Commands};
+use crate::api::outputs::{self, Output};
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification};
+
+/// wlroots based compositors we know how to recognise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compositor {
+  Sway,
+  Hyprland,
+  River,
+}
+
+impl Compositor {
+  /// Detect the running compositor from the environment it exports.
+  pub fn detect() -> Option<Self> {
+    if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+      return Some(Compositor::Hyprland);
+    }
+    if std::env::var_os("SWAYSOCK").is_some() {
+      return Some(Compositor::Sway);
+    }
+    let desktop = std::env::var("XDG_CURRENT_DESKTOP")
+      .unwrap_or_default()
+      .to_lowercase();
+    match desktop.as_str() {
+      "sway" => Some(Compositor::Sway),
+      "hyprland" => Some(Compositor::Hyprland),
+      "river" => Some(Compositor::River),
+      _ => None,
+    }
+  }
+}
+
+/// Tool used to draw the wallpaper.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setter {
+  /// The `swww` daemon, driven through the `swww` client.
+  Swww,
+  /// The `hyprpaper` daemon, driven through `hyprctl hyprpaper`.
+  Hyprpaper,
+  /// A long-lived `swaybg` child that we restart on every change.
+  Swaybg,
+}
+
+/// DesktopApi for wlroots compositors (sway, Hyprland, river) without gsettings.
+pub struct WlrootsDesktopApi {
+  commands: Commands,
+  setter: Setter,
+  state: Mutex<SetterState>,
+}
+
+#[derive(Default)]
+struct SetterState {
+  /// Image shown on every output without its own.
+  all: Option<PathBuf>,
+  /// Images pinned to an output, by output name.
+  outputs: BTreeMap<String, PathBuf>,
+  /// Running `swaybg`, when that is the setter.
+  swaybg: Option<Child>,
+}
+
+impl WlrootsDesktopApi {
+  pub fn new() -> Self {
+    Self::with_commands(Commands::new())
+  }
+
+  /// Pick the setter by probing the daemons reachable through `commands`.
+  pub fn with_commands(commands: Commands) -> Self {
+    let setter = Self::detect_setter(&commands, Compositor::detect());
+    Self::with_setter(commands, setter)
+  }
+
+  pub fn with_setter(commands: Commands, setter: Setter) -> Self {
+    WlrootsDesktopApi {
+      commands,
+      setter,
+      state: Mutex::new(SetterState::default()),
+    }
+  }
+
+  pub fn setter(&self) -> Setter {
+    self.setter
+  }
+
+  /// Prefer a daemon that is already running, then fall back to managing `swaybg` ourselves.
+  fn detect_setter(commands: &Commands, compositor: Option<Compositor>) -> Setter {
+    if compositor == Some(Compositor::Hyprland)
+      && commands
+        .output("hyprctl", ["hyprpaper", "listloaded"])
+        .is_ok()
+    {
+      return Setter::Hyprpaper;
+    }
+    if commands.output("swww", ["query"]).is_ok() {
+      return Setter::Swww;
+    }
+    Setter::Swaybg
+  }
+
+  /// Show `image` on `output`, or on every output when `None`.
+  fn set(&self, output: Option<&str>, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    // Outputs to draw on, none meaning all of them.
+    let mut state = self.state.lock().unwrap();
+    let targets = match output {
+      Some(output) => vec![output.to_string()],
+      // swaybg draws pinned outputs over the image for all of them by itself.
+      None if state.outputs.is_empty() || self.setter == Setter::Swaybg => Vec::new(),
+      // Pinned outputs keep their image, the new one only goes to the others.
+      None => {
+        let unpinned: Vec<String> = self
+          .outputs()?
+          .into_iter()
+          .map(|o| o.name)
+          .filter(|name| !state.outputs.contains_key(name))
+          .collect();
+        if unpinned.is_empty() {
+          state.all = Some(image.to_path_buf());
+          return Ok(());
+        }
+        unpinned
+      }
+    };
+    match output {
+      Some(output) => {
+        state
+          .outputs
+          .insert(output.to_string(), image.to_path_buf());
+      }
+      None => state.all = Some(image.to_path_buf()),
+    }
+
+    match self.setter {
+      Setter::Swww => {
+        let targets = targets.join(",");
+        let mut args = vec![OsStr::new("img")];
+        if !targets.is_empty() {
+          args.extend([OsStr::new("-o"), OsStr::new(targets.as_str())]);
+        }
+        args.push(image.as_os_str());
+        self.commands.run("swww", args)
+      }
+      Setter::Hyprpaper => {
+        let image = image.display().to_string();
+        self
+          .commands
+          .run("hyprctl", ["hyprpaper", "preload", image.as_str()])?;
+        // An empty output name sets every output.
+        let everywhere = [String::new()];
+        let targets = if targets.is_empty() {
+          &everywhere[..]
+        } else {
+          &targets[..]
+        };
+        for target in targets {
+          let target = format!("{target},{image}");
+          self
+            .commands
+            .run("hyprctl", ["hyprpaper", "wallpaper", target.as_str()])?;
+        }
+        // Keeping every previous image loaded would leak memory in the daemon.
+        let _ = self
+          .commands
+          .run("hyprctl", ["hyprpaper", "unload", "unused"]);
+        Ok(())
+      }
+      Setter::Swaybg => self.restart_swaybg(&mut state),
+    }
+  }
+
+  /// Start a `swaybg` drawing the current state, then stop the previous one.
+  ///
+  /// The new child is started first so the desktop never flashes empty.
+  fn restart_swaybg(&self, state: &mut SetterState) -> Result<(), DesktopApiError> {
+    let mut args: Vec<&OsStr> = Vec::new();
+    let all = state.all.iter().map(|image| ("*", image));
+    let pinned = state.outputs.iter().map(|(o, image)| (o.as_str(), image));
+    for (output, image) in all.chain(pinned) {
+      args.extend([
+        OsStr::new("-o"),
+        OsStr::new(output),
+        OsStr::new("-i"),
+        image.as_os_str(),
+        OsStr::new("-m"),
+        OsStr::new("fill"),
+      ]);
+    }
+
+    let child = self
+      .commands
+      .command("swaybg")
+      .args(args)
+      .stdin(Stdio::null())
+      .spawn()
+      .map_err(DesktopApiError::Io)?;
+
+    if let Some(previous) = state.swaybg.replace(child) {
+      Self::stop(previous);
+    }
+    Ok(())
+  }
+
+  fn stop(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+  }
+}
+
+impl DesktopApi for WlrootsDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    self.set(None, image)?;
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn change_background_on(&self, output: &str, image: &Path) -> Result<(), DesktopApiError> {
+    self.set(Some(output), image)?;
+    println!("Successfully changed wallpaper of {output} to {image:?}");
+    Ok(())
+  }
+
+  fn change_background_animated(
+    &self,
+    output: Option<&str>,
+    media: &Path,
+  ) -> Result<(), DesktopApiError> {
+    // swww plays GIFs itself, videos need a player we don't manage.
+    let is_gif = media
+      .extension()
+      .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+    if self.setter != Setter::Swww || !is_gif {
+      return Err(DesktopApiError::Unsupported);
+    }
+    self.set(output, media)?;
+    println!("Successfully changed wallpaper to animated {media:?}");
+    Ok(())
+  }
+
+  fn outputs(&self) -> Result<Vec<Output>, DesktopApiError> {
+    if Compositor::detect() == Some(Compositor::Sway) && self.commands.exists("swaymsg") {
+      outputs::query_sway(&self.commands)
+    } else {
+      outputs::query_wlr_randr(&self.commands)
+    }
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: true,
+      animated_wallpaper: self.setter == Setter::Swww,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    freedesktop::send_notification(notification)
+  }
+
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+
+  fn shutdown(&self) {
+    if let Some(child) = self.state.lock().unwrap().swaybg.take() {
+      Self::stop(child);
+    }
+  }
+}
+
+impl Default for WlrootsDesktopApi {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Drop for WlrootsDesktopApi {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+
+  fn image(dir: &Path, name: &str) -> PathBuf {
+    let image = dir.join(name);
+    std::fs::write(&image, b"png").unwrap();
+    image
+  }
+
+  #[test]
+  fn prefers_running_daemons() {
+    let dir = tempfile::tempdir().unwrap();
+    let commands = Commands::with_path(dir.path());
+    assert_eq!(
+      WlrootsDesktopApi::detect_setter(&commands, Some(Compositor::Hyprland)),
+      Setter::Swaybg
+    );
+
+    recording_command(dir.path(), "swww");
+    assert_eq!(
+      WlrootsDesktopApi::detect_setter(&commands, Some(Compositor::Hyprland)),
+      Setter::Swww
+    );
+
+    recording_command(dir.path(), "hyprctl");
+    assert_eq!(
+      WlrootsDesktopApi::detect_setter(&commands, Some(Compositor::Hyprland)),
+      Setter::Hyprpaper
+    );
+    assert_eq!(
+      WlrootsDesktopApi::detect_setter(&commands, Some(Compositor::Sway)),
+      Setter::Swww
+    );
+  }
+
+  #[test]
+  fn swww_targets_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "swww");
+    let a = image(dir.path(), "a.png");
+    let api = WlrootsDesktopApi::with_setter(Commands::with_path(dir.path()), Setter::Swww);
+
+    api.change_background(&a).unwrap();
+    api.change_background_on("DP-1", &a).unwrap();
+
+    let a = a.display().to_string();
+    assert_eq!(
+      recorded(dir.path(), "swww"),
+      ["img", &a, "img", "-o", "DP-1", &a]
+    );
+  }
+
+  #[test]
+  fn swww_keeps_pinned_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "swww");
+    let listing = dir.path().join("wlr-randr.txt");
+    std::fs::write(
+      &listing,
+      "DP-1 \"A\"\n  Enabled: yes\n  Modes:\n    1920x1080 px, 60.000000 Hz (current)\n\
+       HDMI-A-1 \"B\"\n  Enabled: yes\n  Modes:\n    1920x1080 px, 60.000000 Hz (current)\n",
+    )
+    .unwrap();
+    fake_command(
+      dir.path(),
+      "wlr-randr",
+      &format!("exec /bin/cat '{}'", listing.display()),
+    );
+    let a = image(dir.path(), "a.png");
+    let b = image(dir.path(), "b.png");
+    let api = WlrootsDesktopApi::with_setter(Commands::with_path(dir.path()), Setter::Swww);
+
+    api.change_background_on("DP-1", &a).unwrap();
+    api.change_background(&b).unwrap();
+
+    let (a, b) = (a.display().to_string(), b.display().to_string());
+    assert_eq!(
+      recorded(dir.path(), "swww"),
+      ["img", "-o", "DP-1", &a, "img", "-o", "HDMI-A-1", &b]
+    );
+  }
+
+  #[test]
+  fn hyprpaper_preloads_then_sets() {
+    let dir = tempfile::tempdir().unwrap();
+    recording_command(dir.path(), "hyprctl");
+    let a = image(dir.path(), "a.png");
+    let api = WlrootsDesktopApi::with_setter(Commands::with_path(dir.path()), Setter::Hyprpaper);
+
+    api.change_background_on("HDMI-A-1", &a).unwrap();
+
+    let a = a.display().to_string();
+    assert_eq!(
+      recorded(dir.path(), "hyprctl"),
+      [
+        "hyprpaper",
+        "preload",
+        &a,
+        "hyprpaper",
+        "wallpaper",
+        &format!("HDMI-A-1,{a}"),
+        "hyprpaper",
+        "unload",
+        "unused",
+      ]
+    );
+  }
+
+  /// Wait until `log` has `count` lines, the fake `swaybg` writes it asynchronously.
+  fn wait_for_lines(log: &Path, count: usize) -> Vec<String> {
+    for _ in 0..100 {
+      let text = std::fs::read_to_string(log).unwrap_or_default();
+      let lines: Vec<String> = text.lines().map(str::to_string).collect();
+      if lines.len() >= count {
+        return lines;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    panic!("{log:?} never reached {count} lines");
+  }
+
+  #[test]
+  fn swaybg_is_restarted_and_torn_down() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("swaybg.log");
+    fake_command(
+      dir.path(),
+      "swaybg",
+      &format!("echo \"$*\" >> '{}'\nexec /bin/sleep 60", log.display()),
+    );
+    let a = image(dir.path(), "a.png");
+    let b = image(dir.path(), "b.png");
+    let api = WlrootsDesktopApi::with_setter(Commands::with_path(dir.path()), Setter::Swaybg);
+
+    api.change_background(&a).unwrap();
+    wait_for_lines(&log, 1);
+    let first_pid = api.state.lock().unwrap().swaybg.as_ref().unwrap().id();
+    api.change_background_on("DP-1", &b).unwrap();
+    let lines = wait_for_lines(&log, 2);
+
+    assert_eq!(
+      lines,
+      [
+        format!("-o * -i {} -m fill", a.display()),
+        format!(
+          "-o * -i {} -m fill -o DP-1 -i {} -m fill",
+          a.display(),
+          b.display()
+        ),
+      ]
+    );
+
+    {
+      let mut state = api.state.lock().unwrap();
+      let child = state.swaybg.as_mut().unwrap();
+      assert_ne!(child.id(), first_pid);
+      assert!(child.try_wait().unwrap().is_none());
+    }
+
+    api.shutdown();
+    assert!(api.state.lock().unwrap().swaybg.is_none());
+  }
+}
@@ -0,0 +1,516 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use image::RgbImage;
+use image::imageops::FilterType;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::xproto::{
+  AtomEnum, ChangeWindowAttributesAux, CloseDown, ConnectionExt as _, CreateGCAux, ImageFormat,
+  ImageOrder, PropMode, Setup, VisualClass,
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+use crate::api::freedesktop::{self, Commands};
+use crate::api::outputs::{compose_spanned, parse_xrandr};
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification, Output};
+
+/// Plain X11 implementation of DesktopApi for window managers without a desktop (i3, bspwm,
+/// openbox, ...).
+///
+/// The image is drawn into a pixmap that is kept alive after we disconnect and set as the
+/// root window background. `_XROOTPMAP_ID` and `ESETROOT_PMAP_ID` point to it so compositors
+/// and pseudo-transparent terminals pick it up, the same convention `feh` and `hsetroot` use.
+pub struct X11DesktopApi {
+  display: Option<String>,
+  commands: Commands,
+  span: Mutex<SpanState>,
+}
+
+#[derive(Default)]
+struct SpanState {
+  /// Image assigned to each output, by output name.
+  images: HashMap<String, PathBuf>,
+  /// Last image set for the whole screen, shown on outputs without their own image.
+  fallback: Option<PathBuf>,
+}
+
+fn x11_error(e: impl std::fmt::Display) -> DesktopApiError {
+  DesktopApiError::Backend(format!("x11 error: {e}"))
+}
+
+impl X11DesktopApi {
+  pub fn new() -> Self {
+    Self::with_display(None)
+  }
+
+  /// Use the X display `display` instead of `$DISPLAY`.
+  pub fn with_display(display: Option<String>) -> Self {
+    X11DesktopApi {
+      display,
+      commands: Commands::new(),
+      span: Mutex::new(SpanState::default()),
+    }
+  }
+
+  fn connect(&self) -> Result<(RustConnection, usize), DesktopApiError> {
+    x11rb::connect(self.display.as_deref()).map_err(x11_error)
+  }
+
+  /// Size of the root window of the default screen.
+  fn root_size(&self) -> Result<(u32, u32), DesktopApiError> {
+    let (conn, screen_num) = self.connect()?;
+    let screen = &conn.setup().roots[screen_num];
+    Ok((
+      screen.width_in_pixels as u32,
+      screen.height_in_pixels as u32,
+    ))
+  }
+
+  /// Draw `image` on the root window, top-left aligned.
+  fn set_root(&self, image: &RgbImage) -> Result<(), DesktopApiError> {
+    let (conn, screen_num) = self.connect()?;
+    let setup = conn.setup();
+    let screen = &setup.roots[screen_num];
+    let root = screen.root;
+    let depth = screen.root_depth;
+    let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+    let format = PixelFormat::of_root(setup, screen_num)?;
+
+    let pixmap = conn.generate_id().map_err(x11_error)?;
+    conn
+      .create_pixmap(depth, pixmap, root, width, height)
+      .map_err(x11_error)?;
+    let gc = conn.generate_id().map_err(x11_error)?;
+    conn
+      .create_gc(gc, pixmap, &CreateGCAux::new())
+      .map_err(x11_error)?;
+
+    // Convert to the server's pixel format and upload in strips that fit in a single request.
+    let row_bytes = format.row_bytes(width as usize);
+    let max_rows = ((conn.maximum_request_bytes() - 32) / row_bytes).max(1);
+    let mut strip = Vec::with_capacity(row_bytes * max_rows);
+    for top in (0..height as u32).step_by(max_rows) {
+      let rows = (height as u32 - top).min(max_rows as u32);
+      strip.clear();
+      for y in top..top + rows {
+        for x in 0..width as u32 {
+          let rgb = if x < image.width() && y < image.height() {
+            image.get_pixel(x, y).0
+          } else {
+            [0, 0, 0]
+          };
+          format.push(rgb, &mut strip);
+        }
+        strip.resize(strip.len().next_multiple_of(format.pad_bytes), 0);
+      }
+      conn
+        .put_image(
+          ImageFormat::Z_PIXMAP,
+          pixmap,
+          gc,
+          width,
+          rows as u16,
+          0,
+          top as i16,
+          0,
+          depth,
+          &strip,
+        )
+        .map_err(x11_error)?;
+    }
+    conn.free_gc(gc).map_err(x11_error)?;
+
+    let root_atom = conn
+      .intern_atom(false, b"_XROOTPMAP_ID")
+      .map_err(x11_error)?
+      .reply()
+      .map_err(x11_error)?
+      .atom;
+    let esetroot_atom = conn
+      .intern_atom(false, b"ESETROOT_PMAP_ID")
+      .map_err(x11_error)?
+      .reply()
+      .map_err(x11_error)?
+      .atom;
+
+    // Free the pixmap left by the previous setter, as Esetroot does: it was kept alive by
+    // RetainPermanent so killing its client is the only way to release it.
+    let previous = |atom| -> Option<u32> {
+      conn
+        .get_property(false, root, atom, AtomEnum::PIXMAP, 0, 1)
+        .ok()?
+        .reply()
+        .ok()?
+        .value32()?
+        .next()
+    };
+    if let (Some(old_root), Some(old_esetroot)) = (previous(root_atom), previous(esetroot_atom))
+      && old_root == old_esetroot
+    {
+      let _ = conn.kill_client(old_root);
+    }
+
+    for atom in [root_atom, esetroot_atom] {
+      conn
+        .change_property32(PropMode::REPLACE, root, atom, AtomEnum::PIXMAP, &[pixmap])
+        .map_err(x11_error)?;
+    }
+    conn
+      .change_window_attributes(
+        root,
+        &ChangeWindowAttributesAux::new().background_pixmap(pixmap),
+      )
+      .map_err(x11_error)?;
+    conn
+      .clear_area(false, root, 0, 0, 0, 0)
+      .map_err(x11_error)?;
+
+    // Keep the pixmap once we disconnect.
+    conn
+      .set_close_down_mode(CloseDown::RETAIN_PERMANENT)
+      .map_err(x11_error)?;
+    conn.sync().map_err(x11_error)?;
+    Ok(())
+  }
+
+  /// Compose the images of `span` across `outputs` and draw the result on the root window.
+  fn show_spanned(&self, span: &SpanState, outputs: &[Output]) -> Result<(), DesktopApiError> {
+    let layout: Vec<(Output, Option<&Path>)> = outputs
+      .iter()
+      .map(|o| {
+        let image = span.images.get(&o.name).or(span.fallback.as_ref());
+        (o.clone(), image.map(PathBuf::as_path))
+      })
+      .collect();
+    self.set_root(&compose_spanned(&layout)?)
+  }
+}
+
+/// How the root window stores a pixel, from the setup of the server.
+///
+/// Any TrueColor or DirectColor visual with 8 to 32 bits per pixel is handled, palette based
+/// visuals are refused.
+#[derive(Debug, PartialEq)]
+struct PixelFormat {
+  bytes_per_pixel: usize,
+  /// Rows are padded to a multiple of this many bytes.
+  pad_bytes: usize,
+  red_mask: u32,
+  green_mask: u32,
+  blue_mask: u32,
+  msb_first: bool,
+}
+
+impl PixelFormat {
+  fn of_root(setup: &Setup, screen_num: usize) -> Result<Self, DesktopApiError> {
+    let screen = &setup.roots[screen_num];
+    let depth = screen.root_depth;
+    let visual = screen
+      .allowed_depths
+      .iter()
+      .filter(|d| d.depth == depth)
+      .flat_map(|d| &d.visuals)
+      .find(|v| v.visual_id == screen.root_visual);
+    let pixmap_format = setup.pixmap_formats.iter().find(|f| f.depth == depth);
+    let (Some(visual), Some(pixmap_format)) = (visual, pixmap_format) else {
+      return Err(DesktopApiError::Backend(format!(
+        "no root visual or pixmap format for depth {depth}"
+      )));
+    };
+
+    let bits_per_pixel = pixmap_format.bits_per_pixel;
+    let direct = matches!(
+      visual.class,
+      VisualClass::TRUE_COLOR | VisualClass::DIRECT_COLOR
+    );
+    if !direct || bits_per_pixel % 8 != 0 || !(8..=32).contains(&bits_per_pixel) {
+      return Err(DesktopApiError::Backend(format!(
+        "unsupported root visual: {:?} at {bits_per_pixel} bits per pixel",
+        visual.class
+      )));
+    }
+    Ok(PixelFormat {
+      bytes_per_pixel: bits_per_pixel as usize / 8,
+      pad_bytes: (pixmap_format.scanline_pad as usize / 8).max(1),
+      red_mask: visual.red_mask,
+      green_mask: visual.green_mask,
+      blue_mask: visual.blue_mask,
+      msb_first: setup.image_byte_order == ImageOrder::MSB_FIRST,
+    })
+  }
+
+  /// Bytes in a row of `width` pixels, padding included.
+  fn row_bytes(&self, width: usize) -> usize {
+    (width * self.bytes_per_pixel).next_multiple_of(self.pad_bytes)
+  }
+
+  /// Append the pixel of color `rgb` to `out`.
+  fn push(&self, [r, g, b]: [u8; 3], out: &mut Vec<u8>) {
+    let channel = |value: u8, mask: u32| {
+      let shift = mask.trailing_zeros() % 32;
+      let max = mask >> shift;
+      ((value as u32 * max + 127) / 255) << shift
+    };
+    let pixel =
+      channel(r, self.red_mask) | channel(g, self.green_mask) | channel(b, self.blue_mask);
+    let bytes = if self.msb_first {
+      &pixel.to_be_bytes()[4 - self.bytes_per_pixel..]
+    } else {
+      &pixel.to_le_bytes()[..self.bytes_per_pixel]
+    };
+    out.extend_from_slice(bytes);
+  }
+}
+
+fn open_image(path: &Path) -> Result<image::DynamicImage, DesktopApiError> {
+  image::open(path).map_err(|e| DesktopApiError::Backend(format!("failed to decode {path:?}: {e}")))
+}
+
+impl DesktopApi for X11DesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let mut span = self.span.lock().unwrap();
+    span.fallback = Some(image.to_path_buf());
+
+    // Pinned outputs keep their image, the new one only fills the others.
+    if !span.images.is_empty() {
+      self.show_spanned(&span, &self.outputs()?)?;
+    } else {
+      let (width, height) = self.root_size()?;
+      let scaled = open_image(image)?
+        .resize_to_fill(width, height, FilterType::Lanczos3)
+        .to_rgb8();
+      self.set_root(&scaled)?;
+    }
+
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn change_background_on(&self, output: &str, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let outputs = self.outputs()?;
+    if !outputs.iter().any(|o| o.name == output) {
+      return Err(DesktopApiError::Backend(format!(
+        "output {output} is not connected"
+      )));
+    }
+
+    let mut span = self.span.lock().unwrap();
+    span.images.insert(output.to_string(), image.to_path_buf());
+    self.show_spanned(&span, &outputs)?;
+
+    println!("Successfully changed wallpaper of {output} to {image:?}");
+    Ok(())
+  }
+
+  fn outputs(&self) -> Result<Vec<Output>, DesktopApiError> {
+    let mut command = self.commands.command("xrandr");
+    command.arg("--query");
+    if let Some(display) = &self.display {
+      command.env("DISPLAY", display);
+    }
+    let output = command.output().map_err(DesktopApiError::Io)?;
+    if !output.status.success() {
+      return Err(DesktopApiError::Backend(format!(
+        "xrandr failed with exit code: {}",
+        output.status
+      )));
+    }
+    Ok(parse_xrandr(&String::from_utf8_lossy(&output.stdout)))
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: true,
+      animated_wallpaper: false,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    freedesktop::send_notification(notification)
+  }
+
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+}
+
+impl Default for X11DesktopApi {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::Rgb;
+  use std::io::{BufRead, BufReader};
+  use std::process::{Child, Command, Stdio};
+
+  /// A throwaway Xvfb server, killed on drop.
+  struct Xvfb {
+    child: Child,
+    display: String,
+  }
+
+  impl Xvfb {
+    /// Start Xvfb on a free display, or `None` when it is not installed.
+    fn start() -> Option<Self> {
+      let mut child = Command::new("Xvfb")
+        .args([
+          "-displayfd",
+          "1",
+          "-screen",
+          "0",
+          "64x48x24",
+          "-nolisten",
+          "tcp",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+      let mut line = String::new();
+      BufReader::new(child.stdout.take()?)
+        .read_line(&mut line)
+        .ok()?;
+      Some(Xvfb {
+        child,
+        display: format!(":{}", line.trim()),
+      })
+    }
+  }
+
+  impl Drop for Xvfb {
+    fn drop(&mut self) {
+      let _ = self.child.kill();
+      let _ = self.child.wait();
+    }
+  }
+
+  fn root_pixmap_pixel(display: &str, x: i16, y: i16) -> (u32, [u8; 4]) {
+    let (conn, screen_num) = x11rb::connect(Some(display)).unwrap();
+    let root = conn.setup().roots[screen_num].root;
+    let atom = conn
+      .intern_atom(true, b"_XROOTPMAP_ID")
+      .unwrap()
+      .reply()
+      .unwrap()
+      .atom;
+    let pixmap = conn
+      .get_property(false, root, atom, AtomEnum::PIXMAP, 0, 1)
+      .unwrap()
+      .reply()
+      .unwrap()
+      .value32()
+      .unwrap()
+      .next()
+      .unwrap();
+    let image = conn
+      .get_image(ImageFormat::Z_PIXMAP, pixmap, x, y, 1, 1, !0)
+      .unwrap()
+      .reply()
+      .unwrap();
+    (pixmap, image.data[..4].try_into().unwrap())
+  }
+
+  #[test]
+  fn encodes_pixels_for_the_visual() {
+    let rgb565 = PixelFormat {
+      bytes_per_pixel: 2,
+      pad_bytes: 4,
+      red_mask: 0xF800,
+      green_mask: 0x07E0,
+      blue_mask: 0x001F,
+      msb_first: false,
+    };
+    let mut out = Vec::new();
+    rgb565.push([255, 0, 0], &mut out);
+    rgb565.push([0, 255, 0], &mut out);
+    assert_eq!(out, [0x00, 0xF8, 0xE0, 0x07]);
+    assert_eq!(rgb565.row_bytes(3), 8);
+
+    let xrgb = PixelFormat {
+      bytes_per_pixel: 4,
+      pad_bytes: 4,
+      red_mask: 0xFF0000,
+      green_mask: 0x00FF00,
+      blue_mask: 0x0000FF,
+      msb_first: true,
+    };
+    let mut out = Vec::new();
+    xrgb.push([1, 2, 3], &mut out);
+    assert_eq!(out, [0, 1, 2, 3]);
+  }
+
+  #[test]
+  #[ignore = "needs Xvfb, run with `cargo test -- --ignored`"]
+  fn sets_root_pixmap_under_xvfb() {
+    let xvfb = Xvfb::start().expect("Xvfb is not installed");
+
+    let dir = tempfile::tempdir().unwrap();
+    let red = dir.path().join("red.png");
+    let blue = dir.path().join("blue.png");
+    RgbImage::from_pixel(4, 4, Rgb([255, 0, 0]))
+      .save(&red)
+      .unwrap();
+    RgbImage::from_pixel(4, 4, Rgb([0, 0, 255]))
+      .save(&blue)
+      .unwrap();
+    let api = X11DesktopApi::with_display(Some(xvfb.display.clone()));
+
+    api.change_background(&red).unwrap();
+    let (first, pixel) = root_pixmap_pixel(&xvfb.display, 10, 10);
+    assert_eq!(pixel[..3], [0, 0, 255]);
+
+    api.change_background(&blue).unwrap();
+    let (second, pixel) = root_pixmap_pixel(&xvfb.display, 63, 47);
+    assert_eq!(pixel[..3], [255, 0, 0]);
+    assert_ne!(first, second);
+  }
+}
@@ -0,0 +1,234 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::api::freedesktop::{self, Commands};
+use crate::api::{DesktopApi, DesktopApiError, DesktopCapabilities, Notification};
+
+const CHANNEL: &str = "xfce4-desktop";
+
+/// XFCE implementation of DesktopApi using `xfconf-query` on the `xfce4-desktop` channel.
+///
+/// xfdesktop keeps one `last-image` property per monitor and workspace, every one of them
+/// is updated, except those of monitors with an image of their own.
+pub struct XfceDesktopApi {
+  commands: Commands,
+  /// Monitors given their own image, left alone by desktop-wide changes.
+  pinned: Mutex<HashSet<String>>,
+}
+
+impl XfceDesktopApi {
+  pub fn new() -> Self {
+    Self::with_commands(Commands::new())
+  }
+
+  pub fn with_commands(commands: Commands) -> Self {
+    XfceDesktopApi {
+      commands,
+      pinned: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// All `last-image` properties, optionally restricted to one monitor.
+  fn image_properties(&self, output: Option<&str>) -> Result<Vec<String>, DesktopApiError> {
+    let listing = self
+      .commands
+      .output("xfconf-query", ["-c", CHANNEL, "-l"])?;
+    let monitor = output.map(|o| format!("/monitor{o}/"));
+    Ok(
+      listing
+        .lines()
+        .map(str::trim)
+        .filter(|p| p.ends_with("/last-image"))
+        .filter(|p| monitor.as_ref().is_none_or(|m| p.contains(m.as_str())))
+        .map(str::to_string)
+        .collect(),
+    )
+  }
+
+  fn set(&self, output: Option<&str>, image: &Path) -> Result<(), DesktopApiError> {
+    if !image.exists() {
+      return Err(DesktopApiError::InvalidNotification(format!(
+        "image path {image:?} does not exist"
+      )));
+    }
+
+    let mut properties = self.image_properties(output)?;
+    if properties.is_empty() {
+      return Err(DesktopApiError::Backend(match output {
+        Some(output) => format!("no {CHANNEL} backdrop found for output {output}"),
+        None => format!("no {CHANNEL} backdrop found"),
+      }));
+    }
+
+    let mut pinned = self.pinned.lock().unwrap();
+    if output.is_none() {
+      properties.retain(|p| {
+        !pinned
+          .iter()
+          .any(|o| p.contains(format!("/monitor{o}/").as_str()))
+      });
+    }
+
+    let image = image.display().to_string();
+    for property in properties {
+      self.commands.run(
+        "xfconf-query",
+        ["-c", CHANNEL, "-p", property.as_str(), "-s", image.as_str()],
+      )?;
+    }
+    if let Some(output) = output {
+      pinned.insert(output.to_string());
+    }
+    Ok(())
+  }
+}
+
+impl DesktopApi for XfceDesktopApi {
+  fn change_background(&self, image: &Path) -> Result<(), DesktopApiError> {
+    self.set(None, image)?;
+    println!("Successfully changed wallpaper to {image:?}");
+    Ok(())
+  }
+
+  fn change_background_on(&self, output: &str, image: &Path) -> Result<(), DesktopApiError> {
+    self.set(Some(output), image)?;
+    println!("Successfully changed wallpaper of {output} to {image:?}");
+    Ok(())
+  }
+
+  fn capabilities(&self) -> DesktopCapabilities {
+    DesktopCapabilities {
+      notifications: true,
+      actions: true,
+      set_wallpaper: true,
+      raw_icon_bytes: true,
+      open_file: true,
+      per_output_wallpaper: true,
+      animated_wallpaper: false,
+    }
+  }
+
+  fn send_notification(&self, notification: &Notification) -> Result<(), DesktopApiError> {
+    freedesktop::send_notification(notification)
+  }
+
+  fn wait_for_action(
+    &self,
+    notification: &Notification,
+  ) -> Result<Option<String>, DesktopApiError> {
+    freedesktop::wait_for_action(notification)
+  }
+
+  fn open_file(&self, file: &Path) -> Result<(), DesktopApiError> {
+    freedesktop::open_file(&self.commands, file)
+  }
+}
+
+impl Default for XfceDesktopApi {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::freedesktop::test_support::*;
+
+  /// A fake `xfconf-query` listing two monitors with two workspaces each.
+  fn fake_xfconf(dir: &Path) {
+    let log = dir.join("xfconf-query.log");
+    fake_command(
+      dir,
+      "xfconf-query",
+      &format!(
+        "case \"$*\" in\n\
+         *-l*) printf '%s\\n' \
+           /backdrop/screen0/monitorDP-1/workspace0/color-style \
+           /backdrop/screen0/monitorDP-1/workspace0/last-image \
+           /backdrop/screen0/monitorDP-1/workspace1/last-image \
+           /backdrop/screen0/monitorHDMI-1/workspace0/last-image ;;\n\
+         *) echo \"$*\" >> '{}' ;;\n\
+         esac",
+        log.display()
+      ),
+    );
+  }
+
+  fn set_calls(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("xfconf-query.log"))
+      .unwrap_or_default()
+      .lines()
+      .map(str::to_string)
+      .collect()
+  }
+
+  #[test]
+  fn sets_every_last_image_property() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_xfconf(dir.path());
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+    let xfce = XfceDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    xfce.change_background(&image).unwrap();
+
+    let image = image.display();
+    assert_eq!(
+      set_calls(dir.path()),
+      [
+        format!(
+          "-c xfce4-desktop -p /backdrop/screen0/monitorDP-1/workspace0/last-image -s {image}"
+        ),
+        format!(
+          "-c xfce4-desktop -p /backdrop/screen0/monitorDP-1/workspace1/last-image -s {image}"
+        ),
+        format!(
+          "-c xfce4-desktop -p /backdrop/screen0/monitorHDMI-1/workspace0/last-image -s {image}"
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn targets_a_single_monitor() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_xfconf(dir.path());
+    let image = dir.path().join("a.png");
+    std::fs::write(&image, b"png").unwrap();
+    let xfce = XfceDesktopApi::with_commands(Commands::with_path(dir.path()));
+
+    xfce.change_background_on("HDMI-1", &image).unwrap();
+    assert_eq!(set_calls(dir.path()).len(), 1);
+
+    // Later desktop-wide changes leave the pinned monitor alone.
+    xfce.change_background(&image).unwrap();
+    let calls = set_calls(dir.path());
+    assert_eq!(calls.len(), 3);
+    assert!(calls[1..].iter().all(|c| c.contains("monitorDP-1")));
+
+    assert!(matches!(
+      xfce.change_background_on("DP-9", &image),
+      Err(DesktopApiError::Backend(_))
+    ));
+  }
+}
@@ -0,0 +1,235 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Approve-before-apply: new images wait for the user's consent before reaching the desktop.
+//!
+//! Images are approved or rejected from the review notification, the `approve` and `reject`
+//! commands, or automatically once `ApprovalPolicy::timeout` runs out.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::model::config::{Preferences, TimeoutAction};
+
+/// What happens to a pending image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+  Approve,
+  Reject,
+}
+
+impl From<TimeoutAction> for Decision {
+  fn from(action: TimeoutAction) -> Self {
+    match action {
+      TimeoutAction::Apply => Decision::Approve,
+      TimeoutAction::Reject => Decision::Reject,
+    }
+  }
+}
+
+/// How long images wait for approval and what happens when nobody answers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApprovalPolicy {
+  /// How long an image waits, forever when `None`.
+  pub timeout: Option<Duration>,
+  pub on_timeout: Decision,
+  /// Send a Disgust response for rejected images.
+  pub disgust_on_reject: bool,
+}
+
+impl ApprovalPolicy {
+  /// The policy set in `preferences`, `None` unless `requireApproval` is on.
+  pub fn from_preferences(preferences: &Preferences) -> Option<Self> {
+    if !preferences.require_approval.unwrap_or(false) {
+      return None;
+    }
+    Some(ApprovalPolicy {
+      timeout: preferences
+        .approval_timeout
+        .map(|minutes| Duration::from_secs(minutes * 60)),
+      on_timeout: preferences
+        .approval_timeout_action
+        .unwrap_or_default()
+        .into(),
+      disgust_on_reject: preferences.disgust_on_reject.unwrap_or(false),
+    })
+  }
+}
+
+/// Images waiting for approval, at most one per link.
+pub struct Approvals {
+  policy: ApprovalPolicy,
+  /// Post id and decision channel of the pending image, by link id.
+  pending: Mutex<HashMap<i64, (i64, oneshot::Sender<Decision>)>>,
+}
+
+impl Approvals {
+  pub fn new(policy: ApprovalPolicy) -> Self {
+    Approvals {
+      policy,
+      pending: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn policy(&self) -> &ApprovalPolicy {
+    &self.policy
+  }
+
+  /// Hold `post_id` of `link_id` until it is decided on. It replaces the image the link had
+  /// pending, which is then dropped.
+  pub fn request(&self, link_id: i64, post_id: i64) -> Pending {
+    let (sender, receiver) = oneshot::channel();
+    self
+      .pending
+      .lock()
+      .unwrap()
+      .insert(link_id, (post_id, sender));
+    Pending {
+      receiver,
+      timeout: self.policy.timeout,
+      on_timeout: self.policy.on_timeout,
+    }
+  }
+
+  /// Decide on the image pending for `link_id`, only if it is still `post_id` when given.
+  ///
+  /// Returns whether an image was waiting for that decision.
+  pub fn decide(&self, link_id: i64, post_id: Option<i64>, decision: Decision) -> bool {
+    let mut pending = self.pending.lock().unwrap();
+    match pending.get(&link_id) {
+      Some((pending_post, _)) if post_id.is_none_or(|id| id == *pending_post) => {}
+      _ => return false,
+    }
+    let (_, sender) = pending.remove(&link_id).unwrap();
+    sender.send(decision).is_ok()
+  }
+
+  /// Links with an image waiting for approval, in ascending order.
+  pub fn pending(&self) -> Vec<i64> {
+    let mut pending = self.pending.lock().unwrap();
+    pending.retain(|_, (_, sender)| !sender.is_closed());
+    let mut links: Vec<i64> = pending.keys().copied().collect();
+    links.sort();
+    links
+  }
+}
+
+/// An image waiting for a `Decision`.
+pub struct Pending {
+  receiver: oneshot::Receiver<Decision>,
+  timeout: Option<Duration>,
+  on_timeout: Decision,
+}
+
+impl Pending {
+  /// Wait for the decision, or the timeout policy. `None` when a newer image of the same link
+  /// replaced this one.
+  pub async fn decision(self) -> Option<Decision> {
+    match self.timeout {
+      Some(timeout) => match tokio::time::timeout(timeout, self.receiver).await {
+        Ok(decision) => decision.ok(),
+        Err(_) => {
+          println!("Nobody answered in time, taking the default decision");
+          Some(self.on_timeout)
+        }
+      },
+      None => self.receiver.await.ok(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn approvals(timeout: Option<Duration>, on_timeout: Decision) -> Approvals {
+    Approvals::new(ApprovalPolicy {
+      timeout,
+      on_timeout,
+      disgust_on_reject: false,
+    })
+  }
+
+  #[tokio::test]
+  async fn waits_for_a_decision_on_the_current_post() {
+    let approvals = approvals(None, Decision::Reject);
+    let pending = approvals.request(1, 10);
+    assert_eq!(approvals.pending(), [1]);
+
+    // A stale notification can't decide on the newer image.
+    assert!(!approvals.decide(1, Some(9), Decision::Approve));
+    assert!(!approvals.decide(2, None, Decision::Approve));
+    assert!(approvals.decide(1, Some(10), Decision::Approve));
+    assert_eq!(pending.decision().await, Some(Decision::Approve));
+    assert!(approvals.pending().is_empty());
+  }
+
+  #[tokio::test]
+  async fn newer_posts_replace_pending_ones() {
+    let approvals = approvals(None, Decision::Reject);
+    let first = approvals.request(1, 10);
+    let second = approvals.request(1, 11);
+    assert_eq!(first.decision().await, None);
+
+    assert!(approvals.decide(1, None, Decision::Reject));
+    assert_eq!(second.decision().await, Some(Decision::Reject));
+  }
+
+  #[tokio::test]
+  async fn applies_the_timeout_policy() {
+    let soon = Some(Duration::from_millis(10));
+    let approvals = approvals(soon, Decision::Approve);
+    assert_eq!(
+      approvals.request(1, 10).decision().await,
+      Some(Decision::Approve)
+    );
+    // The image is gone, deciding afterwards has no effect.
+    assert!(!approvals.decide(1, None, Decision::Reject));
+    assert!(approvals.pending().is_empty());
+
+    let approvals = self::approvals(soon, Decision::Reject);
+    assert_eq!(
+      approvals.request(1, 10).decision().await,
+      Some(Decision::Reject)
+    );
+  }
+
+  #[test]
+  fn reads_the_policy_from_preferences() {
+    let preferences = |toml: &str| {
+      let config = format!("[Base]\n[Feed]\n[Preferences]\n{toml}");
+      crate::model::config::Config::from_str(&config)
+        .unwrap()
+        .preferences
+    };
+    assert_eq!(ApprovalPolicy::from_preferences(&preferences("")), None);
+    assert_eq!(
+      ApprovalPolicy::from_preferences(&preferences(
+        "requireApproval = true\napprovalTimeout = 5\napprovalTimeoutAction = \"apply\""
+      )),
+      Some(ApprovalPolicy {
+        timeout: Some(Duration::from_secs(300)),
+        on_timeout: Decision::Approve,
+        disgust_on_reject: false,
+      })
+    );
+  }
+}
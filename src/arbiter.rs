@@ -0,0 +1,376 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api::{DesktopApi, DesktopApiError, WallpaperInfo};
+use crate::model::config::MergePolicy;
+
+/// Decides which of the watched links gets to show its post on the desktop.
+///
+/// Every watcher hands its freshly downloaded image to the arbiter, which applies it
+/// (or not) according to the configured `MergePolicy`. Links pinned to an output skip the
+/// policy and always update their own monitor.
+pub struct Arbiter {
+  desktop: Arc<dyn DesktopApi>,
+  policy: MergePolicy,
+  /// Link ids in priority order, highest first.
+  links: Vec<i64>,
+  /// Output each pinned link is shown on.
+  outputs: HashMap<i64, String>,
+  state: Mutex<ArbiterState>,
+}
+
+#[derive(Default)]
+struct ArbiterState {
+  /// Latest image received for each link.
+  latest: HashMap<i64, (PathBuf, WallpaperInfo)>,
+  /// Link whose image is currently on the desktop.
+  shown: Option<i64>,
+  /// Pinned links whose output cannot be set here, sharing the desktop instead.
+  unpinned: HashSet<i64>,
+}
+
+impl Arbiter {
+  pub fn new(desktop: Arc<dyn DesktopApi>, policy: MergePolicy, links: Vec<i64>) -> Self {
+    Arbiter {
+      desktop,
+      policy,
+      links,
+      outputs: HashMap::new(),
+      state: Mutex::new(ArbiterState::default()),
+    }
+  }
+
+  /// Pin links to outputs, by output name.
+  ///
+  /// Pinned links keep their place in `links`, so one whose output cannot be set falls back
+  /// to the shared desktop with its configured priority.
+  pub fn with_outputs(mut self, outputs: HashMap<i64, String>) -> Self {
+    self.outputs = outputs;
+    self
+  }
+
+  pub fn policy(&self) -> MergePolicy {
+    self.policy
+  }
+
+  /// Link whose image is on the shared desktop.
+  pub fn shown(&self) -> Option<i64> {
+    self.state.lock().unwrap().shown
+  }
+
+  /// Latest image of every link, on the desktop or waiting for its turn.
+  pub fn images(&self) -> Vec<PathBuf> {
+    let state = self.state.lock().unwrap();
+    state
+      .latest
+      .values()
+      .flat_map(|(image, info)| std::iter::once(image).chain(&info.animated))
+      .cloned()
+      .collect()
+  }
+
+  /// Record a new image for `link_id` and apply it if the policy lets it win.
+  pub fn submit(&self, link_id: i64, image: &Path, info: &WallpaperInfo) {
+    let latest = (image.to_path_buf(), info.clone());
+    self.state.lock().unwrap().latest.insert(link_id, latest);
+
+    if let Some(output) = self.outputs.get(&link_id) {
+      match self.desktop.apply_wallpaper(Some(output), image, info) {
+        Ok(()) => return,
+        Err(DesktopApiError::Unsupported) => {
+          if self.state.lock().unwrap().unpinned.insert(link_id) {
+            eprintln!(
+              "Per-output wallpapers are not supported here, link {link_id} shares the desktop instead"
+            );
+          }
+        }
+        Err(e) => {
+          eprintln!("Failed to change background of {output}: {e}");
+          return;
+        }
+      }
+    }
+
+    let winner = {
+      let mut state = self.state.lock().unwrap();
+      let wins = match self.policy {
+        MergePolicy::LatestWins => true,
+        MergePolicy::Priority => state
+          .shown
+          .is_none_or(|shown| self.rank(link_id) <= self.rank(shown)),
+        // Rotation picks it up on its next turn, unless nothing is shown yet
+        // or the link's own slot is on screen.
+        MergePolicy::RoundRobin => state.shown.is_none_or(|shown| shown == link_id),
+      };
+
+      if wins {
+        state.shown = Some(link_id);
+        Some(state.latest[&link_id].clone())
+      } else {
+        None
+      }
+    };
+
+    match winner {
+      Some((path, info)) => self.apply(&path, &info),
+      None => println!(
+        "Keeping current background, link {link_id} does not win under {:?}",
+        self.policy
+      ),
+    }
+  }
+
+  /// Show the next link that has an image, in configured order.
+  ///
+  /// Only meaningful for `MergePolicy::RoundRobin`.
+  pub fn rotate(&self) {
+    let next = {
+      let mut state = self.state.lock().unwrap();
+      let start = state.shown.map(|shown| self.rank(shown) + 1).unwrap_or(0);
+      let next = (0..self.links.len())
+        .map(|offset| self.links[(start + offset) % self.links.len()])
+        .find(|link| state.latest.contains_key(link) && !self.is_pinned(&state, *link));
+
+      next.map(|link| {
+        state.shown = Some(link);
+        state.latest[&link].clone()
+      })
+    };
+
+    if let Some((path, info)) = next {
+      self.apply(&path, &info);
+    }
+  }
+
+  /// Rotate the desktop every `period`, forever.
+  pub async fn run_rotation(self: Arc<Self>, period: Duration) {
+    let mut ticker = tokio::time::interval(period);
+    // The first tick completes immediately, skip it so the first image stays up a full period.
+    ticker.tick().await;
+    loop {
+      ticker.tick().await;
+      self.rotate();
+    }
+  }
+
+  /// Whether `link_id` is shown on its own output rather than the shared desktop.
+  fn is_pinned(&self, state: &ArbiterState, link_id: i64) -> bool {
+    self.outputs.contains_key(&link_id) && !state.unpinned.contains(&link_id)
+  }
+
+  fn rank(&self, link_id: i64) -> usize {
+    self
+      .links
+      .iter()
+      .position(|l| *l == link_id)
+      .unwrap_or(self.links.len())
+  }
+
+  fn apply(&self, image: &Path, info: &WallpaperInfo) {
+    if let Err(e) = self.desktop.apply_wallpaper(None, image, info) {
+      eprintln!("Failed to change background: {e}");
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+  use super::*;
+  use crate::api::{DesktopCapabilities, Notification};
+
+  /// Records every wallpaper applied instead of touching the desktop.
+  #[derive(Default)]
+  pub struct RecordingDesktop {
+    /// Output (`None` for the whole desktop), image and whether it came with an animation.
+    pub applied: Mutex<Vec<(Option<String>, PathBuf, bool)>>,
+    /// Fail per-output changes with `Unsupported`.
+    pub single_output: bool,
+  }
+
+  impl RecordingDesktop {
+    /// Images applied to the whole desktop.
+    pub fn shared(&self) -> Vec<PathBuf> {
+      let applied = self.applied.lock().unwrap();
+      applied
+        .iter()
+        .filter(|(output, _, _)| output.is_none())
+        .map(|(_, image, _)| image.clone())
+        .collect()
+    }
+
+    /// Images applied to a single output, with its name.
+    pub fn per_output(&self) -> Vec<(String, PathBuf)> {
+      let applied = self.applied.lock().unwrap();
+      applied
+        .iter()
+        .filter_map(|(output, image, _)| Some((output.clone()?, image.clone())))
+        .collect()
+    }
+  }
+
+  impl DesktopApi for RecordingDesktop {
+    fn change_background(&self, _image: &Path) -> Result<(), DesktopApiError> {
+      Ok(())
+    }
+
+    fn apply_wallpaper(
+      &self,
+      output: Option<&str>,
+      image: &Path,
+      info: &WallpaperInfo,
+    ) -> Result<(), DesktopApiError> {
+      if output.is_some() && self.single_output {
+        return Err(DesktopApiError::Unsupported);
+      }
+      let applied = (
+        output.map(str::to_string),
+        image.to_path_buf(),
+        info.animated.is_some(),
+      );
+      self.applied.lock().unwrap().push(applied);
+      Ok(())
+    }
+
+    fn capabilities(&self) -> DesktopCapabilities {
+      DesktopCapabilities {
+        notifications: false,
+        actions: false,
+        set_wallpaper: true,
+        raw_icon_bytes: false,
+        open_file: false,
+        per_output_wallpaper: !self.single_output,
+        animated_wallpaper: true,
+      }
+    }
+
+    fn send_notification(&self, _notification: &Notification) -> Result<(), DesktopApiError> {
+      Ok(())
+    }
+
+    fn open_file(&self, _file: &Path) -> Result<(), DesktopApiError> {
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::test_support::RecordingDesktop;
+  use super::*;
+
+  fn arbiter(policy: MergePolicy) -> (Arc<RecordingDesktop>, Arbiter) {
+    let desktop = Arc::new(RecordingDesktop::default());
+    let arbiter = Arbiter::new(desktop.clone(), policy, vec![1, 2, 3]);
+    (desktop, arbiter)
+  }
+
+  fn applied(desktop: &RecordingDesktop) -> Vec<PathBuf> {
+    desktop.shared()
+  }
+
+  #[test]
+  fn latest_wins_applies_everything() {
+    let (desktop, arbiter) = arbiter(MergePolicy::LatestWins);
+    arbiter.submit(1, Path::new("a"), &WallpaperInfo::default());
+    arbiter.submit(3, Path::new("b"), &WallpaperInfo::default());
+    arbiter.submit(2, Path::new("c"), &WallpaperInfo::default());
+    assert_eq!(applied(&desktop), ["a", "b", "c"].map(PathBuf::from));
+  }
+
+  #[test]
+  fn priority_ignores_lower_links_once_a_higher_one_is_shown() {
+    let (desktop, arbiter) = arbiter(MergePolicy::Priority);
+    arbiter.submit(2, Path::new("a"), &WallpaperInfo::default());
+    arbiter.submit(3, Path::new("b"), &WallpaperInfo::default());
+    arbiter.submit(1, Path::new("c"), &WallpaperInfo::default());
+    arbiter.submit(2, Path::new("d"), &WallpaperInfo::default());
+    arbiter.submit(1, Path::new("e"), &WallpaperInfo::default());
+    assert_eq!(applied(&desktop), ["a", "c", "e"].map(PathBuf::from));
+    assert_eq!(arbiter.shown(), Some(1));
+  }
+
+  #[test]
+  fn round_robin_cycles_through_links_with_images() {
+    let (desktop, arbiter) = arbiter(MergePolicy::RoundRobin);
+    arbiter.submit(1, Path::new("a"), &WallpaperInfo::default());
+    arbiter.submit(3, Path::new("b"), &WallpaperInfo::default());
+    arbiter.rotate();
+    arbiter.rotate();
+    arbiter.submit(2, Path::new("c"), &WallpaperInfo::default());
+    arbiter.rotate();
+    arbiter.rotate();
+    assert_eq!(
+      applied(&desktop),
+      ["a", "b", "a", "c", "b"].map(PathBuf::from)
+    );
+  }
+
+  #[test]
+  fn pinned_links_go_to_their_output() {
+    let desktop = Arc::new(RecordingDesktop::default());
+    let arbiter = Arbiter::new(desktop.clone(), MergePolicy::Priority, vec![1, 2])
+      .with_outputs(HashMap::from([(2, "HDMI-1".to_string())]));
+
+    arbiter.submit(1, Path::new("a"), &WallpaperInfo::default());
+    arbiter.submit(2, Path::new("b"), &WallpaperInfo::default());
+
+    assert_eq!(applied(&desktop), [PathBuf::from("a")]);
+    assert_eq!(
+      desktop.per_output(),
+      [("HDMI-1".to_string(), PathBuf::from("b"))]
+    );
+
+    // Both images stay in use, even though only one is on the shared desktop.
+    let mut images = arbiter.images();
+    images.sort();
+    assert_eq!(images, [PathBuf::from("a"), PathBuf::from("b")]);
+
+    // The pinned link stays out of the shared rotation.
+    let arbiter = Arbiter::new(desktop.clone(), MergePolicy::RoundRobin, vec![1, 2])
+      .with_outputs(HashMap::from([(2, "HDMI-1".to_string())]));
+    arbiter.submit(1, Path::new("c"), &WallpaperInfo::default());
+    arbiter.submit(2, Path::new("d"), &WallpaperInfo::default());
+    arbiter.rotate();
+    assert_eq!(applied(&desktop), ["a", "c", "c"].map(PathBuf::from));
+  }
+
+  #[test]
+  fn unsupported_pins_share_the_desktop() {
+    let desktop = Arc::new(RecordingDesktop {
+      single_output: true,
+      ..Default::default()
+    });
+    let arbiter = Arbiter::new(desktop.clone(), MergePolicy::RoundRobin, vec![1, 2, 3])
+      .with_outputs(HashMap::from([(2, "HDMI-1".to_string())]));
+
+    arbiter.submit(1, Path::new("a"), &WallpaperInfo::default());
+    arbiter.submit(2, Path::new("b"), &WallpaperInfo::default());
+    arbiter.submit(3, Path::new("c"), &WallpaperInfo::default());
+    arbiter.rotate();
+    arbiter.rotate();
+    arbiter.rotate();
+
+    // The fallen-back link takes its configured turn in the rotation.
+    assert_eq!(applied(&desktop), ["a", "b", "c", "a"].map(PathBuf::from));
+  }
+}
@@ -0,0 +1,390 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! On-disk cache of post images, keyed by the md5 e621 puts in every post URL.
+//!
+//! Every file derived from a post (still frames, thumbnails, resized copies) is named
+//! `<key>.<something>`, so a post and everything made from it are evicted together.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::media::MediaKind;
+use crate::model::config::Preferences;
+
+/// Size budget of the temporary cache when `cacheMaxBytes` is not set.
+pub const DEFAULT_MAX_BYTES: u64 = 512 * 1024 * 1024;
+/// Age budget of the temporary cache when `cacheMaxAge` is not set.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The md5 of a post, from URLs such as `https://static1.e621.net/data/5d/87/<md5>.png`.
+pub fn post_md5(url: &str) -> Option<String> {
+  let url = url::Url::parse(url).ok()?;
+  let file = url.path_segments()?.next_back()?;
+  let stem = file.split('.').next()?;
+  (stem.len() == 32 && stem.bytes().all(|b| b.is_ascii_hexdigit())).then(|| stem.to_lowercase())
+}
+
+/// The post key of a cached file or of anything derived from it.
+pub fn key_of(path: &Path) -> Option<&str> {
+  path.file_name()?.to_str()?.split('.').next()
+}
+
+/// Whether `key` is a post md5, that is the file is one loxerpaper created.
+fn is_post_key(key: &str) -> bool {
+  key.len() == 32 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The post each link holds on to, whether shown, waiting for approval or hidden behind a
+/// censored preview, and the posts still being downloaded or prepared. Shared by the
+/// watchers so none evicts another's post.
+#[derive(Debug, Default)]
+pub struct InUse {
+  keys: Mutex<HashMap<i64, String>>,
+  /// Posts in flight, with how many watchers are working on each.
+  reserved: Mutex<HashMap<String, usize>>,
+}
+
+impl InUse {
+  /// Record `key` as the post `link_id` now holds, releasing the previous one.
+  pub fn hold(&self, link_id: i64, key: &str) {
+    self.keys.lock().unwrap().insert(link_id, key.to_string());
+  }
+
+  /// Protect `key` while it is downloaded and prepared, until the reservation is dropped.
+  pub fn reserve(&self, key: &str) -> Reservation<'_> {
+    *self
+      .reserved
+      .lock()
+      .unwrap()
+      .entry(key.to_string())
+      .or_default() += 1;
+    Reservation {
+      in_use: self,
+      key: key.to_string(),
+    }
+  }
+
+  pub fn keys(&self) -> HashSet<String> {
+    let mut keys: HashSet<String> = self.keys.lock().unwrap().values().cloned().collect();
+    keys.extend(self.reserved.lock().unwrap().keys().cloned());
+    keys
+  }
+}
+
+/// A post in flight, see `InUse::reserve`.
+#[derive(Debug)]
+pub struct Reservation<'a> {
+  in_use: &'a InUse,
+  key: String,
+}
+
+impl Drop for Reservation<'_> {
+  fn drop(&mut self) {
+    let mut reserved = self.in_use.reserved.lock().unwrap();
+    if let Some(count) = reserved.get_mut(&self.key) {
+      *count -= 1;
+      if *count == 0 {
+        reserved.remove(&self.key);
+      }
+    }
+  }
+}
+
+/// Directory of post images with a size and age budget, evicted least recently used first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageCache {
+  dir: PathBuf,
+  max_bytes: Option<u64>,
+  max_age: Option<Duration>,
+}
+
+impl ImageCache {
+  pub fn new(dir: PathBuf, max_bytes: Option<u64>, max_age: Option<Duration>) -> Self {
+    ImageCache {
+      dir,
+      max_bytes,
+      max_age,
+    }
+  }
+
+  /// With `saveLocally` images are kept in `~/Pictures/WallTaker` and only evicted when a
+  /// budget is set explicitly, otherwise they go to the user cache directory with the
+  /// default budget.
+  pub fn from_preferences(preferences: &Preferences) -> Self {
+    let max_age = preferences
+      .cache_max_age
+      .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    if preferences.save_locally.unwrap_or(false) {
+      let dir = dirs_next::picture_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("WallTaker");
+      ImageCache::new(dir, preferences.cache_max_bytes, max_age)
+    } else {
+      let dir = dirs_next::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("loxerpaper")
+        .join("images");
+      ImageCache::new(
+        dir,
+        Some(preferences.cache_max_bytes.unwrap_or(DEFAULT_MAX_BYTES)),
+        Some(max_age.unwrap_or(DEFAULT_MAX_AGE)),
+      )
+    }
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Where the post `key` is downloaded to before its real format is known.
+  pub fn path_for(&self, key: &str, extension: &str) -> PathBuf {
+    self.dir.join(format!("{key}.{extension}"))
+  }
+
+  /// The downloaded post `key`, if it is cached. Marks it as used.
+  pub fn lookup(&self, key: &str) -> Option<(MediaKind, PathBuf)> {
+    [
+      MediaKind::Jpeg,
+      MediaKind::Png,
+      MediaKind::Webp,
+      MediaKind::Gif,
+      MediaKind::Webm,
+      MediaKind::Mp4,
+    ]
+    .into_iter()
+    .map(|kind| (kind, self.path_for(key, kind.extension())))
+    .find(|(_, path)| path.is_file())
+    .inspect(|(_, path)| touch(path))
+  }
+
+  /// Remove posts over the budget, oldest use first, never touching those in `keep`.
+  ///
+  /// Only files named after a post md5 are considered, anything else in the directory (such
+  /// as the user's own pictures in `~/Pictures/WallTaker`) is left alone and not counted.
+  /// Returns how many files were removed.
+  pub fn evict(&self, keep: &HashSet<String>) -> io::Result<usize> {
+    if self.max_bytes.is_none() && self.max_age.is_none() {
+      return Ok(0);
+    }
+
+    // Group files by post, a post was last used when any of its files was.
+    let mut posts: HashMap<String, (SystemTime, u64, Vec<PathBuf>)> = HashMap::new();
+    for entry in fs::read_dir(&self.dir)? {
+      let entry = entry?;
+      let path = entry.path();
+      let Some(key) = key_of(&path).filter(|key| is_post_key(key)) else {
+        continue;
+      };
+      // Another watcher may be evicting the same directory.
+      let metadata = match entry.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => continue,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e),
+      };
+      let post = posts
+        .entry(key.to_string())
+        .or_insert((SystemTime::UNIX_EPOCH, 0, Vec::new()));
+      post.0 = post.0.max(metadata.modified()?);
+      post.1 += metadata.len();
+      post.2.push(path);
+    }
+
+    let mut total: u64 = posts.values().map(|(_, size, _)| size).sum();
+    let mut posts: Vec<_> = posts
+      .into_iter()
+      .filter(|(key, _)| !keep.contains(key))
+      .collect();
+    posts.sort_by_key(|(_, (used, _, _))| *used);
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for (_, (used, size, files)) in posts {
+      let expired = self
+        .max_age
+        .is_some_and(|max_age| now.duration_since(used).unwrap_or_default() > max_age);
+      let over_budget = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+      if !expired && !over_budget {
+        continue;
+      }
+      for file in files {
+        match fs::remove_file(&file) {
+          Ok(()) => removed += 1,
+          Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+          Err(e) => eprintln!("Failed to evict {file:?}: {e}"),
+        }
+      }
+      total -= size;
+    }
+    Ok(removed)
+  }
+}
+
+/// Mark `path` as just used. The modification time doubles as the last use.
+pub fn touch(path: &Path) {
+  if let Ok(file) = File::options().append(true).open(path) {
+    let _ = file.set_modified(SystemTime::now());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MD5: &str = "5d87428c4839b0dc7d585b87a25af61a";
+
+  /// A post key made of `digit` only.
+  fn key(digit: char) -> String {
+    digit.to_string().repeat(32)
+  }
+
+  /// Write `size` bytes to `name` in `dir`, last used `age` ago.
+  fn file(dir: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+    let path = dir.join(name);
+    fs::write(&path, vec![0; size]).unwrap();
+    File::options()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .set_modified(SystemTime::now() - age)
+      .unwrap();
+    path
+  }
+
+  #[test]
+  fn extracts_the_md5_from_post_urls() {
+    assert_eq!(
+      post_md5(&format!("https://static1.e621.net/data/5d/87/{MD5}.png")).as_deref(),
+      Some(MD5)
+    );
+    assert_eq!(
+      post_md5(&format!(
+        "https://static1.e621.net/data/preview/5d/87/{}.jpg",
+        MD5.to_uppercase()
+      ))
+      .as_deref(),
+      Some(MD5)
+    );
+    assert_eq!(
+      post_md5("https://static1.e621.net/data/5d/87/cat.png"),
+      None
+    );
+    assert_eq!(post_md5("not a url"), None);
+  }
+
+  #[test]
+  fn finds_cached_posts_by_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ImageCache::new(dir.path().to_path_buf(), None, None);
+    assert_eq!(cache.lookup(MD5), None);
+
+    let hour = Duration::from_secs(3600);
+    let gif = file(dir.path(), &format!("{MD5}.gif"), 4, hour);
+    file(dir.path(), &format!("{MD5}.gif.part"), 4, hour);
+    assert_eq!(cache.lookup(MD5), Some((MediaKind::Gif, gif.clone())));
+
+    // Looking a post up counts as using it.
+    let used = fs::metadata(&gif).unwrap().modified().unwrap();
+    assert!(SystemTime::now().duration_since(used).unwrap() < hour);
+  }
+
+  #[test]
+  fn evicts_least_recently_used_posts_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    let (old, older, oldest, current, pending) = (key('a'), key('b'), key('c'), key('d'), key('e'));
+    file(dir.path(), &format!("{old}.png"), 100, minutes(30));
+    file(
+      dir.path(),
+      &format!("{old}.fit-1920x1080.png"),
+      100,
+      minutes(1),
+    );
+    file(dir.path(), &format!("{older}.png"), 100, minutes(20));
+    file(dir.path(), &format!("{oldest}.png"), 100, minutes(40));
+    file(dir.path(), &format!("{current}.png"), 100, minutes(50));
+    file(dir.path(), &format!("{pending}.png"), 100, minutes(60));
+    // The user's own picture is neither counted nor removed.
+    file(dir.path(), "holiday.png", 1000, minutes(90));
+
+    // 600 bytes against a 400 byte budget: the oldest post goes first, then `older`, whose
+    // last use is earlier than `old`'s resized copy. `current` and `pending` are protected.
+    let cache = ImageCache::new(dir.path().to_path_buf(), Some(400), None);
+    let in_use = InUse::default();
+    in_use.hold(1, &current);
+    in_use.hold(2, &pending);
+    assert_eq!(cache.evict(&in_use.keys()).unwrap(), 2);
+
+    let mut left: Vec<_> = fs::read_dir(dir.path())
+      .unwrap()
+      .map(|e| e.unwrap().file_name().into_string().unwrap())
+      .collect();
+    left.sort();
+    let mut expected = vec![
+      format!("{old}.fit-1920x1080.png"),
+      format!("{old}.png"),
+      format!("{current}.png"),
+      format!("{pending}.png"),
+      "holiday.png".to_string(),
+    ];
+    expected.sort();
+    assert_eq!(left, expected);
+  }
+
+  #[test]
+  fn reservations_protect_posts_in_flight() {
+    let in_use = InUse::default();
+    let (shown, downloading) = (key('a'), key('b'));
+    in_use.hold(1, &shown);
+
+    let first = in_use.reserve(&downloading);
+    let second = in_use.reserve(&downloading);
+    assert_eq!(
+      in_use.keys(),
+      HashSet::from([shown.clone(), downloading.clone()])
+    );
+    drop(first);
+    assert!(in_use.keys().contains(&downloading));
+    drop(second);
+    assert_eq!(in_use.keys(), HashSet::from([shown]));
+  }
+
+  #[test]
+  fn evicts_expired_posts() {
+    let dir = tempfile::tempdir().unwrap();
+    let day = Duration::from_secs(24 * 60 * 60);
+    let (stale, fresh) = (format!("{}.png", key('a')), format!("{}.png", key('b')));
+    file(dir.path(), &stale, 10, day * 3);
+    file(dir.path(), &fresh, 10, Duration::ZERO);
+    file(dir.path(), "wallpaper.png", 10, day * 3);
+
+    let cache = ImageCache::new(dir.path().to_path_buf(), None, Some(day));
+    assert_eq!(cache.evict(&HashSet::new()).unwrap(), 1);
+    assert!(dir.path().join(&fresh).exists());
+    assert!(dir.path().join("wallpaper.png").exists());
+
+    let unbounded = ImageCache::new(dir.path().to_path_buf(), None, None);
+    file(dir.path(), &stale, 10, day * 3);
+    assert_eq!(unbounded.evict(&HashSet::new()).unwrap(), 0);
+  }
+}
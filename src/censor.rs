@@ -0,0 +1,143 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Censored previews: new wallpapers go on the desktop pixelated or blurred, and the real
+//! image only replaces them once revealed from the notification or with `reveal`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::api::WallpaperInfo;
+use crate::arbiter::Arbiter;
+use crate::model::config::CensorMode;
+
+/// Images shown censored, waiting to be revealed.
+pub struct Reveals {
+  arbiter: Arc<Arbiter>,
+  mode: CensorMode,
+  /// Post id, real image and its info, by link id.
+  hidden: Mutex<HashMap<i64, (i64, PathBuf, WallpaperInfo)>>,
+}
+
+impl Reveals {
+  pub fn new(arbiter: Arc<Arbiter>, mode: CensorMode) -> Self {
+    Reveals {
+      arbiter,
+      mode,
+      hidden: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn mode(&self) -> CensorMode {
+    self.mode
+  }
+
+  /// Hand the censored `preview` of `post_id` to the arbiter, keeping `image` until revealed.
+  pub fn show(
+    &self,
+    link_id: i64,
+    post_id: i64,
+    preview: &Path,
+    image: PathBuf,
+    info: WallpaperInfo,
+  ) {
+    // The animated original would give the game away.
+    let preview_info = WallpaperInfo {
+      animated: None,
+      ..info.clone()
+    };
+    self
+      .hidden
+      .lock()
+      .unwrap()
+      .insert(link_id, (post_id, image, info));
+    self.arbiter.submit(link_id, preview, &preview_info);
+  }
+
+  /// Swap the real image of `link_id` in, only if it is still `post_id` when given.
+  ///
+  /// Returns whether there was an image to reveal.
+  pub fn reveal(&self, link_id: i64, post_id: Option<i64>) -> bool {
+    let hidden = {
+      let mut hidden = self.hidden.lock().unwrap();
+      match hidden.get(&link_id) {
+        Some((hidden_post, _, _)) if post_id.is_none_or(|id| id == *hidden_post) => {
+          hidden.remove(&link_id)
+        }
+        _ => None,
+      }
+    };
+    let Some((_, image, info)) = hidden else {
+      return false;
+    };
+    self.arbiter.submit(link_id, &image, &info);
+    true
+  }
+
+  /// Links showing a censored image, in ascending order.
+  pub fn hidden(&self) -> Vec<i64> {
+    let mut links: Vec<i64> = self.hidden.lock().unwrap().keys().copied().collect();
+    links.sort();
+    links
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::arbiter::test_support::RecordingDesktop;
+  use crate::model::config::MergePolicy;
+
+  #[test]
+  fn shows_the_preview_until_revealed() {
+    let desktop = Arc::new(RecordingDesktop::default());
+    let arbiter = Arc::new(Arbiter::new(
+      desktop.clone(),
+      MergePolicy::LatestWins,
+      vec![1],
+    ));
+    let reveals = Reveals::new(arbiter, CensorMode::Pixelate);
+    let info = WallpaperInfo {
+      link_id: Some(1),
+      set_by: None,
+      animated: Some(PathBuf::from("post.gif")),
+    };
+
+    reveals.show(
+      1,
+      42,
+      Path::new("post.pixelated.png"),
+      "post.png".into(),
+      info,
+    );
+    assert_eq!(reveals.hidden(), [1]);
+    assert!(!reveals.reveal(1, Some(41)));
+    assert!(reveals.reveal(1, Some(42)));
+    assert!(!reveals.reveal(1, None));
+    assert!(reveals.hidden().is_empty());
+
+    assert_eq!(
+      *desktop.applied.lock().unwrap(),
+      [
+        (None, PathBuf::from("post.pixelated.png"), false),
+        (None, PathBuf::from("post.png"), true),
+      ]
+    );
+  }
+}
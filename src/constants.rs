@@ -57,6 +57,19 @@ pub fn user_url_opt(username: impl ToString, api_key: Option<impl ToString>) ->
   }
 }
 
+/// ActionCable endpoint pushing link updates as they happen.
+pub const CABLE_URL: &str = "wss://walltaker.joi.how/cable";
+
+/// Origin sent with the websocket handshake, ActionCable rejects unknown origins.
+pub const CABLE_ORIGIN: &str = "https://walltaker.joi.how";
+
+/// User-Agent sent with every HTTP request.
+pub const USER_AGENT: &str = concat!(
+  "loxerpaper/",
+  env!("CARGO_PKG_VERSION"),
+  " (+https://github.com/cliftontoaster-reid/loxerpaper)"
+);
+
 pub const DISCORD_CLIENT_ID: &str = "123456789012345678";
 
 #[cfg(test)]
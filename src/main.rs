@@ -16,25 +16,21 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-mod api;
-mod constants;
-mod model;
 use std::{
   fs,
-  io::{self, BufRead, BufReader, Write as _},
+  io::{self, BufRead, BufReader},
   sync::Arc,
   thread,
 };
 
-use model::config::Config;
-
-use crate::api::{ApiClient, DesktopApi, create_desktop_api, spawn_review_notification};
-
-#[cfg(target_os = "linux")]
-use crate::api::GnomeDesktopApi;
-
-#[cfg(windows)]
-use crate::api::WindowsDesktopApi;
+use loxerpaper::api::{ApiClient, DesktopApi, create_desktop_api};
+use loxerpaper::approval::{ApprovalPolicy, Approvals, Decision};
+use loxerpaper::arbiter::Arbiter;
+use loxerpaper::cache::InUse;
+use loxerpaper::censor::Reveals;
+use loxerpaper::model::config::{Config, MergePolicy};
+use loxerpaper::model::link::ResponseType;
+use loxerpaper::watcher::LinkWatcher;
 
 fn print_gpl_notice() {
   println!("loxerpaper  Copyright (C) 2025  Clifton Toaster Reid");
@@ -73,7 +69,115 @@ fn show_conditions() {
   println!();
 }
 
-fn handle_stdin_commands() {
+/// `respond <horny|disgust|came> [text]`: react to the post on screen.
+///
+/// `respond@<link>` reacts to the post of that link instead, which is needed when nothing is
+/// on the shared desktop yet and several links are configured.
+fn respond(
+  client: &ApiClient,
+  arbiter: &Arbiter,
+  runtime: &tokio::runtime::Handle,
+  link: Option<&str>,
+  args: &str,
+) {
+  let (kind, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+  if kind.is_empty() {
+    println!("Usage: respond[@link] <horny|disgust|came> [text]");
+    return;
+  }
+  let response_type = match kind.to_lowercase().parse::<ResponseType>() {
+    Ok(response_type) => response_type,
+    Err(e) => {
+      println!("{e}");
+      return;
+    }
+  };
+  let link = match link {
+    Some(link) => match link.parse::<i64>() {
+      Ok(link) => link,
+      Err(_) => {
+        println!("'{link}' is not a link id");
+        return;
+      }
+    },
+    None => match arbiter.shown() {
+      Some(link) => link,
+      None => match pick_link("", client.config.feed.links(), "on screen") {
+        Some(link) => link,
+        None => return,
+      },
+    },
+  };
+
+  match runtime.block_on(client.react(link, response_type, text.trim())) {
+    Ok(_) => println!("Sent {} to link {link}", kind.to_lowercase()),
+    Err(e) => eprintln!("Failed to respond: {e}"),
+  }
+}
+
+/// The link named in `args`, or the only one in `waiting` when `args` is empty.
+fn pick_link(args: &str, waiting: Vec<i64>, state: &str) -> Option<i64> {
+  if !args.is_empty() {
+    let link = args.parse::<i64>();
+    if link.is_err() {
+      println!("'{args}' is not a link id");
+    }
+    return link.ok();
+  }
+  match waiting.as_slice() {
+    [] => println!("No image is {state}"),
+    [link] => return Some(*link),
+    links => println!("Several links have an image {state}, pick one of {links:?}"),
+  }
+  None
+}
+
+/// `approve [link]` and `reject [link]`: decide on an image waiting for approval.
+///
+/// The link may be left out when only one is waiting.
+fn decide(approvals: Option<&Approvals>, args: &str, decision: Decision) {
+  let Some(approvals) = approvals else {
+    println!("Approval is not required, set requireApproval in the preferences");
+    return;
+  };
+  let Some(link) = pick_link(args, approvals.pending(), "waiting for approval") else {
+    return;
+  };
+
+  if approvals.decide(link, None, decision) {
+    println!("Decided on the image of link {link}");
+  } else {
+    println!("Link {link} has no image waiting for approval");
+  }
+}
+
+/// `reveal [link]`: replace a censored preview with the real image.
+///
+/// The link may be left out when only one is shown censored.
+fn reveal(reveals: Option<&Reveals>, args: &str) {
+  let Some(reveals) = reveals else {
+    println!("Images are not censored, set censor in the preferences");
+    return;
+  };
+  let Some(link) = pick_link(args, reveals.hidden(), "shown censored") else {
+    return;
+  };
+
+  if reveals.reveal(link, None) {
+    println!("Revealed the image of link {link}");
+  } else {
+    println!("Link {link} has no censored image");
+  }
+}
+
+fn handle_stdin_commands(
+  desktop: Arc<dyn DesktopApi>,
+  client: ApiClient,
+  arbiter: Arc<Arbiter>,
+  approvals: Option<Arc<Approvals>>,
+  reveals: Option<Arc<Reveals>>,
+  runtime: tokio::runtime::Handle,
+) {
   let stdin = io::stdin();
   let reader = BufReader::new(stdin);
 
@@ -86,6 +190,10 @@ fn handle_stdin_commands() {
       }
     };
     let trimmed = input.trim().to_lowercase();
+    let (command, args) = input
+      .trim()
+      .split_once(char::is_whitespace)
+      .unwrap_or((input.trim(), ""));
     match trimmed.as_str() {
       "show w" => show_warranty(),
       "show c" => show_conditions(),
@@ -93,15 +201,43 @@ fn handle_stdin_commands() {
         println!("Available commands:");
         println!("  show w - Show warranty information");
         println!("  show c - Show license conditions");
+        println!("  respond <horny|disgust|came> [text] - React to the post on screen");
+        println!("  respond@<link> <horny|disgust|came> [text] - React to the post of a link");
+        println!("  pending - List links with an image waiting for approval");
+        println!("  approve [link] - Apply the image waiting for approval");
+        println!("  reject [link]  - Drop the image waiting for approval");
+        println!("  reveal [link]  - Show the real image instead of the censored one");
         println!("  help   - Show this help message");
         println!("  quit   - Exit the program");
         println!();
       }
       "quit" | "exit" => {
         println!("Goodbye!");
+        desktop.shutdown();
         std::process::exit(0);
       }
       "" => {} // Ignore empty lines
+      "pending" => match &approvals {
+        Some(approvals) => println!("Waiting for approval: {:?}", approvals.pending()),
+        None => println!("Approval is not required"),
+      },
+      _ if command.eq_ignore_ascii_case("approve") => {
+        decide(approvals.as_deref(), args.trim(), Decision::Approve)
+      }
+      _ if command.eq_ignore_ascii_case("reject") => {
+        decide(approvals.as_deref(), args.trim(), Decision::Reject)
+      }
+      _ if command.eq_ignore_ascii_case("reveal") => reveal(reveals.as_deref(), args.trim()),
+      _ if command.eq_ignore_ascii_case("respond") => {
+        respond(&client, &arbiter, &runtime, None, args.trim())
+      }
+      _ if command
+        .split_once('@')
+        .is_some_and(|(name, _)| name.eq_ignore_ascii_case("respond")) =>
+      {
+        let link = command.split_once('@').map(|(_, link)| link);
+        respond(&client, &arbiter, &runtime, link, args.trim())
+      }
       _ => {
         println!(
           "Unknown command: '{}'. Type 'help' for available commands.",
@@ -112,19 +248,6 @@ fn handle_stdin_commands() {
   }
 }
 
-fn hash_str(s: &str) -> i64 {
-  // Polynomial rolling hash:
-  // hash(s) = sum_{i=0..n-1} (s[i]+1) * base^{n-1-i}  (computed iteratively)
-  const MOD: i64 = 1_000_000_007;
-  const BASE: i64 = 257;
-  let mut h: i64 = 0;
-  for b in s.bytes() {
-    // use (b as i64 + 1) so that '\0' and other zeros contribute
-    h = (h.wrapping_mul(BASE) + (b as i64 + 1)) % MOD;
-  }
-  h
-}
-
 #[tokio::main]
 async fn main() {
   // Print GPL notice
@@ -139,14 +262,6 @@ async fn main() {
     std::process::exit(1);
   }
 
-  // Detect desktop environment and create appropriate DesktopApi implementation
-  let desktop: Arc<dyn DesktopApi> = create_desktop_api();
-
-  // Spawn stdin handler in background thread
-  thread::spawn(|| {
-    handle_stdin_commands();
-  });
-
   // Try to load a local `config.toml` in the cwd; fall back to defaults.
   let cfg = Config::load();
 
@@ -174,159 +289,119 @@ async fn main() {
 
   let cfg_data = cfg.unwrap();
 
+  // Detect desktop environment and create appropriate DesktopApi implementation
+  let desktop: Arc<dyn DesktopApi> = match create_desktop_api(&cfg_data.desktop) {
+    Ok(desktop) => desktop,
+    Err(e) => {
+      eprintln!("Cannot change the background here: {e}");
+      std::process::exit(1);
+    }
+  };
+
   let client = ApiClient::from_config(&cfg_data);
+  let approvals =
+    ApprovalPolicy::from_preferences(&cfg_data.preferences).map(|p| Arc::new(Approvals::new(p)));
 
   // Then the tool should loop, pinging the API for updates (link) and apply changes if a needed, sending a notification
   // and then waiting for the user defined period of time to restart the loop.
 
-  // Access preferences and feed directly (they are not Option<> in Config)
-  let should_keep = client.config.preferences.save_locally.unwrap_or(false);
-
-  // feed.feed is Option<i64>; preserve original behavior and unwrap (will panic if absent)
-  let link_id = client.config.feed.feed.unwrap();
-
-  // Determine sleep time, defaulting to 60 seconds if not set
-  let sleep_time = tokio::time::Duration::from_secs(cfg_data.preferences.interval.unwrap_or(60));
-
-  // Determine if we should send notifications
-  let send_notifications = cfg_data.preferences.notifications.unwrap_or(true);
-
-  // clone the Option<String> before unwrapping to avoid moving out of cfg_data
-  let api_key = cfg_data
-    .feed
-    .token
-    .clone()
-    .unwrap_or_else(|| "your_token".to_string());
-
-  let current_id = Arc::new(std::sync::atomic::AtomicI64::new(-1));
-
-  loop {
-    // Ping the API for updates (link)
-    let updates = client.get_link(link_id).await;
-    match updates {
-      Ok(link) => {
-        // We first check if this is a new url with the post id.
-        let post_url = link.post_url.unwrap();
-
-        // Try to parse the URL and extract the final path segment (the filename).
-        // Fallback to a safe replacement when parsing fails.
-        let filename = match url::Url::parse(&post_url).ok().and_then(|u| {
-          u.path_segments()
-            .and_then(|mut s| s.next_back().map(|s| s.to_string()))
-        }) {
-          Some(f) => f,
-          None => {
-            // Fallback: use the whole URL but sanitize characters so we don't produce
-            // a filename that contains ':' or '/'.
-            post_url.replace(
-              |c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'),
-              "_",
-            )
-          }
-        };
-
-        // Derive a stem and extension from the filename.
-        let path_like = std::path::Path::new(&filename);
-        let stem = path_like
-          .file_stem()
-          .and_then(|s| s.to_str())
-          .unwrap_or("image");
-        let ext = path_like
-          .extension()
-          .and_then(|e| e.to_str())
-          .unwrap_or("png");
-
-        // Sanitize stem to remove any unexpected characters.
-        let sanitize: String = stem
-          .chars()
-          .map(|c| {
-            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
-              c
-            } else {
-              '_'
-            }
-          })
-          .collect();
-
-        let hashed_id = hash_str(&sanitize);
-
-        if current_id.load(std::sync::atomic::Ordering::SeqCst) == hashed_id {
-          // We have the same image, we print a debug message, and return.
-          println!("No new image, current is still id {hashed_id}");
-          // Wait before next poll
-          tokio::time::sleep(sleep_time).await;
-          continue;
-        }
+  let links = client.config.feed.links();
+  if links.is_empty() {
+    eprintln!(
+      "No link configured, add one to {}",
+      Config::path().display()
+    );
+    return;
+  }
 
-        // We have a new image, we download it and set it as the background.
-        // Build the target filename from the sanitized stem and extension.
-        let target_filename = format!("{sanitize}.{ext}");
-
-        // Build the target path for the downloaded image. Don't canonicalize the full
-        // file path (the file won't exist yet) and avoid using a TempDir that is
-        // immediately dropped (which would delete the directory). Instead use the
-        // system temp directory for transient files.
-        let path = if should_keep {
-          let mut dir = dirs_next::picture_dir().unwrap_or(std::path::PathBuf::from("."));
-          dir.push("WallTaker");
-          dir.push(&target_filename);
-          dir
-        } else {
-          let mut dir = std::env::temp_dir();
-          dir.push(&target_filename);
-          dir
-        };
-
-        // Ensure the parent directory exists before creating the file.
-        let parent = path
-          .parent()
-          .map(|p| p.to_path_buf())
-          .unwrap_or(std::env::current_dir().unwrap());
-        fs::create_dir_all(&parent).unwrap();
-
-        // We now download the image using reqwest
-        let response = reqwest::get(&post_url).await;
-        match response {
-          Ok(resp) => {
-            let mut file = fs::File::create(&path).unwrap();
-            let content = resp.bytes().await.unwrap();
-            file.write_all(&content).unwrap();
-          }
-          Err(e) => {
-            eprintln!("Failed to download image: {e}");
-            // Wait before next poll
-            tokio::time::sleep(sleep_time).await;
-            continue;
-          }
-        }
+  for warning in cfg_data.feed.output_warnings() {
+    eprintln!("{warning}");
+  }
+  let policy = cfg_data.preferences.policy.unwrap_or_default();
+  let arbiter = Arc::new(
+    Arbiter::new(desktop.clone(), policy, links.clone())
+      .with_outputs(cfg_data.feed.pinned_outputs()),
+  );
+  let reveals = cfg_data
+    .preferences
+    .censor
+    .map(|mode| Arc::new(Reveals::new(arbiter.clone(), mode)));
+
+  // Spawn stdin handler in background thread, once the setup wizard no longer needs stdin
+  let stdin_desktop = desktop.clone();
+  let stdin_client = client.clone();
+  let stdin_arbiter = arbiter.clone();
+  let stdin_approvals = approvals.clone();
+  let stdin_reveals = reveals.clone();
+  let runtime = tokio::runtime::Handle::current();
+  thread::spawn(move || {
+    handle_stdin_commands(
+      stdin_desktop,
+      stdin_client,
+      stdin_arbiter,
+      stdin_approvals,
+      stdin_reveals,
+      runtime,
+    );
+  });
 
-        // We now send the notification and edit the current ID
-        current_id.store(hashed_id, std::sync::atomic::Ordering::SeqCst);
-        if send_notifications {
-          spawn_review_notification(
-            &client,
-            desktop.clone(),
-            current_id.clone(),
-            link_id,
-            hashed_id,
-            link.set_by.unwrap_or("unknown".to_string()),
-            api_key.clone(),
-            path.clone(),
-          );
-        }
+  // One watcher per link, they all report to the same arbiter.
+  let mut tasks = Vec::new();
+  let in_use = Arc::new(InUse::default());
+  for link_id in links {
+    let mut watcher = LinkWatcher::new(client.clone(), desktop.clone(), arbiter.clone(), link_id)
+      .with_in_use(in_use.clone());
+    if let Some(approvals) = &approvals {
+      watcher = watcher.with_approvals(approvals.clone());
+    }
+    if let Some(reveals) = &reveals {
+      watcher = watcher.with_reveals(reveals.clone());
+    }
+    tasks.push(tokio::spawn(async move { watcher.run().await }));
+  }
+
+  if policy == MergePolicy::RoundRobin {
+    let period = tokio::time::Duration::from_secs(cfg_data.preferences.interval.unwrap_or(60));
+    tasks.push(tokio::spawn(arbiter.run_rotation(period)));
+  }
 
-        // We now set the background.
-        let _ = desktop.change_background(&path);
+  let watchers = async {
+    for task in tasks {
+      if let Err(e) = task.await {
+        eprintln!("Watcher task failed: {e}");
       }
-      Err(e) => {
-        eprintln!("Failed to fetch link: {e}");
-        // Wait before next poll on error
-        tokio::time::sleep(sleep_time).await;
-        continue;
+    }
+  };
+
+  tokio::select! {
+    _ = watchers => {}
+    _ = shutdown_signal() => println!("Goodbye!"),
+  }
+
+  // Let the backend stop any helper it spawned, such as swaybg.
+  desktop.shutdown();
+}
+
+/// Resolve when the daemon is asked to stop, through Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{SignalKind, signal};
+    match signal(SignalKind::terminate()) {
+      Ok(mut terminate) => {
+        tokio::select! {
+          _ = tokio::signal::ctrl_c() => {}
+          _ = terminate.recv() => {}
+        }
+      }
+      Err(_) => {
+        let _ = tokio::signal::ctrl_c().await;
       }
     }
+  }
 
-    // Wait for the user defined period of time before next iteration
-    tokio::time::sleep(sleep_time).await;
+  #[cfg(not(unix))]
+  {
+    let _ = tokio::signal::ctrl_c().await;
   }
 }
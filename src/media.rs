@@ -0,0 +1,591 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Recognising what a post actually is and turning animations and videos into stills.
+
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, ImageDecoder, ImageError, ImageFormat, ImageReader, Limits};
+
+use crate::model::config::Preferences;
+
+/// Number of GIF frames looked at when picking a still.
+const GIF_FRAME_LIMIT: usize = 64;
+
+/// Media types found behind a post URL, told apart by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediaKind {
+  Jpeg,
+  Png,
+  Webp,
+  Gif,
+  Webm,
+  Mp4,
+  Unknown,
+}
+
+impl MediaKind {
+  /// Identify `bytes` from their signature, ignoring what the URL or server claims.
+  pub fn sniff(bytes: &[u8]) -> Self {
+    match bytes {
+      [0xFF, 0xD8, 0xFF, ..] => MediaKind::Jpeg,
+      [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => MediaKind::Png,
+      [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => MediaKind::Gif,
+      [
+        b'R',
+        b'I',
+        b'F',
+        b'F',
+        _,
+        _,
+        _,
+        _,
+        b'W',
+        b'E',
+        b'B',
+        b'P',
+        ..,
+      ] => MediaKind::Webp,
+      // EBML header, shared by WebM and Matroska.
+      [0x1A, 0x45, 0xDF, 0xA3, ..] => MediaKind::Webm,
+      [_, _, _, _, b'f', b't', b'y', b'p', ..] => MediaKind::Mp4,
+      _ => MediaKind::Unknown,
+    }
+  }
+
+  pub fn extension(self) -> &'static str {
+    match self {
+      MediaKind::Jpeg => "jpg",
+      MediaKind::Png => "png",
+      MediaKind::Webp => "webp",
+      MediaKind::Gif => "gif",
+      MediaKind::Webm => "webm",
+      MediaKind::Mp4 => "mp4",
+      MediaKind::Unknown => "bin",
+    }
+  }
+
+  pub fn is_video(self) -> bool {
+    matches!(self, MediaKind::Webm | MediaKind::Mp4)
+  }
+
+  fn image_format(self) -> Option<ImageFormat> {
+    match self {
+      MediaKind::Jpeg => Some(ImageFormat::Jpeg),
+      MediaKind::Png => Some(ImageFormat::Png),
+      MediaKind::Webp => Some(ImageFormat::WebP),
+      MediaKind::Gif => Some(ImageFormat::Gif),
+      _ => None,
+    }
+  }
+}
+
+/// Hard limits on what a link may make us download and decode.
+///
+/// Anyone with access to a link can push any post, so these keep a hostile one from
+/// exhausting memory or CPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediaLimits {
+  pub max_bytes: u64,
+  pub max_pixels: u64,
+  pub decode_timeout: Duration,
+}
+
+impl Default for MediaLimits {
+  fn default() -> Self {
+    MediaLimits {
+      max_bytes: 64 * 1024 * 1024,
+      max_pixels: 100_000_000,
+      decode_timeout: Duration::from_secs(20),
+    }
+  }
+}
+
+impl MediaLimits {
+  pub fn from_preferences(preferences: &Preferences) -> Self {
+    let defaults = MediaLimits::default();
+    MediaLimits {
+      max_bytes: preferences.max_download_bytes.unwrap_or(defaults.max_bytes),
+      max_pixels: preferences.max_pixels.unwrap_or(defaults.max_pixels),
+      decode_timeout: preferences
+        .decode_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(defaults.decode_timeout),
+    }
+  }
+}
+
+/// Which of the `MediaLimits` a download broke.
+#[derive(Debug, PartialEq)]
+pub enum LimitBreach {
+  /// The body is, or announced to be, larger than allowed.
+  Bytes { limit: u64, size: u64 },
+  /// The image has more pixels than allowed.
+  Pixels { limit: u64, width: u32, height: u32 },
+  /// Decoding took longer than allowed.
+  DecodeTimeout(Duration),
+}
+
+impl fmt::Display for LimitBreach {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LimitBreach::Bytes { limit, size } => {
+        write!(f, "{size} bytes is over the {limit} byte limit")
+      }
+      LimitBreach::Pixels {
+        limit,
+        width,
+        height,
+      } => write!(f, "{width}x{height} is over the {limit} pixel limit"),
+      LimitBreach::DecodeTimeout(timeout) => {
+        write!(f, "decoding took over {}s", timeout.as_secs_f32())
+      }
+    }
+  }
+}
+
+/// Why a download was refused, by the downloader or by `validate`.
+#[derive(Debug, PartialEq)]
+pub enum InvalidMedia {
+  /// The server answered with a non-2xx status.
+  Status(u16),
+  /// The server said it sent something that is not an image or a video.
+  ContentType(String),
+  /// The body is shorter or longer than `Content-Length` announced.
+  Truncated { expected: u64, received: u64 },
+  /// The body doesn't start with the signature of any format we handle.
+  UnknownFormat,
+  /// The body looks like an image but can't be decoded.
+  Undecodable(String),
+  /// The download breaks one of the `MediaLimits`.
+  LimitExceeded(LimitBreach),
+}
+
+impl fmt::Display for InvalidMedia {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      InvalidMedia::Status(status) => write!(f, "server answered with status {status}"),
+      InvalidMedia::ContentType(content_type) => {
+        write!(f, "unexpected content type {content_type}")
+      }
+      InvalidMedia::Truncated { expected, received } => {
+        write!(f, "received {received} of {expected} bytes")
+      }
+      InvalidMedia::UnknownFormat => write!(f, "not an image or video we recognise"),
+      InvalidMedia::Undecodable(e) => write!(f, "image can't be decoded: {e}"),
+      InvalidMedia::LimitExceeded(breach) => write!(f, "limit exceeded: {breach}"),
+    }
+  }
+}
+
+impl Error for InvalidMedia {}
+
+/// Check a downloaded body before it is allowed anywhere near the desktop.
+///
+/// Looks at the `Content-Type`, magic bytes and size limits, then decodes images completely.
+/// Videos are only checked up to their signature. The status and length are the
+/// downloader's business. This decodes the whole image, so call it off the async runtime.
+pub fn validate(
+  content_type: Option<&str>,
+  body: &[u8],
+  limits: &MediaLimits,
+) -> Result<MediaKind, InvalidMedia> {
+  if let Some(content_type) = content_type {
+    let essence = content_type
+      .split(';')
+      .next()
+      .unwrap_or_default()
+      .trim()
+      .to_ascii_lowercase();
+    let acceptable = essence.starts_with("image/")
+      || essence.starts_with("video/")
+      || essence == "application/octet-stream";
+    if !acceptable {
+      return Err(InvalidMedia::ContentType(essence));
+    }
+  }
+
+  if body.len() as u64 > limits.max_bytes {
+    return Err(InvalidMedia::LimitExceeded(LimitBreach::Bytes {
+      limit: limits.max_bytes,
+      size: body.len() as u64,
+    }));
+  }
+
+  let kind = MediaKind::sniff(body);
+  if kind == MediaKind::Unknown {
+    return Err(InvalidMedia::UnknownFormat);
+  }
+  if let Some(format) = kind.image_format() {
+    decode_checked(body, format, limits)?;
+  }
+  Ok(kind)
+}
+
+/// Decode an image, refusing it from its header alone when it has too many pixels.
+fn decode_checked(
+  body: &[u8],
+  format: ImageFormat,
+  limits: &MediaLimits,
+) -> Result<(), InvalidMedia> {
+  let undecodable = |e: ImageError| InvalidMedia::Undecodable(e.to_string());
+
+  let (width, height) = ImageReader::with_format(Cursor::new(body), format)
+    .into_dimensions()
+    .map_err(undecodable)?;
+  if width as u64 * height as u64 > limits.max_pixels {
+    return Err(InvalidMedia::LimitExceeded(LimitBreach::Pixels {
+      limit: limits.max_pixels,
+      width,
+      height,
+    }));
+  }
+
+  // The header could lie, so the decoder is capped as well.
+  let mut reader = ImageReader::with_format(Cursor::new(body), format);
+  reader.limits(decoder_limits(limits));
+  reader.decode().map_err(|e| match e {
+    ImageError::Limits(_) => InvalidMedia::LimitExceeded(LimitBreach::Pixels {
+      limit: limits.max_pixels,
+      width,
+      height,
+    }),
+    e => undecodable(e),
+  })?;
+  Ok(())
+}
+
+/// Decoder limits allowing roughly `max_pixels` of RGBA at 16 bits per channel.
+fn decoder_limits(limits: &MediaLimits) -> Limits {
+  let mut decoder_limits = Limits::default();
+  decoder_limits.max_alloc = Some(limits.max_pixels.saturating_mul(8));
+  decoder_limits
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+  Image(ImageError),
+  Io(std::io::Error),
+  /// `ffmpeg` ran but could not produce a frame.
+  Ffmpeg(String),
+  /// `ffmpeg` was killed for running longer than allowed.
+  TimedOut(Duration),
+}
+
+impl fmt::Display for MediaError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MediaError::Image(e) => write!(f, "image error: {e}"),
+      MediaError::Io(e) => write!(f, "IO error: {e}"),
+      MediaError::Ffmpeg(msg) => write!(f, "ffmpeg error: {msg}"),
+      MediaError::TimedOut(timeout) => {
+        write!(f, "ffmpeg took over {}s", timeout.as_secs_f32())
+      }
+    }
+  }
+}
+
+impl Error for MediaError {}
+
+impl From<ImageError> for MediaError {
+  fn from(e: ImageError) -> Self {
+    MediaError::Image(e)
+  }
+}
+
+fn gif_decoder<'a>(
+  bytes: &'a [u8],
+  limits: &MediaLimits,
+) -> Result<GifDecoder<Cursor<&'a [u8]>>, ImageError> {
+  let mut decoder = GifDecoder::new(Cursor::new(bytes))?;
+  decoder.set_limits(decoder_limits(limits))?;
+  Ok(decoder)
+}
+
+/// Whether a GIF has more than one frame.
+pub fn is_animated_gif(bytes: &[u8], limits: &MediaLimits) -> bool {
+  gif_decoder(bytes, limits)
+    .map(|decoder| decoder.into_frames().take(2).count() > 1)
+    .unwrap_or(false)
+}
+
+/// Save a representative frame of a GIF to `out`: the middle of its first frames.
+///
+/// Frames are decoded twice, once to count them and once to reach the middle, so only one is
+/// ever held in memory.
+pub fn gif_still(bytes: &[u8], limits: &MediaLimits, out: &Path) -> Result<(), MediaError> {
+  let count = gif_decoder(bytes, limits)?
+    .into_frames()
+    .take(GIF_FRAME_LIMIT)
+    .try_fold(0, |count, frame| frame.map(|_| count + 1))?;
+  let Some(frame) = gif_decoder(bytes, limits)?.into_frames().nth(count / 2) else {
+    return Err(MediaError::Io(std::io::Error::new(
+      std::io::ErrorKind::InvalidData,
+      "GIF has no frames",
+    )));
+  };
+  image::DynamicImage::ImageRgba8(frame?.into_buffer())
+    .to_rgb8()
+    .save(out)?;
+  Ok(())
+}
+
+/// Save a representative frame of `video` to `out` with `ffmpeg`, killing it after `timeout`.
+///
+/// ffmpeg's `thumbnail` filter picks the most typical frame among the first hundred, which
+/// skips the black or faded frames videos often start with.
+pub fn video_still(
+  ffmpeg: impl AsRef<OsStr>,
+  video: &Path,
+  out: &Path,
+  timeout: Duration,
+) -> Result<(), MediaError> {
+  // Nothing reads ffmpeg's errors while it runs, a pipe would fill up and stall it.
+  let mut log = tempfile::tempfile().map_err(MediaError::Io)?;
+  let mut child = Command::new(ffmpeg)
+    .args(["-v", "error", "-y", "-i"])
+    .arg(video)
+    .args(["-vf", "thumbnail", "-frames:v", "1"])
+    .arg(out)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(log.try_clone().map_err(MediaError::Io)?)
+    .spawn()
+    .map_err(MediaError::Io)?;
+
+  let deadline = Instant::now() + timeout;
+  let status = loop {
+    if let Some(status) = child.try_wait().map_err(MediaError::Io)? {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      return Err(MediaError::TimedOut(timeout));
+    }
+    std::thread::sleep(Duration::from_millis(20));
+  };
+
+  if status.success() && out.exists() {
+    Ok(())
+  } else {
+    let mut stderr = String::new();
+    if log.rewind().is_ok() {
+      let _ = log.read_to_string(&mut stderr);
+    }
+    Err(MediaError::Ffmpeg(stderr.trim().to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::codecs::gif::GifEncoder;
+  use image::{Delay, Frame, Rgba, RgbaImage};
+
+  fn gif(colors: &[[u8; 4]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    {
+      let mut encoder = GifEncoder::new(&mut bytes);
+      for color in colors {
+        let frame = Frame::from_parts(
+          RgbaImage::from_pixel(4, 4, Rgba(*color)),
+          0,
+          0,
+          Delay::from_numer_denom_ms(100, 1),
+        );
+        encoder.encode_frame(frame).unwrap();
+      }
+    }
+    bytes
+  }
+
+  #[test]
+  fn sniffs_magic_bytes() {
+    assert_eq!(MediaKind::sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), MediaKind::Jpeg);
+    assert_eq!(MediaKind::sniff(b"\x89PNG\r\n\x1a\n...."), MediaKind::Png);
+    assert_eq!(MediaKind::sniff(b"GIF89a...."), MediaKind::Gif);
+    assert_eq!(MediaKind::sniff(b"RIFF\0\0\0\0WEBPVP8 "), MediaKind::Webp);
+    assert_eq!(
+      MediaKind::sniff(&[0x1A, 0x45, 0xDF, 0xA3, 0x01]),
+      MediaKind::Webm
+    );
+    assert_eq!(MediaKind::sniff(b"\0\0\0\x18ftypmp42"), MediaKind::Mp4);
+    assert_eq!(
+      MediaKind::sniff(b"<!DOCTYPE html><html>"),
+      MediaKind::Unknown
+    );
+    assert_eq!(MediaKind::sniff(b""), MediaKind::Unknown);
+  }
+
+  fn png() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))
+      .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+      .unwrap();
+    bytes
+  }
+
+  fn limits() -> MediaLimits {
+    MediaLimits::default()
+  }
+
+  #[test]
+  fn accepts_a_complete_image() {
+    let body = png();
+    assert_eq!(
+      validate(Some("image/png"), &body, &limits()),
+      Ok(MediaKind::Png)
+    );
+    // Servers that don't send a content type are given the benefit of the doubt.
+    assert_eq!(validate(None, &body, &limits()), Ok(MediaKind::Png));
+  }
+
+  #[test]
+  fn rejects_bad_downloads() {
+    let body = png();
+    let html = b"<!DOCTYPE html><html><body>Not Found</body></html>";
+
+    assert_eq!(
+      validate(Some("text/html; charset=utf-8"), html, &limits()),
+      Err(InvalidMedia::ContentType("text/html".to_string()))
+    );
+    assert_eq!(
+      validate(Some("image/png"), html, &limits()),
+      Err(InvalidMedia::UnknownFormat)
+    );
+    assert!(matches!(
+      validate(Some("image/png"), &body[..body.len() / 2], &limits()),
+      Err(InvalidMedia::Undecodable(_))
+    ));
+  }
+
+  #[test]
+  fn enforces_size_limits() {
+    let body = png();
+    let tight = MediaLimits {
+      max_bytes: 10,
+      ..limits()
+    };
+    assert_eq!(
+      validate(None, &body, &tight),
+      Err(InvalidMedia::LimitExceeded(LimitBreach::Bytes {
+        limit: 10,
+        size: body.len() as u64,
+      }))
+    );
+
+    let few_pixels = MediaLimits {
+      max_pixels: 15,
+      ..limits()
+    };
+    assert_eq!(
+      validate(None, &body, &few_pixels),
+      Err(InvalidMedia::LimitExceeded(LimitBreach::Pixels {
+        limit: 15,
+        width: 4,
+        height: 4,
+      }))
+    );
+  }
+
+  #[test]
+  fn picks_the_middle_gif_frame() {
+    let animated = gif(&[[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]);
+    assert!(is_animated_gif(&animated, &limits()));
+    assert!(!is_animated_gif(&gif(&[[255, 0, 0, 255]]), &limits()));
+    assert!(!is_animated_gif(b"GIF89a", &limits()));
+
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("still.png");
+    gif_still(&animated, &limits(), &out).unwrap();
+    let still = image::open(&out).unwrap().to_rgb8();
+    assert_eq!(still.get_pixel(0, 0), &image::Rgb([0, 255, 0]));
+
+    let few_pixels = MediaLimits {
+      max_pixels: 1,
+      ..limits()
+    };
+    assert!(matches!(
+      gif_still(&animated, &few_pixels, &out),
+      Err(MediaError::Image(ImageError::Limits(_)))
+    ));
+  }
+
+  #[test]
+  fn reports_a_missing_ffmpeg() {
+    let dir = tempfile::tempdir().unwrap();
+    let err = video_still(
+      dir.path().join("ffmpeg"),
+      &dir.path().join("post.webm"),
+      &dir.path().join("post.png"),
+      Duration::from_secs(5),
+    )
+    .unwrap_err();
+    assert!(matches!(err, MediaError::Io(_)));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn kills_a_stuck_ffmpeg() {
+    let dir = tempfile::tempdir().unwrap();
+    let ffmpeg = crate::api::freedesktop::test_support::fake_command(
+      dir.path(),
+      "ffmpeg",
+      "exec /bin/sleep 10",
+    );
+    let err = video_still(
+      ffmpeg,
+      &dir.path().join("post.webm"),
+      &dir.path().join("post.png"),
+      Duration::from_millis(100),
+    )
+    .unwrap_err();
+    assert!(matches!(err, MediaError::TimedOut(_)));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn reports_what_a_chatty_ffmpeg_said() {
+    let dir = tempfile::tempdir().unwrap();
+    // Far more than a pipe buffer holds, then the actual error.
+    let ffmpeg = crate::api::freedesktop::test_support::fake_command(
+      dir.path(),
+      "ffmpeg",
+      "/usr/bin/head -c 1000000 /dev/zero | /usr/bin/tr '\\0' x >&2; echo >&2; echo broken >&2; exit 1",
+    );
+    let err = video_still(
+      ffmpeg,
+      &dir.path().join("post.webm"),
+      &dir.path().join("post.png"),
+      Duration::from_secs(10),
+    )
+    .unwrap_err();
+    match err {
+      MediaError::Ffmpeg(stderr) => assert!(stderr.ends_with("broken"), "{}", stderr.len()),
+      e => panic!("unexpected {e}"),
+    }
+  }
+}
@@ -18,6 +18,7 @@
 
 use dirs_next::{config_dir, picture_dir};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env::current_dir;
 use std::fs;
 use std::path::PathBuf;
@@ -31,21 +32,120 @@ use crate::constants::DISCORD_CLIENT_ID;
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BaseConfig {
   pub base: Option<String>,
+  /// ActionCable endpoint used for realtime updates, defaults to `CABLE_URL`.
+  #[serde(default)]
+  pub cable: Option<String>,
 }
 
-/// Feed section: which link id to watch.
+/// Feed section: which link ids to watch.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FeedConfig {
   pub feed: Option<i64>,
+  /// Additional links to watch, in priority order after `feed`.
+  #[serde(default)]
+  pub feeds: Option<Vec<i64>>,
   #[serde(default)]
   pub token: Option<String>,
+  /// Output (monitor) name each link is pinned to, keyed by link id.
+  #[serde(default)]
+  pub outputs: Option<HashMap<String, String>>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl FeedConfig {
+  /// Every watched link id, highest priority first and without duplicates.
+  pub fn links(&self) -> Vec<i64> {
+    let mut links: Vec<i64> = Vec::new();
+    for id in self.feed.iter().chain(self.feeds.iter().flatten()) {
+      if !links.contains(id) {
+        links.push(*id);
+      }
+    }
+    links
+  }
+
+  /// Links pinned to a specific output, mapped to that output's name.
+  ///
+  /// Keys that are not a link id are skipped, see `output_warnings`.
+  pub fn pinned_outputs(&self) -> HashMap<i64, String> {
+    self
+      .outputs
+      .iter()
+      .flatten()
+      .filter_map(|(link, output)| Some((link.trim().parse().ok()?, output.clone())))
+      .collect()
+  }
+
+  /// A warning for every `[Feed.outputs]` key that is not a link id, and so is ignored.
+  pub fn output_warnings(&self) -> Vec<String> {
+    let mut warnings: Vec<String> = self
+      .outputs
+      .iter()
+      .flatten()
+      .filter(|(link, _)| link.trim().parse::<i64>().is_err())
+      .map(|(link, output)| {
+        format!("Ignoring [Feed.outputs] {link:?} = {output:?}, {link:?} is not a link id")
+      })
+      .collect();
+    warnings.sort();
+    warnings
+  }
+}
+
+/// How a wallpaper is fitted to the screen.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ResizeMode {
+  /// Scale the whole image inside the screen and letterbox the rest.
   Fit,
+  /// Scale the image to cover the screen and cut off what overflows, keeping the busiest part.
   Crop,
+  /// Fit the image over a blurred, darkened copy of itself stretched to cover the screen.
+  Blur,
+}
+
+/// Which part of the image a crop keeps, overriding the automatic choice.
+///
+/// For images wider than the screen, `top` keeps the left side and `bottom` the right.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+  Top,
+  Center,
+  Bottom,
+}
+
+/// How new wallpapers are hidden until revealed.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CensorMode {
+  /// Show large blocks of color.
+  Pixelate,
+  /// Show a heavily blurred copy.
+  Blur,
+}
+
+/// Which link gets the desktop when several links are watched.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergePolicy {
+  /// The most recent update from any link is shown.
+  #[default]
+  LatestWins,
+  /// Updates only replace the background if their link ranks at least as high as the shown one.
+  Priority,
+  /// The desktop cycles through the latest image of each link every interval.
+  RoundRobin,
+}
+
+/// What happens to an image still waiting for approval when `approvalTimeout` runs out.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutAction {
+  /// Put the image on the desktop.
+  Apply,
+  /// Drop the image and keep the current background.
+  #[default]
+  Reject,
 }
 
 /// Preferences section for various user settings.
@@ -53,6 +153,15 @@ pub enum ResizeMode {
 pub struct Preferences {
   pub interval: Option<u64>,
   pub mode: Option<ResizeMode>,
+  /// Part of the image kept in `crop` mode. When unset the busiest region is kept.
+  #[serde(default)]
+  pub gravity: Option<Gravity>,
+  /// Blur radius of the background in `blur` mode, in screen pixels.
+  #[serde(rename = "blurRadius", default)]
+  pub blur_radius: Option<f32>,
+  /// How much the background is darkened in `blur` mode, from 0 (not at all) to 1 (black).
+  #[serde(rename = "blurDim", default)]
+  pub blur_dim: Option<f32>,
   #[serde(rename = "discordPresence")]
   pub discord_presence: Option<bool>,
   #[serde(rename = "discordClientId")]
@@ -61,6 +170,110 @@ pub struct Preferences {
   #[serde(rename = "saveLocally")]
   pub save_locally: Option<bool>,
   pub notifications: Option<bool>,
+  /// Size budget of the image cache in bytes. Defaults to 512 MiB, or no limit with
+  /// `saveLocally`.
+  #[serde(rename = "cacheMaxBytes", default)]
+  pub cache_max_bytes: Option<u64>,
+  /// Days a cached image is kept after its last use. Defaults to 30, or no limit with
+  /// `saveLocally`.
+  #[serde(rename = "cacheMaxAge", default)]
+  pub cache_max_age: Option<u64>,
+  /// Largest download accepted, in bytes. Defaults to 64 MiB.
+  #[serde(rename = "maxDownloadBytes", default)]
+  pub max_download_bytes: Option<u64>,
+  /// Largest image accepted, in pixels (width times height). Defaults to 100 million.
+  #[serde(rename = "maxPixels", default)]
+  pub max_pixels: Option<u64>,
+  /// Seconds an image may take to decode before it is given up on. Defaults to 20.
+  #[serde(rename = "decodeTimeout", default)]
+  pub decode_timeout: Option<u64>,
+  /// Origins post images may be downloaded from, HTTPS only. Defaults to
+  /// `https://static1.e621.net`.
+  #[serde(rename = "allowedHosts", default)]
+  pub allowed_hosts: Option<Vec<String>>,
+  /// Hold new images until they are approved from the notification or with `approve`.
+  #[serde(rename = "requireApproval", default)]
+  pub require_approval: Option<bool>,
+  /// Minutes an image waits for approval before `approvalTimeoutAction` is taken. When unset
+  /// it waits until approved, rejected or replaced by a newer one.
+  #[serde(rename = "approvalTimeout", default)]
+  pub approval_timeout: Option<u64>,
+  /// `apply` or `reject` (the default) images nobody answered for in time.
+  #[serde(rename = "approvalTimeoutAction", default)]
+  pub approval_timeout_action: Option<TimeoutAction>,
+  /// Send a Disgust response when an image is rejected.
+  #[serde(rename = "disgustOnReject", default)]
+  pub disgust_on_reject: Option<bool>,
+  /// Apply new wallpapers pixelated or blurred until "Reveal" is clicked or `reveal` is run.
+  #[serde(default)]
+  pub censor: Option<CensorMode>,
+  /// Subscribe to the link over a websocket instead of only polling it.
+  #[serde(default)]
+  pub realtime: Option<bool>,
+  /// How updates from several links share the desktop.
+  #[serde(default)]
+  pub policy: Option<MergePolicy>,
+}
+
+/// Desktop backends that can be forced with `backend` in the `[Desktop]` section.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DesktopBackend {
+  Command,
+  Portal,
+  Gnome,
+  Kde,
+  Xfce,
+  Cinnamon,
+  Mate,
+  Wlroots,
+  X11,
+  Windows,
+}
+
+impl std::fmt::Display for DesktopBackend {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let name = match self {
+      DesktopBackend::Command => "command",
+      DesktopBackend::Portal => "portal",
+      DesktopBackend::Gnome => "gnome",
+      DesktopBackend::Kde => "kde",
+      DesktopBackend::Xfce => "xfce",
+      DesktopBackend::Cinnamon => "cinnamon",
+      DesktopBackend::Mate => "mate",
+      DesktopBackend::Wlroots => "wlroots",
+      DesktopBackend::X11 => "x11",
+      DesktopBackend::Windows => "windows",
+    };
+    f.write_str(name)
+  }
+}
+
+/// Desktop section: how the wallpaper is applied.
+///
+/// Templates are split like a shell command line, then `{path}`, `{setter}`, `{link_id}`,
+/// `{output}`, `{title}` and `{body}` are substituted inside each argument. No shell is
+/// involved, so substituted values never need quoting.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DesktopConfig {
+  /// Force a backend instead of detecting the desktop environment.
+  #[serde(default)]
+  pub backend: Option<DesktopBackend>,
+  /// Command setting the wallpaper, e.g. `feh --bg-fill {path}`. Selects the command backend.
+  #[serde(default)]
+  pub set_command: Option<String>,
+  /// Command playing an animated image or video as the wallpaper, e.g. `mpvpaper '*' {path}`.
+  #[serde(default)]
+  pub animated_command: Option<String>,
+  /// Command opening an image for review, e.g. `xdg-open {path}`.
+  #[serde(default)]
+  pub open_command: Option<String>,
+  /// Command showing a notification, e.g. `notify-send {title} {body}`.
+  #[serde(default)]
+  pub notify_command: Option<String>,
+  /// Seconds a command may run before it is killed, defaults to 30.
+  #[serde(default)]
+  pub command_timeout: Option<u64>,
 }
 
 /// Top-level typed configuration that mirrors the exported TOML layout.
@@ -72,10 +285,13 @@ pub struct Config {
   pub feed: FeedConfig,
   #[serde(rename = "Preferences")]
   pub preferences: Preferences,
+  #[serde(rename = "Desktop", default)]
+  pub desktop: DesktopConfig,
 }
 
 impl Config {
   /// Parse a TOML string into Config.
+  #[allow(clippy::should_implement_trait)]
   pub fn from_str(toml: &str) -> Result<Self, toml::de::Error> {
     toml::from_str(toml)
   }
@@ -217,17 +433,20 @@ impl Config {
       .parse::<u64>()
       .map_err(|_| Box::<dyn std::error::Error>::from("Failed to parse update interval"))?;
 
-    // We then ask for the mode, either 'crop' or 'fit' by asking if the images should be resized
-    let resize_mode = if dialoguer::Confirm::new()
-      .with_prompt(
-        "Would you like to resize images to fit your screen? (otherwise they will be cropped by your system)",
-      )
-      .default(true)
+    // We then ask how images should be adapted to the screen
+    let resize_mode = match dialoguer::Select::new()
+      .with_prompt("How should images be adapted to your screen?")
+      .items([
+        "Fit the whole image, with black bars",
+        "Crop the image to fill the screen",
+        "Fit the whole image over a blurred copy of itself",
+      ])
+      .default(0)
       .interact()?
     {
-      ResizeMode::Fit
-    } else {
-      ResizeMode::Crop
+      0 => ResizeMode::Fit,
+      1 => ResizeMode::Crop,
+      _ => ResizeMode::Blur,
     };
 
     // We then ask if the user wants to store the images or just store them temporarily
@@ -278,25 +497,109 @@ impl Config {
       .default(true)
       .interact()?;
 
+    // We then ask if updates should be pushed to us as they happen rather than only polled
+    let realtime = dialoguer::Confirm::new()
+      .with_prompt("Would you like to receive link updates in real time?")
+      .default(true)
+      .interact()?;
+
     // We then build the config
     let config = Config {
-      base: Some(BaseConfig {
+      base: BaseConfig {
         base: Some(link_url.to_string()),
-      }),
-      feed: Some(FeedConfig {
+        cable: None,
+      },
+      feed: FeedConfig {
         feed: Some(link_id),
+        feeds: None,
         token: Some(api_token),
-      }),
-      preferences: Some(Preferences {
+        outputs: None,
+      },
+      preferences: Preferences {
         interval: Some(update_interval),
         mode: Some(resize_mode),
+        gravity: None,
+        blur_radius: None,
+        blur_dim: None,
         discord_presence: Some(discord_rich_presence),
         discord_client_id: discord_app_id,
         save_locally: image_path.map(|p| !p.is_empty()),
         notifications: Some(enable_notifications),
-      }),
+        max_download_bytes: None,
+        max_pixels: None,
+        decode_timeout: None,
+        allowed_hosts: None,
+        cache_max_bytes: None,
+        cache_max_age: None,
+        require_approval: None,
+        approval_timeout: None,
+        approval_timeout_action: None,
+        disgust_on_reject: None,
+        censor: None,
+        realtime: Some(realtime),
+        policy: None,
+      },
+      desktop: DesktopConfig::default(),
     };
 
     Ok(config)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_several_links_and_policy() {
+    let cfg = Config::from_str(
+      r#"
+      [Base]
+      base = "https://walltaker.joi.how/links/1"
+
+      [Feed]
+      feed = 1
+      feeds = [2, 1, 3]
+
+      [Feed.outputs]
+      2 = "HDMI-1"
+      nope = "DP-1"
+
+      [Preferences]
+      interval = 30
+      policy = "round-robin"
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(cfg.feed.links(), vec![1, 2, 3]);
+    assert_eq!(
+      cfg.feed.pinned_outputs(),
+      HashMap::from([(2, "HDMI-1".to_string())])
+    );
+    assert_eq!(
+      cfg.feed.output_warnings(),
+      [r#"Ignoring [Feed.outputs] "nope" = "DP-1", "nope" is not a link id"#]
+    );
+    assert_eq!(cfg.preferences.policy, Some(MergePolicy::RoundRobin));
+  }
+
+  #[test]
+  fn single_feed_still_works() {
+    let cfg = Config::from_str(
+      r#"
+      [Base]
+      [Feed]
+      feed = 5
+      [Preferences]
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(cfg.feed.links(), vec![5]);
+    assert_eq!(
+      cfg.preferences.policy.unwrap_or_default(),
+      MergePolicy::LatestWins
+    );
+  }
+}
@@ -16,10 +16,14 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use serde::Deserialize;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::response::InvalidResponse;
 
 /// Known response types from the API. Unknown values are captured as `Other(String)`.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ResponseType {
   Horny,
   Disgust,
@@ -27,18 +31,48 @@ pub enum ResponseType {
   Other(String),
 }
 
+impl ResponseType {
+  /// The name the API uses for this response type.
+  pub fn as_str(&self) -> &str {
+    match self {
+      ResponseType::Horny => "horny",
+      ResponseType::Disgust => "disgust",
+      ResponseType::Came => "came",
+      ResponseType::Other(other) => other,
+    }
+  }
+}
+
+/// Parses the types a response can be sent with, unknown ones are refused.
+impl FromStr for ResponseType {
+  type Err = InvalidResponse;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "horny" => Ok(ResponseType::Horny),
+      "disgust" => Ok(ResponseType::Disgust),
+      "came" => Ok(ResponseType::Came),
+      other => Err(InvalidResponse::UnknownType(other.to_string())),
+    }
+  }
+}
+
+impl Serialize for ResponseType {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serializer.serialize_str(self.as_str())
+  }
+}
+
 impl<'de> Deserialize<'de> for ResponseType {
   fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
   where
     D: serde::Deserializer<'de>,
   {
     let s = String::deserialize(deserializer)?;
-    match s.as_str() {
-      "horny" => Ok(ResponseType::Horny),
-      "disgust" => Ok(ResponseType::Disgust),
-      "came" => Ok(ResponseType::Came),
-      other => Ok(ResponseType::Other(other.to_string())),
-    }
+    Ok(s.parse().unwrap_or(ResponseType::Other(s)))
   }
 }
 
@@ -16,25 +16,113 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::error::Error;
+use std::fmt;
+
 use serde::Serialize;
 
+use crate::model::link::ResponseType;
+
+/// Longest reply text we send along with a response, in characters.
+pub const MAX_TEXT_CHARS: usize = 1000;
+
 #[derive(Debug, Serialize)]
 pub struct Response {
   pub api_key: String,
-  pub r#type: String,
+  pub r#type: ResponseType,
   pub text: String,
 }
 
 impl Response {
-  pub fn new(
-    api_key: impl Into<String>,
-    r#type: impl Into<String>,
-    text: impl Into<String>,
-  ) -> Self {
+  pub fn new(api_key: impl Into<String>, r#type: ResponseType, text: impl Into<String>) -> Self {
     Response {
       api_key: api_key.into(),
-      r#type: r#type.into(),
+      r#type,
       text: text.into(),
     }
   }
+
+  /// Check the response can be sent before it reaches the API.
+  pub fn validate(&self) -> Result<(), InvalidResponse> {
+    if self.api_key.is_empty() || self.api_key == "your_token" {
+      return Err(InvalidResponse::MissingToken);
+    }
+    if let ResponseType::Other(other) = &self.r#type {
+      return Err(InvalidResponse::UnknownType(other.clone()));
+    }
+    let length = self.text.chars().count();
+    if length > MAX_TEXT_CHARS {
+      return Err(InvalidResponse::TextTooLong(length));
+    }
+    Ok(())
+  }
+}
+
+/// Why a `Response` was not sent.
+#[derive(Debug, PartialEq)]
+pub enum InvalidResponse {
+  /// No API token is configured, or it is still the placeholder.
+  MissingToken,
+  /// Only horny, disgust and came can be sent.
+  UnknownType(String),
+  /// The reply text is longer than `MAX_TEXT_CHARS`.
+  TextTooLong(usize),
+}
+
+impl fmt::Display for InvalidResponse {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      InvalidResponse::MissingToken => write!(f, "Unauthorized: missing or placeholder token"),
+      InvalidResponse::UnknownType(other) => write!(
+        f,
+        "unknown response type '{other}', expected horny, disgust or came"
+      ),
+      InvalidResponse::TextTooLong(length) => write!(
+        f,
+        "reply is {length} characters long, at most {MAX_TEXT_CHARS} are allowed"
+      ),
+    }
+  }
+}
+
+impl Error for InvalidResponse {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn serializes_the_type_by_name() {
+    let response = Response::new("key", ResponseType::Came, "wow");
+    assert_eq!(
+      serde_json::to_value(&response).unwrap(),
+      serde_json::json!({ "api_key": "key", "type": "came", "text": "wow" })
+    );
+  }
+
+  #[test]
+  fn validates_before_sending() {
+    assert_eq!(
+      Response::new("key", ResponseType::Horny, "").validate(),
+      Ok(())
+    );
+    assert_eq!(
+      Response::new("your_token", ResponseType::Horny, "").validate(),
+      Err(InvalidResponse::MissingToken)
+    );
+    assert_eq!(
+      Response::new("key", ResponseType::Other("meh".into()), "").validate(),
+      Err(InvalidResponse::UnknownType("meh".into()))
+    );
+    assert_eq!(
+      Response::new("key", ResponseType::Disgust, "é".repeat(MAX_TEXT_CHARS)).validate(),
+      Ok(())
+    );
+    assert_eq!(
+      Response::new("key", ResponseType::Disgust, "a".repeat(MAX_TEXT_CHARS + 1)).validate(),
+      Err(InvalidResponse::TextTooLong(MAX_TEXT_CHARS + 1))
+    );
+    assert_eq!("came".parse::<ResponseType>(), Ok(ResponseType::Came));
+    assert!("meh".parse::<ResponseType>().is_err());
+  }
 }
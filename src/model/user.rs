@@ -21,13 +21,13 @@ use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct User {
-  username: String,
-  id: u32,
-  set_count: u32,
-  online: bool,
-  links: Vec<Link>,
-  authenticated: bool,
-  friend: bool,
+  pub username: String,
+  pub id: u32,
+  pub set_count: u32,
+  pub online: bool,
+  pub links: Vec<Link>,
+  pub authenticated: bool,
+  pub friend: bool,
   #[serde(rename = "self")]
-  is_self: bool,
+  pub is_self: bool,
 }
@@ -0,0 +1,513 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Image processing applied to downloads before they reach the desktop.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageResult, RgbImage};
+
+use crate::api::Output;
+use crate::model::config::{CensorMode, Gravity, Preferences, ResizeMode};
+
+/// Blur radius used in `blur` mode when `blurRadius` is not set.
+pub const DEFAULT_BLUR_RADIUS: f32 = 24.0;
+/// Darkening used in `blur` mode when `blurDim` is not set.
+pub const DEFAULT_BLUR_DIM: f32 = 0.4;
+
+/// How downloads are adapted to the screen, as set in `Preferences`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeOptions {
+  pub mode: ResizeMode,
+  pub gravity: Option<Gravity>,
+  pub blur_radius: f32,
+  pub blur_dim: f32,
+}
+
+impl ResizeOptions {
+  /// `None` when no mode is configured and downloads are used as is.
+  pub fn from_preferences(preferences: &Preferences) -> Option<Self> {
+    Some(ResizeOptions {
+      mode: preferences.mode?,
+      gravity: preferences.gravity,
+      blur_radius: preferences
+        .blur_radius
+        .unwrap_or(DEFAULT_BLUR_RADIUS)
+        .max(0.0),
+      blur_dim: preferences
+        .blur_dim
+        .unwrap_or(DEFAULT_BLUR_DIM)
+        .clamp(0.0, 1.0),
+    })
+  }
+
+  fn suffix(&self) -> String {
+    match self.mode {
+      ResizeMode::Fit => "fit".to_string(),
+      ResizeMode::Crop => match self.gravity {
+        None => "crop".to_string(),
+        Some(Gravity::Top) => "crop-top".to_string(),
+        Some(Gravity::Center) => "crop-center".to_string(),
+        Some(Gravity::Bottom) => "crop-bottom".to_string(),
+      },
+      ResizeMode::Blur => format!(
+        "blur{}d{}",
+        self.blur_radius.round(),
+        (self.blur_dim * 100.0).round()
+      ),
+    }
+  }
+}
+
+/// Scale `image` to fit inside `width`x`height`, centered on a black background.
+pub fn fit(image: &DynamicImage, width: u32, height: u32) -> RgbImage {
+  let scaled = image.resize(width, height, FilterType::Lanczos3).to_rgb8();
+  let mut canvas = RgbImage::new(width, height);
+  let x = (width - scaled.width()) / 2;
+  let y = (height - scaled.height()) / 2;
+  imageops::replace(&mut canvas, &scaled, x as i64, y as i64);
+  canvas
+}
+
+/// Scale `image` to cover `width`x`height` and cut off the overflow.
+///
+/// With a `gravity` the window is placed accordingly, otherwise it goes where the image has
+/// the most edges, which is usually where the subject is.
+pub fn crop(image: &DynamicImage, width: u32, height: u32, gravity: Option<Gravity>) -> RgbImage {
+  let (image_width, image_height) = image.dimensions();
+  let scale = f64::max(
+    width as f64 / image_width as f64,
+    height as f64 / image_height as f64,
+  );
+  let scaled_width = ((image_width as f64 * scale).round() as u32).max(width);
+  let scaled_height = ((image_height as f64 * scale).round() as u32).max(height);
+  let scaled = image
+    .resize_exact(scaled_width, scaled_height, FilterType::Lanczos3)
+    .to_rgb8();
+
+  let horizontal = scaled_width > width;
+  let (slack, visible) = if horizontal {
+    (scaled_width - width, width as f64 / scaled_width as f64)
+  } else {
+    (scaled_height - height, height as f64 / scaled_height as f64)
+  };
+  let offset = match gravity {
+    _ if slack == 0 => 0,
+    Some(Gravity::Top) => 0,
+    Some(Gravity::Center) => slack / 2,
+    Some(Gravity::Bottom) => slack,
+    None => busiest_offset(&edge_profile(image, horizontal), visible, slack),
+  };
+
+  let (x, y) = if horizontal { (offset, 0) } else { (0, offset) };
+  imageops::crop_imm(&scaled, x, y, width, height).to_image()
+}
+
+/// Edge density of each column (`horizontal`) or row of a small grayscale copy of `image`.
+fn edge_profile(image: &DynamicImage, horizontal: bool) -> Vec<u64> {
+  let gray = image.thumbnail(128, 128).to_luma8();
+  let (w, h) = gray.dimensions();
+  let mut profile = vec![0; if horizontal { w } else { h } as usize];
+
+  for y in 0..h {
+    for x in 0..w {
+      let here = gray.get_pixel(x, y)[0] as i32;
+      let right = gray.get_pixel((x + 1).min(w - 1), y)[0] as i32;
+      let below = gray.get_pixel(x, (y + 1).min(h - 1))[0] as i32;
+      let edge = (here - right).unsigned_abs() + (here - below).unsigned_abs();
+      profile[if horizontal { x } else { y } as usize] += edge as u64;
+    }
+  }
+  profile
+}
+
+/// Offset in `0..=slack` of the window covering `visible` of `profile` with the largest sum.
+///
+/// Ties go to the window closest to the center, so flat images are cropped evenly.
+fn busiest_offset(profile: &[u64], visible: f64, slack: u32) -> u32 {
+  let len = profile.len();
+  let window = ((len as f64 * visible).round() as usize).clamp(1, len.max(1));
+  if len <= window {
+    return slack / 2;
+  }
+
+  let positions = len - window;
+  let center = positions as f64 / 2.0;
+  let mut sum: u64 = profile[..window].iter().sum();
+  let mut best = (sum, 0);
+  for start in 1..=positions {
+    sum = sum + profile[start + window - 1] - profile[start - 1];
+    let closer = (start as f64 - center).abs() < (best.1 as f64 - center).abs();
+    if sum > best.0 || (sum == best.0 && closer) {
+      best = (sum, start);
+    }
+  }
+
+  (best.1 as f64 / positions as f64 * slack as f64).round() as u32
+}
+
+/// Fit `image` inside `width`x`height` over a blurred and darkened copy covering the screen.
+///
+/// `radius` is in screen pixels and `dim` goes from 0 (unchanged) to 1 (black).
+pub fn blur_fill(image: &DynamicImage, width: u32, height: u32, radius: f32, dim: f32) -> RgbImage {
+  // Blurring at full resolution is slow and the detail is thrown away anyway, so the
+  // background is blurred small and scaled back up.
+  const SHRINK: u32 = 8;
+  let small = image.resize_to_fill(
+    (width / SHRINK).max(1),
+    (height / SHRINK).max(1),
+    FilterType::Triangle,
+  );
+  let blurred = small.blur(radius / SHRINK as f32);
+  let mut canvas = imageops::resize(&blurred.to_rgb8(), width, height, FilterType::Triangle);
+
+  let keep = 1.0 - dim.clamp(0.0, 1.0);
+  for pixel in canvas.pixels_mut() {
+    for channel in pixel.0.iter_mut() {
+      *channel = (*channel as f32 * keep).round() as u8;
+    }
+  }
+
+  let scaled = image.resize(width, height, FilterType::Lanczos3).to_rgb8();
+  let x = (width - scaled.width()) / 2;
+  let y = (height - scaled.height()) / 2;
+  imageops::replace(&mut canvas, &scaled, x as i64, y as i64);
+  canvas
+}
+
+/// Blocks across the longer side of a pixelated preview.
+const PIXELATE_BLOCKS: u32 = 16;
+
+/// A copy of `image` of the same size with nothing recognizable left in it.
+pub fn censor(image: &DynamicImage, mode: CensorMode) -> RgbImage {
+  let (width, height) = image.dimensions();
+  match mode {
+    CensorMode::Pixelate => {
+      let scale = PIXELATE_BLOCKS as f64 / width.max(height) as f64;
+      let small = image.resize_exact(
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+        FilterType::Triangle,
+      );
+      imageops::resize(&small.to_rgb8(), width, height, FilterType::Nearest)
+    }
+    CensorMode::Blur => {
+      // Same trick as `blur_fill`: blur small, scale back up.
+      let small = image.resize(64, 64, FilterType::Triangle);
+      let blurred = small.blur(6.0);
+      imageops::resize(&blurred.to_rgb8(), width, height, FilterType::Triangle)
+    }
+  }
+}
+
+/// Write the censored copy of `original` next to it and return its path.
+pub fn censor_file(original: &Path, mode: CensorMode) -> ImageResult<PathBuf> {
+  let stem = original
+    .file_stem()
+    .and_then(|s| s.to_str())
+    .unwrap_or("image");
+  let suffix = match mode {
+    CensorMode::Pixelate => "pixelated",
+    CensorMode::Blur => "blurred",
+  };
+  let target = original.with_file_name(format!("{stem}.{suffix}.png"));
+  if is_rendered(&target, image::image_dimensions(original)?) {
+    return Ok(target);
+  }
+
+  save_atomically(&censor(&image::open(original)?, mode), &target)?;
+  Ok(target)
+}
+
+/// Whether `target` was already rendered at `size` by an earlier call.
+fn is_rendered(target: &Path, size: (u32, u32)) -> bool {
+  image::image_dimensions(target).is_ok_and(|dimensions| dimensions == size)
+}
+
+/// Save `image` to a temporary file next to `target`, then rename it into place.
+///
+/// A crash or a concurrent render of the same post never leaves a partial `target` behind.
+fn save_atomically(image: &RgbImage, target: &Path) -> ImageResult<()> {
+  static NEXT: AtomicU64 = AtomicU64::new(0);
+
+  let format = ImageFormat::from_path(target)?;
+  let mut name = target.file_name().unwrap_or_default().to_os_string();
+  name.push(format!(
+    ".{}-{}.part",
+    std::process::id(),
+    NEXT.fetch_add(1, Ordering::Relaxed)
+  ));
+  let part = target.with_file_name(name);
+
+  let result = image
+    .save_with_format(&part, format)
+    .and_then(|()| Ok(fs::rename(&part, target)?));
+  if result.is_err() {
+    let _ = fs::remove_file(&part);
+  }
+  result
+}
+
+/// Resolution to render for: the pinned output if there is one, else the largest output.
+pub fn target_size(outputs: &[Output], pinned: Option<&str>) -> Option<(u32, u32)> {
+  let output = pinned
+    .and_then(|name| outputs.iter().find(|o| o.name == name))
+    .or_else(|| {
+      outputs.iter().max_by_key(|o| {
+        let (width, height) = o.pixel_size();
+        width as u64 * height as u64
+      })
+    })?;
+  Some(output.pixel_size())
+}
+
+/// Resize the image at `original` for a `width`x`height` screen.
+///
+/// The result is written next to the original, named after the options and size so the
+/// same post is only processed once per screen.
+pub fn resize_file(
+  original: &Path,
+  options: &ResizeOptions,
+  width: u32,
+  height: u32,
+) -> ImageResult<PathBuf> {
+  let stem = original
+    .file_stem()
+    .and_then(|s| s.to_str())
+    .unwrap_or("image");
+  // Keep JPEG as JPEG, anything else is stored losslessly.
+  let ext = match original.extension().and_then(|e| e.to_str()) {
+    Some(ext) if ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg") => "jpg",
+    _ => "png",
+  };
+  let suffix = options.suffix();
+  let target = original.with_file_name(format!("{stem}.{suffix}-{width}x{height}.{ext}"));
+  if is_rendered(&target, (width, height)) {
+    return Ok(target);
+  }
+
+  let image = image::open(original)?;
+  let resized = match options.mode {
+    ResizeMode::Fit => fit(&image, width, height),
+    ResizeMode::Crop => crop(&image, width, height, options.gravity),
+    ResizeMode::Blur => blur_fill(&image, width, height, options.blur_radius, options.blur_dim),
+  };
+  save_atomically(&resized, &target)?;
+  Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::Rgb;
+
+  const RED: Rgb<u8> = Rgb([255, 0, 0]);
+  const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+  /// A 40x20 image, red on the left half and blue on the right.
+  fn wide() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(40, 20, |x, _| {
+      if x < 20 { RED } else { Rgb([0, 0, 255]) }
+    }))
+  }
+
+  fn output(name: &str, width: u32, height: u32) -> Output {
+    Output {
+      name: name.to_string(),
+      width,
+      height,
+      x: 0,
+      y: 0,
+      scale: 1.0,
+    }
+  }
+
+  #[test]
+  fn fit_letterboxes() {
+    let fitted = fit(&wide(), 20, 20);
+    assert_eq!(fitted.dimensions(), (20, 20));
+    assert_eq!(fitted.get_pixel(10, 0), &BLACK);
+    assert_eq!(fitted.get_pixel(10, 19), &BLACK);
+    assert_eq!(fitted.get_pixel(2, 10), &RED);
+  }
+
+  #[test]
+  fn crop_keeps_the_center() {
+    let cropped = crop(&wide(), 20, 20, None);
+    assert_eq!(cropped.dimensions(), (20, 20));
+    // The outer quarters are gone, the seam ends up in the middle.
+    assert_eq!(cropped.get_pixel(2, 10), &RED);
+    assert_eq!(cropped.get_pixel(17, 10), &Rgb([0, 0, 255]));
+    assert!(cropped.pixels().all(|p| *p != BLACK));
+  }
+
+  #[test]
+  fn crop_follows_the_detail() {
+    // A flat gray image with a checkerboard near the right edge.
+    let busy = DynamicImage::ImageRgb8(RgbImage::from_fn(90, 30, |x, y| {
+      if x >= 65 && (x / 3 + y / 3) % 2 == 0 {
+        Rgb([255, 255, 255])
+      } else {
+        Rgb([128, 128, 128])
+      }
+    }));
+    let cropped = crop(&busy, 30, 30, None);
+    let white = cropped
+      .pixels()
+      .filter(|p| **p == Rgb([255, 255, 255]))
+      .count();
+    assert!(white > 300, "only {white} checkerboard pixels kept");
+  }
+
+  #[test]
+  fn gravity_overrides_the_crop() {
+    // Red top half, blue bottom half, cropped to a square.
+    let tall = DynamicImage::ImageRgb8(RgbImage::from_fn(20, 60, |_, y| {
+      if y < 30 { RED } else { Rgb([0, 0, 255]) }
+    }));
+    let top = crop(&tall, 20, 20, Some(Gravity::Top));
+    assert!(top.pixels().all(|p| *p == RED));
+    let bottom = crop(&tall, 20, 20, Some(Gravity::Bottom));
+    assert!(bottom.pixels().all(|p| *p == Rgb([0, 0, 255])));
+  }
+
+  #[test]
+  fn blur_fill_covers_the_bars() {
+    // A tall red image on a wide screen: the sides get a dimmed red, not black.
+    let tall = DynamicImage::ImageRgb8(RgbImage::from_pixel(20, 40, RED));
+    let filled = blur_fill(&tall, 64, 32, 4.0, 0.5);
+    assert_eq!(filled.dimensions(), (64, 32));
+    assert_eq!(filled.get_pixel(32, 16), &RED);
+    let side = filled.get_pixel(2, 16);
+    assert!((120..=135).contains(&side[0]), "{side:?}");
+    assert_eq!(side[1], 0);
+  }
+
+  /// A 256x128 checkerboard of single black and white pixels.
+  fn checkerboard() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(256, 128, |x, y| {
+      if (x + y) % 2 == 0 {
+        BLACK
+      } else {
+        Rgb([255, 255, 255])
+      }
+    }))
+  }
+
+  #[test]
+  fn censoring_hides_the_detail() {
+    // Pixelated: 16x16 blocks of a single gray.
+    let pixelated = censor(&checkerboard(), CensorMode::Pixelate);
+    assert_eq!(pixelated.dimensions(), (256, 128));
+    let block = pixelated.get_pixel(16, 16);
+    assert!((100..=155).contains(&block[0]), "{block:?}");
+    for (x, y) in [(17, 16), (16, 31), (31, 31)] {
+      assert_eq!(pixelated.get_pixel(x, y), block);
+    }
+
+    let blurred = censor(&checkerboard(), CensorMode::Blur);
+    assert_eq!(blurred.dimensions(), (256, 128));
+    let (min, max) = blurred
+      .pixels()
+      .fold((255, 0), |(min, max), p| (min.min(p[0]), max.max(p[0])));
+    assert!(max - min < 40, "{min}..{max}");
+  }
+
+  #[test]
+  fn picks_pinned_or_largest_output() {
+    let outputs = [output("HDMI-1", 1920, 1080), output("DP-1", 2560, 1440)];
+    assert_eq!(target_size(&outputs, None), Some((2560, 1440)));
+    assert_eq!(target_size(&outputs, Some("HDMI-1")), Some((1920, 1080)));
+    assert_eq!(target_size(&outputs, Some("eDP-1")), Some((2560, 1440)));
+    assert_eq!(target_size(&[], None), None);
+
+    // Sizes are logical, a scaled output renders at its pixel resolution.
+    let hidpi = Output {
+      scale: 2.0,
+      ..output("eDP-1", 1440, 900)
+    };
+    assert_eq!(target_size(&[hidpi], None), Some((2880, 1800)));
+  }
+
+  #[test]
+  fn writes_next_to_the_original() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = dir.path().join("post.png");
+    wide().save(&original).unwrap();
+
+    let options = ResizeOptions {
+      mode: ResizeMode::Crop,
+      gravity: None,
+      blur_radius: DEFAULT_BLUR_RADIUS,
+      blur_dim: DEFAULT_BLUR_DIM,
+    };
+    let resized = resize_file(&original, &options, 16, 9).unwrap();
+    assert_eq!(resized, dir.path().join("post.crop-16x9.png"));
+    assert_eq!(image::open(&resized).unwrap().dimensions(), (16, 9));
+
+    let censored = censor_file(&resized, CensorMode::Blur).unwrap();
+    assert_eq!(censored, dir.path().join("post.crop-16x9.blurred.png"));
+    assert_eq!(image::open(&censored).unwrap().dimensions(), (16, 9));
+  }
+
+  #[test]
+  fn rerenders_a_broken_target() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = dir.path().join("post.png");
+    wide().save(&original).unwrap();
+    // Left behind by a crash halfway through an earlier render.
+    let target = dir.path().join("post.fit-16x9.png");
+    std::fs::write(&target, b"\x89PNG\r\n").unwrap();
+
+    let options = ResizeOptions {
+      mode: ResizeMode::Fit,
+      gravity: None,
+      blur_radius: DEFAULT_BLUR_RADIUS,
+      blur_dim: DEFAULT_BLUR_DIM,
+    };
+    let resized = resize_file(&original, &options, 16, 9).unwrap();
+    assert_eq!(resized, target);
+    assert_eq!(image::open(&resized).unwrap().dimensions(), (16, 9));
+
+    let censored = dir.path().join("post.fit-16x9.pixelated.png");
+    std::fs::write(&censored, b"\x89PNG").unwrap();
+    assert_eq!(
+      censor_file(&resized, CensorMode::Pixelate).unwrap(),
+      censored
+    );
+    assert_eq!(image::open(&censored).unwrap().dimensions(), (16, 9));
+
+    // Nothing but the original and the renders is left in the directory.
+    let mut names: Vec<_> = std::fs::read_dir(dir.path())
+      .unwrap()
+      .map(|e| e.unwrap().file_name().into_string().unwrap())
+      .collect();
+    names.sort();
+    assert_eq!(
+      names,
+      [
+        "post.fit-16x9.pixelated.png",
+        "post.fit-16x9.png",
+        "post.png"
+      ]
+    );
+  }
+}
@@ -0,0 +1,253 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Where post images may be downloaded from.
+
+use std::error::Error;
+use std::fmt;
+
+use reqwest::redirect;
+use url::{Origin, Url};
+
+use crate::model::config::Preferences;
+
+/// Origin posts are served from on e621, the only one allowed unless configured otherwise.
+pub const DEFAULT_ALLOWED_HOST: &str = "https://static1.e621.net";
+
+/// A download that the `DownloadPolicy` refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyViolation {
+  /// The URL could not be parsed.
+  InvalidUrl(String),
+  /// The URL is not served over HTTPS.
+  NotHttps(Url),
+  /// The URL points to a host outside the allowlist.
+  HostNotAllowed(Url),
+}
+
+impl fmt::Display for PolicyViolation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PolicyViolation::InvalidUrl(url) => write!(f, "invalid URL {url:?}"),
+      PolicyViolation::NotHttps(url) => write!(f, "{url} is not served over HTTPS"),
+      PolicyViolation::HostNotAllowed(url) => {
+        write!(
+          f,
+          "{} is not an allowed host",
+          url.origin().ascii_serialization()
+        )
+      }
+    }
+  }
+}
+
+impl Error for PolicyViolation {}
+
+/// HTTPS-only allowlist of origins post images may come from, redirects included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadPolicy {
+  allowed: Vec<Origin>,
+  require_https: bool,
+}
+
+impl Default for DownloadPolicy {
+  fn default() -> Self {
+    DownloadPolicy::new([DEFAULT_ALLOWED_HOST])
+  }
+}
+
+impl DownloadPolicy {
+  /// Allow the given origins, e.g. `https://static1.e621.net`.
+  ///
+  /// A bare host is taken to mean HTTPS. Entries that are not HTTPS or can't be parsed are
+  /// reported and skipped.
+  pub fn new<I, S>(hosts: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+  {
+    let allowed = hosts
+      .into_iter()
+      .filter_map(|host| {
+        let host = host.as_ref().trim();
+        let url = if host.contains("://") {
+          Url::parse(host)
+        } else {
+          Url::parse(&format!("https://{host}"))
+        };
+        match url {
+          Ok(url) if url.scheme() == "https" => Some(url.origin()),
+          Ok(_) => {
+            eprintln!("Ignoring allowed host {host:?}, only HTTPS hosts are allowed");
+            None
+          }
+          Err(e) => {
+            eprintln!("Ignoring allowed host {host:?}: {e}");
+            None
+          }
+        }
+      })
+      .collect();
+    DownloadPolicy {
+      allowed,
+      require_https: true,
+    }
+  }
+
+  /// Allow exactly the origins of `urls`, plain HTTP included, for tests against local servers.
+  #[cfg(test)]
+  pub(crate) fn insecure(urls: &[&Url]) -> Self {
+    DownloadPolicy {
+      allowed: urls.iter().map(|url| url.origin()).collect(),
+      require_https: false,
+    }
+  }
+
+  /// Whether only HTTPS may be used, always the case outside of tests.
+  pub fn requires_https(&self) -> bool {
+    self.require_https
+  }
+
+  pub fn from_preferences(preferences: &Preferences) -> Self {
+    match &preferences.allowed_hosts {
+      Some(hosts) => DownloadPolicy::new(hosts),
+      None => DownloadPolicy::default(),
+    }
+  }
+
+  /// Check that `url` may be downloaded.
+  pub fn check(&self, url: &Url) -> Result<(), PolicyViolation> {
+    if self.require_https && url.scheme() != "https" {
+      return Err(PolicyViolation::NotHttps(url.clone()));
+    }
+    if !self.allowed.contains(&url.origin()) {
+      return Err(PolicyViolation::HostNotAllowed(url.clone()));
+    }
+    Ok(())
+  }
+
+  /// Parse and check `url`.
+  pub fn check_str(&self, url: &str) -> Result<Url, PolicyViolation> {
+    let parsed = Url::parse(url).map_err(|_| PolicyViolation::InvalidUrl(url.to_string()))?;
+    self.check(&parsed)?;
+    Ok(parsed)
+  }
+
+  /// Redirect policy holding every hop to the same rules.
+  pub fn redirect_policy(&self) -> redirect::Policy {
+    let policy = self.clone();
+    redirect::Policy::custom(move |attempt| {
+      if attempt.previous().len() >= 10 {
+        return attempt.error("too many redirects");
+      }
+      match policy.check(attempt.url()) {
+        Ok(()) => attempt.follow(),
+        Err(violation) => attempt.error(violation),
+      }
+    })
+  }
+}
+
+/// Find a `PolicyViolation` behind `error`, such as a refused redirect inside a reqwest error.
+pub fn find_violation<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a PolicyViolation> {
+  let mut current = Some(error);
+  while let Some(error) = current {
+    if let Some(violation) = error.downcast_ref::<PolicyViolation>() {
+      return Some(violation);
+    }
+    current = error.source();
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn url(s: &str) -> Url {
+    Url::parse(s).unwrap()
+  }
+
+  #[test]
+  fn default_only_allows_e621_over_https() {
+    let policy = DownloadPolicy::default();
+    assert_eq!(
+      policy.check(&url("https://static1.e621.net/data/5d/87/x.png")),
+      Ok(())
+    );
+    assert!(matches!(
+      policy.check(&url("http://static1.e621.net/data/5d/87/x.png")),
+      Err(PolicyViolation::NotHttps(_))
+    ));
+    for other in [
+      "https://evil.example/x.png",
+      "https://static1.e621.net.evil.example/x.png",
+      "https://static1.e621.net:8443/x.png",
+    ] {
+      assert!(
+        matches!(
+          policy.check(&url(other)),
+          Err(PolicyViolation::HostNotAllowed(_))
+        ),
+        "{other}"
+      );
+    }
+    assert!(matches!(
+      policy.check_str("not a url"),
+      Err(PolicyViolation::InvalidUrl(_))
+    ));
+  }
+
+  #[test]
+  fn parses_configured_hosts() {
+    let policy = DownloadPolicy::new([
+      "static1.e926.net",
+      "https://cdn.example:8443",
+      "http://plain.example",
+    ]);
+    assert!(policy.check(&url("https://static1.e926.net/x.png")).is_ok());
+    assert!(policy.check(&url("https://cdn.example:8443/x.png")).is_ok());
+    assert!(policy.check(&url("https://plain.example/x.png")).is_err());
+    assert!(
+      policy
+        .check(&url("https://static1.e621.net/x.png"))
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn finds_violations_in_error_chains() {
+    #[derive(Debug)]
+    struct Wrapper(PolicyViolation);
+    impl fmt::Display for Wrapper {
+      fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request failed")
+      }
+    }
+    impl Error for Wrapper {
+      fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+      }
+    }
+
+    let violation = PolicyViolation::NotHttps(url("http://a.example/"));
+    let wrapped = Wrapper(violation.clone());
+    assert_eq!(find_violation(&wrapped), Some(&violation));
+    assert_eq!(find_violation(&std::fmt::Error), None);
+  }
+}
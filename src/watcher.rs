@@ -0,0 +1,618 @@
+/*
+ * loxerpaper - Automatic wallpaper fetcher and desktop background manager
+ * Copyright (C) 2025  Clifton Toaster Reid
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use crate::api::{
+  ApiClient, DesktopApi, LinkSubscription, LinkUpdate, Notification, Urgency, WallpaperInfo,
+  spawn_review_notification,
+};
+use crate::approval::{Approvals, Decision, Pending};
+use crate::arbiter::Arbiter;
+use crate::cache::{self, ImageCache, InUse};
+use crate::censor::Reveals;
+use crate::constants::CABLE_URL;
+use crate::media::{self, InvalidMedia, LimitBreach, MediaKind, MediaLimits};
+use crate::model::config::CensorMode;
+use crate::model::link::{Link, ResponseType};
+use crate::pipeline::{self, ResizeOptions};
+use crate::policy;
+
+pub fn hash_str(s: &str) -> i64 {
+  // Polynomial rolling hash:
+  // hash(s) = sum_{i=0..n-1} (s[i]+1) * base^{n-1-i}  (computed iteratively)
+  const MOD: i64 = 1_000_000_007;
+  const BASE: i64 = 257;
+  let mut h: i64 = 0;
+  for b in s.bytes() {
+    // use (b as i64 + 1) so that '\0' and other zeros contribute
+    h = (h.wrapping_mul(BASE) + (b as i64 + 1)) % MOD;
+  }
+  h
+}
+
+/// Find a broken `MediaLimits` behind `error`, which may come wrapped in a `DownloadError`.
+fn limit_breach<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a LimitBreach> {
+  let mut current = Some(error);
+  while let Some(error) = current {
+    if let Some(InvalidMedia::LimitExceeded(breach)) = error.downcast_ref::<InvalidMedia>() {
+      return Some(breach);
+    }
+    current = error.source();
+  }
+  None
+}
+
+/// Hand `image` to the arbiter, or its censored `preview` until it is revealed.
+fn present(
+  arbiter: &Arbiter,
+  reveals: Option<&Reveals>,
+  link_id: i64,
+  post_id: i64,
+  image: PathBuf,
+  preview: Option<PathBuf>,
+  info: WallpaperInfo,
+) {
+  match (reveals, preview) {
+    (Some(reveals), Some(preview)) => reveals.show(link_id, post_id, &preview, image, info),
+    _ => arbiter.submit(link_id, &image, &info),
+  }
+}
+
+/// Watches a single link and submits every new post to the `Arbiter`.
+pub struct LinkWatcher {
+  client: ApiClient,
+  desktop: Arc<dyn DesktopApi>,
+  arbiter: Arc<Arbiter>,
+  link_id: i64,
+  current_id: Arc<AtomicI64>,
+  approvals: Option<Arc<Approvals>>,
+  reveals: Option<Arc<Reveals>>,
+  in_use: Arc<InUse>,
+}
+
+impl LinkWatcher {
+  pub fn new(
+    client: ApiClient,
+    desktop: Arc<dyn DesktopApi>,
+    arbiter: Arc<Arbiter>,
+    link_id: i64,
+  ) -> Self {
+    LinkWatcher {
+      client,
+      desktop,
+      arbiter,
+      link_id,
+      current_id: Arc::new(AtomicI64::new(-1)),
+      approvals: None,
+      reveals: None,
+      in_use: Arc::default(),
+    }
+  }
+
+  /// Share the posts held by each link with the other watchers, so the cache keeps them.
+  pub fn with_in_use(mut self, in_use: Arc<InUse>) -> Self {
+    self.in_use = in_use;
+    self
+  }
+
+  /// Hold new images until they are approved, see `approval`.
+  pub fn with_approvals(mut self, approvals: Arc<Approvals>) -> Self {
+    self.approvals = Some(approvals);
+    self
+  }
+
+  /// Show new images censored until they are revealed, see `censor`.
+  pub fn with_reveals(mut self, reveals: Arc<Reveals>) -> Self {
+    self.reveals = Some(reveals);
+    self
+  }
+
+  /// Run forever, following the link over the cable when enabled and polling otherwise.
+  ///
+  /// Whenever the socket drops we go back to polling for one interval before reconnecting.
+  pub async fn run(&self) {
+    let preferences = &self.client.config.preferences;
+
+    // Determine sleep time, defaulting to 60 seconds if not set
+    let sleep_time = tokio::time::Duration::from_secs(preferences.interval.unwrap_or(60));
+    let realtime = preferences.realtime.unwrap_or(false);
+    let cable_url = self
+      .client
+      .config
+      .base
+      .cable
+      .clone()
+      .unwrap_or_else(|| CABLE_URL.to_string());
+
+    loop {
+      if realtime {
+        match LinkSubscription::connect(&cable_url, self.link_id).await {
+          Ok(subscription) => self.follow(subscription, sleep_time).await,
+          Err(e) => eprintln!("Realtime updates unavailable, polling instead: {e}"),
+        }
+      }
+
+      self.poll().await;
+
+      // Wait for the user defined period of time before next iteration
+      tokio::time::sleep(sleep_time).await;
+    }
+  }
+
+  /// Apply updates pushed over the cable until the connection ends.
+  ///
+  /// When an update fails to apply, the link is polled again every `retry` until it works
+  /// or the next update arrives.
+  async fn follow(&self, mut subscription: LinkSubscription, retry: tokio::time::Duration) {
+    println!("Subscribed to realtime updates for link {}", self.link_id);
+
+    // Catch up on anything set while we were not subscribed.
+    let mut applied = self.poll().await;
+
+    loop {
+      let update = if applied {
+        subscription.next_update().await
+      } else {
+        tokio::select! {
+          update = subscription.next_update() => update,
+          _ = tokio::time::sleep(retry) => {
+            applied = self.poll().await;
+            continue;
+          }
+        }
+      };
+      applied = match update {
+        Ok(Some(LinkUpdate::Full(link))) => self.apply(*link).await,
+        Ok(Some(LinkUpdate::Changed)) => self.poll().await,
+        Ok(None) => {
+          eprintln!("Realtime connection closed, falling back to polling");
+          return;
+        }
+        Err(e) => {
+          eprintln!("Realtime connection lost, falling back to polling: {e}");
+          return;
+        }
+      };
+    }
+  }
+
+  /// Fetch the link once and apply it, returning `false` if either failed.
+  async fn poll(&self) -> bool {
+    // Ping the API for updates (link)
+    match self.client.get_link(self.link_id).await {
+      Ok(link) => self.apply(link).await,
+      Err(e) => {
+        eprintln!("Failed to fetch link: {e}");
+        false
+      }
+    }
+  }
+
+  /// Download the post of `link` and set it as the background if it is new.
+  ///
+  /// Returns `false` when the post could not be applied and is worth retrying.
+  async fn apply(&self, link: Link) -> bool {
+    let preferences = &self.client.config.preferences;
+    let send_notifications = preferences.notifications.unwrap_or(true);
+
+    // We first check if this is a new url with the post id.
+    let Some(post_url) = link.post_url else {
+      println!("Link {} has no post set yet", self.link_id);
+      return true;
+    };
+
+    // Try to parse the URL and extract the final path segment (the filename).
+    // Fallback to a safe replacement when parsing fails.
+    let filename = match url::Url::parse(&post_url).ok().and_then(|u| {
+      u.path_segments()
+        .and_then(|mut s| s.next_back().map(|s| s.to_string()))
+    }) {
+      Some(f) => f,
+      None => {
+        // Fallback: use the whole URL but sanitize characters so we don't produce
+        // a filename that contains ':' or '/'.
+        post_url.replace(
+          |c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'),
+          "_",
+        )
+      }
+    };
+
+    // Derive a stem and extension from the filename.
+    let path_like = std::path::Path::new(&filename);
+    let stem = path_like
+      .file_stem()
+      .and_then(|s| s.to_str())
+      .unwrap_or("image");
+    let ext = path_like
+      .extension()
+      .and_then(|e| e.to_str())
+      .unwrap_or("png");
+
+    // Sanitize stem to remove any unexpected characters.
+    let sanitize: String = stem
+      .chars()
+      .map(|c| {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+          c
+        } else {
+          '_'
+        }
+      })
+      .collect();
+
+    let hashed_id = hash_str(&sanitize);
+
+    if self.current_id.load(Ordering::SeqCst) == hashed_id {
+      // We have the same image, we print a debug message, and return.
+      println!("No new image, current is still id {hashed_id}");
+      return true;
+    }
+
+    // We have a new image. Posts are cached under the md5 from their URL, so one seen
+    // before is not downloaded again.
+    let cache = ImageCache::from_preferences(preferences);
+    let key = cache::post_md5(&post_url).unwrap_or_else(|| sanitize.clone());
+    // Until this link holds the post, no watcher's eviction may remove it or what is
+    // derived from it. Released on every return below.
+    let _reservation = self.in_use.reserve(&key);
+
+    let (kind, path, content) = match cache.lookup(&key) {
+      Some((kind, path)) => match tokio::fs::read(&path).await {
+        Ok(content) => {
+          println!("Using cached {}", path.display());
+          (kind, path, content)
+        }
+        Err(e) => {
+          eprintln!("Failed to read cached {}: {e}", path.display());
+          return false;
+        }
+      },
+      None => {
+        if let Err(e) = fs::create_dir_all(cache.dir()) {
+          eprintln!("Failed to create {}: {e}", cache.dir().display());
+          return false;
+        }
+
+        // We now download the image and make sure it is one before touching the desktop.
+        // On failure the current wallpaper stays and the post is retried on the next cycle.
+        let download = match self.fetch(&post_url, &cache.path_for(&key, ext)).await {
+          Ok(download) => download,
+          Err(e) => {
+            eprintln!("Rejected download of {post_url}: {e}");
+            self.notify_rejection(e.as_ref());
+            return false;
+          }
+        };
+
+        // Keep what any link still shows, waits to approve or keeps hidden.
+        let mut keep = self.in_use.keys();
+        keep.extend(
+          self
+            .arbiter
+            .images()
+            .iter()
+            .filter_map(|image| cache::key_of(image))
+            .map(str::to_string),
+        );
+        keep.insert(key.clone());
+        let cache = cache.clone();
+        tokio::task::spawn_blocking(move || match cache.evict(&keep) {
+          Ok(0) => {}
+          Ok(removed) => println!("Evicted {removed} files from the image cache"),
+          Err(e) => eprintln!("Failed to evict from {}: {e}", cache.dir().display()),
+        });
+        download
+      }
+    };
+
+    let Some((path, animated)) = self
+      .still(kind, &content, &path, link.post_thumbnail_url.as_deref())
+      .await
+    else {
+      eprintln!("Nothing to show for {post_url}, keeping the current wallpaper");
+      return false;
+    };
+
+    let set_by = link.set_by.unwrap_or("unknown".to_string());
+    let info = WallpaperInfo {
+      link_id: Some(self.link_id),
+      set_by: Some(set_by.clone()),
+      animated: animated.clone(),
+    };
+
+    // The image opened for review is the original, not the resized or censored copy.
+    let review_image = animated.unwrap_or(path.clone());
+    let path = self.prepare(path).await;
+    let preview = match &self.reveals {
+      Some(reveals) => match self.censored(&path, reveals.mode()).await {
+        Some(preview) => Some(preview),
+        // Better to keep the current wallpaper than to show this one uncensored.
+        None => return false,
+      },
+      None => None,
+    };
+
+    // Only now that there is something to show do we notify and edit the current ID, so a
+    // failure above leaves the post to be retried on the next cycle.
+    self.current_id.store(hashed_id, Ordering::SeqCst);
+    self.in_use.hold(self.link_id, &key);
+    let pending = self
+      .approvals
+      .as_ref()
+      .map(|approvals| approvals.request(self.link_id, hashed_id));
+    if send_notifications {
+      spawn_review_notification(
+        &self.client,
+        self.desktop.clone(),
+        self.current_id.clone(),
+        self.approvals.clone(),
+        self.reveals.clone(),
+        self.link_id,
+        hashed_id,
+        set_by,
+        review_image,
+      );
+    }
+
+    // We now hand the image over, the arbiter sets the background if this link wins.
+    match pending {
+      Some(pending) => self.await_approval(pending, hashed_id, path, preview, info),
+      None => present(
+        &self.arbiter,
+        self.reveals.as_deref(),
+        self.link_id,
+        hashed_id,
+        path,
+        preview,
+        info,
+      ),
+    }
+    true
+  }
+
+  /// The censored preview of `path`, see `pipeline::censor`.
+  async fn censored(&self, path: &Path, mode: CensorMode) -> Option<PathBuf> {
+    let source = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || pipeline::censor_file(&source, mode)).await {
+      Ok(Ok(preview)) => Some(preview),
+      Ok(Err(e)) => {
+        eprintln!("Failed to censor {}: {e}", path.display());
+        None
+      }
+      Err(e) => {
+        eprintln!("Censoring task failed: {e}");
+        None
+      }
+    }
+  }
+
+  /// Hand the image over once it is approved, without holding up the watcher.
+  ///
+  /// Rejected images are dropped, with a Disgust response if `disgustOnReject` is set.
+  fn await_approval(
+    &self,
+    pending: Pending,
+    post_id: i64,
+    path: PathBuf,
+    preview: Option<PathBuf>,
+    info: WallpaperInfo,
+  ) {
+    let disgust = self
+      .approvals
+      .as_ref()
+      .is_some_and(|approvals| approvals.policy().disgust_on_reject);
+    let (client, arbiter, link_id) = (self.client.clone(), self.arbiter.clone(), self.link_id);
+    let reveals = self.reveals.clone();
+    println!("Waiting for approval of the new image of link {link_id}");
+
+    tokio::spawn(async move {
+      match pending.decision().await {
+        Some(Decision::Approve) => present(
+          &arbiter,
+          reveals.as_deref(),
+          link_id,
+          post_id,
+          path,
+          preview,
+          info,
+        ),
+        Some(Decision::Reject) => {
+          println!("Rejected the new image of link {link_id}");
+          if disgust && let Err(e) = client.react(link_id, ResponseType::Disgust, "").await {
+            eprintln!("Failed to send Disgust response: {e}");
+          }
+        }
+        None => println!("The pending image of link {link_id} was replaced by a newer one"),
+      }
+    });
+  }
+
+  /// Download `url` to `dest` and check it with `media::validate`.
+  ///
+  /// The file is renamed after the format actually served, since the URL extension can't be
+  /// trusted, and returned along with its contents. Rejected files are removed.
+  async fn fetch(
+    &self,
+    url: &str,
+    dest: &Path,
+  ) -> Result<(MediaKind, PathBuf, Vec<u8>), Box<dyn Error + Send + Sync>> {
+    let limits = self.limits();
+    let download = self.client.download(url, dest, limits.max_bytes).await?;
+    let body = tokio::fs::read(&download.path).await?;
+
+    // A decode that runs past the timeout is abandoned; the blocking thread finishes on its own.
+    let content_type = download.content_type.clone();
+    let check = tokio::task::spawn_blocking(move || {
+      let kind = media::validate(content_type.as_deref(), &body, &limits)?;
+      Ok::<_, InvalidMedia>((kind, body))
+    });
+    let checked = match tokio::time::timeout(limits.decode_timeout, check).await {
+      Ok(Ok(result)) => result.map_err(Into::into),
+      Ok(Err(e)) => Err(e.into()),
+      Err(_) => {
+        Err(InvalidMedia::LimitExceeded(LimitBreach::DecodeTimeout(limits.decode_timeout)).into())
+      }
+    };
+    let (kind, body) = match checked {
+      Ok(checked) => checked,
+      Err(e) => {
+        let _ = tokio::fs::remove_file(&download.path).await;
+        return Err(e);
+      }
+    };
+
+    let path = dest.with_extension(kind.extension());
+    if path != download.path {
+      tokio::fs::rename(&download.path, &path).await?;
+    }
+    Ok((kind, path, body))
+  }
+
+  fn limits(&self) -> MediaLimits {
+    MediaLimits::from_preferences(&self.client.config.preferences)
+  }
+
+  /// Tell the user when a post was refused for breaking the `DownloadPolicy` or one of the
+  /// `MediaLimits`.
+  fn notify_rejection(&self, error: &(dyn Error + Send + Sync + 'static)) {
+    let reason = if let Some(violation) = policy::find_violation(error) {
+      violation.to_string()
+    } else if let Some(breach) = limit_breach(error) {
+      breach.to_string()
+    } else {
+      return;
+    };
+    let notification = Notification::builder("Post rejected")
+      .body(format!(
+        "A post pushed to link {} was not downloaded: {reason}. Your background was left as is.",
+        self.link_id
+      ))
+      .urgency(Urgency::Critical)
+      .build();
+    if let Err(e) = self.desktop.send_notification(&notification) {
+      eprintln!("Failed to send notification: {e}");
+    }
+  }
+
+  /// Pick the image to put on the desktop for a download of `kind` saved at `path`.
+  ///
+  /// Animated GIFs and videos are reduced to a still frame and returned alongside the original,
+  /// for backends that can play it. When no still can be made, the post thumbnail is used.
+  async fn still(
+    &self,
+    kind: MediaKind,
+    content: &[u8],
+    path: &Path,
+    thumbnail_url: Option<&str>,
+  ) -> Option<(PathBuf, Option<PathBuf>)> {
+    if matches!(kind, MediaKind::Jpeg | MediaKind::Png | MediaKind::Webp) {
+      return Some((path.to_path_buf(), None));
+    }
+
+    let animated = Some(path.to_path_buf());
+    let still = path.with_extension("still.png");
+    if still.is_file() {
+      cache::touch(&still);
+      return Some((still, animated));
+    }
+    let limits = self.limits();
+    let (source, target) = (path.to_path_buf(), still.clone());
+    let bytes = content.to_vec();
+    let extract = tokio::task::spawn_blocking(move || {
+      if kind.is_video() {
+        media::video_still("ffmpeg", &source, &target, limits.decode_timeout).map(|()| true)
+      } else if media::is_animated_gif(&bytes, &limits) {
+        media::gif_still(&bytes, &limits, &target).map(|()| true)
+      } else {
+        Ok(false)
+      }
+    });
+    match tokio::time::timeout(limits.decode_timeout, extract).await {
+      Ok(Ok(Ok(true))) => return Some((still, animated)),
+      Ok(Ok(Ok(false))) => return Some((path.to_path_buf(), None)),
+      Ok(Ok(Err(e))) => eprintln!("No still frame for {}: {e}", path.display()),
+      Ok(Err(e)) => eprintln!("Still frame task failed: {e}"),
+      Err(_) => eprintln!(
+        "No still frame for {}: {}",
+        path.display(),
+        LimitBreach::DecodeTimeout(limits.decode_timeout)
+      ),
+    }
+
+    let thumbnail_url = thumbnail_url?;
+    println!("Falling back to the post thumbnail");
+    let dest = path.with_extension("thumb.jpg");
+    let (thumbnail_kind, thumbnail, _) = match self.fetch(thumbnail_url, &dest).await {
+      Ok(download) => download,
+      Err(e) => {
+        eprintln!("Rejected thumbnail {thumbnail_url}: {e}");
+        self.notify_rejection(e.as_ref());
+        return None;
+      }
+    };
+    if thumbnail_kind.is_video() || thumbnail_kind == MediaKind::Gif {
+      eprintln!("The thumbnail is not a still image");
+      return None;
+    }
+    Some((thumbnail, animated))
+  }
+
+  /// Fit, crop or blur-fill the download to the screen according to `Preferences.mode`.
+  ///
+  /// Falls back to the original image when the mode is unset, the screen size is unknown
+  /// or processing fails.
+  async fn prepare(&self, original: PathBuf) -> PathBuf {
+    let Some(options) = ResizeOptions::from_preferences(&self.client.config.preferences) else {
+      return original;
+    };
+    let pinned = self
+      .client
+      .config
+      .feed
+      .pinned_outputs()
+      .remove(&self.link_id);
+    let desktop = self.desktop.clone();
+    let source = original.clone();
+
+    let result = tokio::task::spawn_blocking(move || {
+      let outputs = desktop.outputs().ok()?;
+      let (width, height) = pipeline::target_size(&outputs, pinned.as_deref())?;
+      Some(pipeline::resize_file(&source, &options, width, height))
+    })
+    .await;
+
+    match result {
+      Ok(Some(Ok(path))) => path,
+      Ok(Some(Err(e))) => {
+        eprintln!("Failed to resize {}: {e}", original.display());
+        original
+      }
+      Ok(None) => original,
+      Err(e) => {
+        eprintln!("Image processing task failed: {e}");
+        original
+      }
+    }
+  }
+}